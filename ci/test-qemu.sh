@@ -13,7 +13,13 @@ srcdir=$(cd ${dn} && git rev-parse --show-toplevel)
 set -x
 tmpdir=$(mktemp -d -p /tmp ccisp.XXXXXXX)
 cd "${tmpdir}"
-fcct ${srcdir}/run-qemu.fcc -o run.ign
+cp ${srcdir}/run-qemu.fcc run.fcc
+# To exercise error paths, build with `--features fault-injection` and
+# set e.g. CCISP_FAULT_INJECT=flakey:0:5
+if test -n "${CCISP_FAULT_INJECT:-}"; then
+    sed -i -e "s,^\(        \)\[Service\]$,&\n\1Environment=CCISP_FAULT_INJECT=${CCISP_FAULT_INJECT}," run.fcc
+fi
+fcct run.fcc -o run.ign
 qemuexec_args=(kola qemuexec --propagate-initramfs-failure --qemu-image "${image}" --qemu-firmware uefi \
     -i run.ign --bind-ro ${srcdir},/run/srcdir --bind-rw .,/run/testtmp)
 disk_args=()
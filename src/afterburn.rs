@@ -0,0 +1,73 @@
+//! Afterburn already knows the quirks of each cloud's metadata service;
+//! use what it found rather than duplicating that here.
+
+use super::*;
+
+/// Written by afterburn.service, e.g. `AFTERBURN_AWS_INSTANCE_ID=i-...`.
+const ATTRS_PATH: &str = "/run/metadata/afterburn";
+/// Where we have Afterburn write attributes if it hasn't run yet.
+const OUR_ATTRS: &str = "afterburn";
+const PREFIX: &str = "AFTERBURN_";
+/// Attribute suffixes for the instance type on the various providers.
+const INSTANCE_TYPE_SUFFIXES: &[&str] = &["_INSTANCE_TYPE", "_MACHINE_TYPE", "_VMSIZE"];
+
+/// Run Afterburn ourselves, using the platform from the kernel
+/// command line; without one, there's nothing for it to query.
+fn fetch() -> Result<Option<String>> {
+    if coreos::cmdline_platform()?.is_none() {
+        return Ok(None);
+    }
+    std::fs::create_dir_all(RUN_DIR)?;
+    let path = Path::new(RUN_DIR).join(OUR_ATTRS);
+    if !path.exists() {
+        let mut arg = std::ffi::OsString::from("--attributes=");
+        arg.push(&path);
+        Command::new("afterburn").arg("--cmdline").arg(&arg).run()?;
+    }
+    Ok(Some(std::fs::read_to_string(&path)?))
+}
+
+fn attributes() -> Option<&'static [(String, String)]> {
+    static ATTRS: std::sync::OnceLock<Option<Vec<(String, String)>>> = std::sync::OnceLock::new();
+    ATTRS
+        .get_or_init(|| {
+            let buf = match std::fs::read_to_string(ATTRS_PATH) {
+                Ok(b) => b,
+                Err(_) => fetch()
+                    .inspect_err(|e| eprintln!("warning: Running afterburn: {:#}", e))
+                    .ok()??,
+            };
+            Some(
+                buf.lines()
+                    .filter_map(|l| l.split_once('='))
+                    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                    .collect(),
+            )
+        })
+        .as_deref()
+}
+
+/// The first attribute whose name ends with one of `suffixes`.
+pub(crate) fn attribute(suffixes: &[&str]) -> Option<String> {
+    attributes()?
+        .iter()
+        .find(|(k, _)| suffixes.iter().any(|s| k.ends_with(s)))
+        .map(|(_, v)| v.clone())
+}
+
+/// The platform Afterburn ran for, derived from its attribute names,
+/// e.g. `AFTERBURN_AWS_...` is `aws`.  Only consults an existing
+/// attributes file; running Afterburn itself needs the platform.
+pub(crate) fn platform() -> Option<String> {
+    let buf = std::fs::read_to_string(ATTRS_PATH).ok()?;
+    buf.lines()
+        .filter_map(|l| l.strip_prefix(PREFIX))
+        .filter_map(|l| l.split('_').next())
+        .map(|p| p.to_lowercase())
+        .next()
+}
+
+/// E.g. `m5d.xlarge` or `Standard_L8s_v3`.
+pub(crate) fn instance_type() -> Option<String> {
+    attribute(INSTANCE_TYPE_SUFFIXES)
+}
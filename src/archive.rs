@@ -0,0 +1,67 @@
+//! With `directory-backup`, directories are archived before they're
+//! emptied, in case one was listed by mistake.
+
+use super::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+/// The archive for `dir` in `dest`, e.g. `var-lib-containers.tar.zst`.
+fn path(dest: &Path, dir: &Path) -> PathBuf {
+    let name = dir
+        .to_str()
+        .expect("utf8")
+        .trim_matches('/')
+        .replace('/', "-");
+    dest.join(format!("{}.tar.zst", name))
+}
+
+/// Stream `dir` through tar and zstd into `dest`, keeping any existing
+/// archive: after the first run, the directory is normally empty.
+pub(crate) fn create(dest: &Path, dir: &Path) -> Result<()> {
+    if !dir.exists() || std::fs::read_dir(dir)?.next().is_none() {
+        return Ok(());
+    }
+    let archive = path(dest, dir);
+    if archive.exists() {
+        println!("Keeping existing archive {:?}", archive);
+        return Ok(());
+    }
+    std::fs::create_dir_all(dest).with_context(|| format!("Creating {:?}", dest))?;
+    let tmp = archive.with_extension("zst.tmp");
+    // The content may well be private.
+    let out = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .with_context(|| format!("Creating {:?}", tmp))?;
+    let mut tar = Command::new("tar");
+    tar.args(["--create", "--xattrs", "--acls", "--selinux", "--file", "-"])
+        .arg("--directory")
+        .arg(dir)
+        .arg(".")
+        .stdout(Stdio::piped());
+    failure::record_command(&tar);
+    let mut tar = tar.spawn()?;
+    let mut zstd = Command::new("zstd");
+    zstd.args(["--quiet", "-T0"])
+        .stdin(tar.stdout.take().expect("stdout"))
+        .stdout(out);
+    failure::record_command(&zstd);
+    let zstd_status = zstd.status()?;
+    let tar_status = tar.wait()?;
+    if !tar_status.success() || !zstd_status.success() {
+        let _ = std::fs::remove_file(&tmp);
+        bail!(
+            "Archiving {:?} failed (tar: {}, zstd: {})",
+            dir,
+            tar_status,
+            zstd_status
+        );
+    }
+    std::fs::rename(&tmp, &archive)?;
+    println!("Archived {:?} to {:?}", dir, archive);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Looking for SELinux denials on the instance storage; a wrong label is
+//! the most likely way for provisioning to break things after the fact.
+
+use super::*;
+use std::io::Write as IoWrite;
+
+const AUDIT_LOG: &str = "/var/log/audit/audit.log";
+const SERVICE_NAME: &str = "ccisp-avc-check.service";
+const TIMER_NAME: &str = "ccisp-avc-check.timer";
+
+/// The paths whose denials we care about.
+fn watched_paths(config: &Config, profile: &profile::Profile) -> Vec<String> {
+    let mut r = vec![config.mountpoint(profile).to_string()];
+    match config.directories.as_ref() {
+        Some(dirs) => r.extend(dirs.iter().map(|d| match d {
+            DirectoryEntry::Path(p) => p.clone(),
+            DirectoryEntry::Directory(d) => d.path.clone(),
+        })),
+        None => r.extend(profile.default_directories.iter().map(|s| s.to_string())),
+    }
+    if config.var_tmp {
+        r.push(vartmp::PATH.to_string());
+    }
+    r.extend(config.files.iter().map(|f| f.path.clone()));
+    r
+}
+
+/// Audit records, from auditd's log if it's running, or the journal.
+fn records() -> Result<String> {
+    match std::fs::read_to_string(AUDIT_LOG) {
+        Ok(s) => Ok(s),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let o = Command::new("journalctl")
+                .args(["-b", "-o", "cat", "_TRANSPORT=audit"])
+                .output()?;
+            if !o.status.success() {
+                bail!("Failed to read audit messages from the journal");
+            }
+            Ok(String::from_utf8_lossy(&o.stdout).into_owned())
+        }
+        Err(e) => Err(e).with_context(|| format!("Reading {}", AUDIT_LOG)),
+    }
+}
+
+/// The `path=` or `name=` of an AVC denial.
+fn denied_path(record: &str) -> Option<&str> {
+    if !record.contains("avc:") || !record.contains(" denied ") {
+        return None;
+    }
+    record.split_whitespace().find_map(|field| {
+        field
+            .strip_prefix("path=\"")
+            .or_else(|| field.strip_prefix("name=\""))
+            .and_then(|v| v.strip_suffix('"'))
+    })
+}
+
+fn is_under(path: &str, prefix: &str) -> bool {
+    Path::new(path).starts_with(prefix)
+}
+
+/// Print denials involving the instance storage; it's an error if
+/// there are any.
+pub(crate) fn check(config: &Config, profile: &profile::Profile) -> Result<()> {
+    let watched = watched_paths(config, profile);
+    let records = records()?;
+    let denials: Vec<&str> = records
+        .lines()
+        .filter(|l| denied_path(l).is_some_and(|p| watched.iter().any(|w| is_under(p, w))))
+        .collect();
+    for denial in denials.iter() {
+        println!("{}", denial);
+    }
+    if !denials.is_empty() {
+        bail!(
+            "Found {} SELinux denials involving instance storage",
+            denials.len()
+        );
+    }
+    println!("No SELinux denials involving instance storage");
+    Ok(())
+}
+
+/// Write a service running `exe check-selinux` and its timer,
+/// returning the timer.
+pub(crate) fn write_units(unit_dir: &str, exe: &Path) -> Result<String> {
+    let dir = openat::Dir::open(unit_dir)?;
+    failure::record_unit(unit_dir, SERVICE_NAME);
+    dir.write_file_with(SERVICE_NAME, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Check for SELinux denials on instance storage
+
+[Service]
+Type=oneshot
+ExecStart={exe} check-selinux
+"##,
+            exe = exe.display(),
+        )?;
+        Ok(())
+    })?;
+    failure::record_unit(unit_dir, TIMER_NAME);
+    dir.write_file_contents(
+        TIMER_NAME,
+        0o644,
+        r##"[Unit]
+Description=Check for SELinux denials on instance storage periodically
+
+[Timer]
+OnActiveSec=10min
+OnUnitActiveSec=1h
+
+[Install]
+WantedBy=timers.target
+"##,
+    )?;
+    Ok(TIMER_NAME.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denied_paths() {
+        let record = "type=AVC msg=audit(1700000000.123:456): avc:  denied  { write } for  pid=1234 comm=\"crio\" name=\"overlay\" dev=\"nvme1n1\" ino=128 scontext=system_u:system_r:container_runtime_t:s0 tcontext=system_u:object_r:var_t:s0 tclass=dir permissive=0";
+        assert_eq!(denied_path(record), Some("overlay"));
+        let record = "type=AVC msg=audit(1700000000.123:457): avc:  denied  { getattr } for  pid=1234 comm=\"journald\" path=\"/var/log/journal\" dev=\"dm-0\" ino=130 scontext=system_u:system_r:syslogd_t:s0 tcontext=system_u:object_r:var_t:s0 tclass=dir permissive=0";
+        assert_eq!(denied_path(record), Some("/var/log/journal"));
+        for record in [
+            // Granted, not denied.
+            "type=AVC msg=audit(1700000000.123:458): avc:  granted  { setenforce } for  pid=1 name=\"x\"",
+            "type=SYSCALL msg=audit(1700000000.123:456): arch=c000003e syscall=257 success=no exit=-13 comm=\"crio\"",
+            // Paths with spaces are hex-encoded, without quotes.
+            "type=AVC msg=audit(1700000000.123:459): avc:  denied  { write } for  pid=1 path=2F7661722F61206200",
+        ] {
+            assert_eq!(denied_path(record), None, "{:?}", record);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use super::*;
+
+const INSTANCE_MODEL: &str = "Amazon EC2 NVMe Instance Storage";
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/latest/api/token";
+const IMDS_BDM_URL: &str = "http://169.254.169.254/latest/meta-data/block-device-mapping/";
+/// Xen-based instances name their disks this way.
+const XEN_PREFIX: &str = "xvd";
+/// On Nitro, EBS volumes have their volume ID as serial
+/// (e.g. `vol0123456789abcdef0`); instance store uses `AWS...`.
+const EBS_SERIAL_PREFIX: &str = "vol";
+
+/// A client for the instance metadata service, using IMDSv2
+/// (which may be required on the instance).
+struct Imds {
+    token_header: String,
+}
+
+impl Imds {
+    fn new() -> Result<Self> {
+        let token = metadata::request(
+            "PUT",
+            IMDS_TOKEN_URL,
+            &["X-aws-ec2-metadata-token-ttl-seconds: 60"],
+        )?;
+        Ok(Self {
+            token_header: format!("X-aws-ec2-metadata-token: {}", token.trim()),
+        })
+    }
+
+    fn get(&self, url: &str) -> Result<String> {
+        metadata::fetch(url, &[self.token_header.as_str()])
+    }
+}
+
+/// Map a block device mapping name like `sdb` or `/dev/sdb` to the
+/// name the Xen blkfront driver gives it, e.g. `/dev/xvdb`.
+fn xen_device_path(mapping: &str) -> String {
+    let name = mapping.trim().trim_start_matches("/dev/");
+    let name = name
+        .strip_prefix("sd")
+        .map(|n| Cow::Owned(format!("{}{}", XEN_PREFIX, n)))
+        .unwrap_or(Cow::Borrowed(name));
+    format!("/dev/{}", name)
+}
+
+/// The `ephemeralN` keys in the block device mapping.
+fn ephemeral_keys(imds: &Imds) -> Result<Vec<String>> {
+    Ok(imds
+        .get(IMDS_BDM_URL)?
+        .lines()
+        .map(|k| k.trim())
+        .filter(|k| k.starts_with("ephemeral"))
+        .map(|k| k.to_string())
+        .collect())
+}
+
+/// Cross-check NVMe instance store devices: drop anything with an
+/// EBS volume ID as serial, and compare the count with the block
+/// device mapping to notice volumes which failed to attach.  The
+/// metadata service is only advisory here; if it's unreachable we
+/// go by lsblk alone.
+fn validate(devs: Vec<String>) -> Result<Vec<String>> {
+    let blockdevs = block::list()?;
+    let devs: Vec<String> = devs
+        .into_iter()
+        .filter(|path| {
+            let serial = blockdevs
+                .iter()
+                .find(|d| &d.path() == path)
+                .and_then(|d| d.serial.as_deref())
+                .map(|s| s.trim())
+                .unwrap_or_default();
+            if serial.starts_with(EBS_SERIAL_PREFIX) {
+                eprintln!(
+                    "warning: Ignoring {} with instance store model but EBS serial {}",
+                    path, serial
+                );
+                false
+            } else {
+                true
+            }
+        })
+        .collect();
+    let expected = match Imds::new().and_then(|imds| ephemeral_keys(&imds)) {
+        Ok(keys) => keys.len(),
+        Err(e) => {
+            eprintln!("warning: Failed to query block device mapping: {:#}", e);
+            return Ok(devs);
+        }
+    };
+    // The mapping may omit instance store volumes not explicitly
+    // specified at launch, so only a shortfall is meaningful.
+    if expected > devs.len() {
+        eprintln!(
+            "warning: Block device mapping has {} instance store volumes, but only {} found",
+            expected,
+            devs.len()
+        );
+    }
+    Ok(devs)
+}
+
+/// Older Xen-based instance types (d2, i2, m3, ...) expose instance
+/// storage as plain xvd devices; the only reliable way to tell them
+/// apart from EBS volumes is the block device mapping in the metadata.
+fn legacy_devices() -> Result<Vec<String>> {
+    let imds = Imds::new()?;
+    let mut r = Vec::new();
+    for key in ephemeral_keys(&imds)? {
+        let mapping = imds.get(&format!("{}{}", IMDS_BDM_URL, key))?;
+        let path = xen_device_path(&mapping);
+        if Path::new(&path).exists() {
+            r.push(path);
+        } else {
+            println!("Instance store {} ({}) is not attached", key, path);
+        }
+    }
+    Ok(r)
+}
+
+pub(crate) fn devices() -> Result<Vec<String>> {
+    let devs = block::with_model(INSTANCE_MODEL)?;
+    if !devs.is_empty() {
+        return validate(devs);
+    }
+    // Avoid querying the metadata service on Nitro instances.
+    let is_xen = block::list()?
+        .iter()
+        .any(|d| d.name.starts_with(XEN_PREFIX));
+    if !is_xen {
+        return Ok(devs);
+    }
+    match legacy_devices() {
+        Ok(devs) => Ok(devs),
+        Err(e) => {
+            eprintln!(
+                "warning: Failed to query block device mapping for Xen instance store: {:#}",
+                e
+            );
+            Ok(Vec::new())
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use super::*;
+use block::Device;
+
+const MODEL: &str = "Virtual Disk";
+const FSTYPE: &str = "ntfs";
+const LABEL: &str = "Temporary Storage";
+const RESOURCE_DISK_LINK: &str = "/dev/disk/cloud/azure_resource";
+/// These are never NTFS formatted.
+const NVME_MODEL: &str = "Microsoft NVMe Direct Disk";
+const IMDS_COMPUTE_URL: &str =
+    "http://169.254.169.254/metadata/instance/compute?api-version=2021-10-01&format=json";
+
+/// Some sizes (e.g. the HB/HC HPC series) have local NVMe disks besides
+/// the SCSI temporary disk, which is much slower.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Disks {
+    /// Both the temporary disk and the NVMe disks.
+    #[default]
+    All,
+    /// Only the SCSI temporary disk.
+    ResourceDisk,
+    /// Only the NVMe disks.
+    Nvme,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Compute {
+    vm_size: String,
+    #[serde(default)]
+    storage_profile: StorageProfile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StorageProfile {
+    resource_disk: Option<ResourceDisk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResourceDisk {
+    /// In MiB, as a string.
+    size: String,
+}
+
+impl Compute {
+    fn resource_disk_mib(&self) -> Option<u64> {
+        self.storage_profile
+            .resource_disk
+            .as_ref()
+            .and_then(|d| d.size.parse().ok())
+    }
+}
+
+/// Query the VM size and its temporary disk size; if IMDS isn't
+/// reachable we fall back to looking at the block devices alone.
+fn imds_compute() -> Option<Compute> {
+    let r = metadata::fetch(IMDS_COMPUTE_URL, &["Metadata: true"])
+        .and_then(|buf| Ok(serde_json::from_str(&buf)?));
+    match r {
+        Ok(c) => Some(c),
+        Err(e) => {
+            eprintln!("warning: Failed to query Azure IMDS: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Otherwise the device will be pre-formatted as ntfs, so we
+/// look for a block device with a single child that matches.
+fn filtermap_child_ntfs(dev: &Device) -> Option<String> {
+    let child = if let Some(children) = dev.children.as_ref() {
+        if children.len() == 1 {
+            &children[0]
+        } else {
+            return None;
+        }
+    } else {
+        return None;
+    };
+    if let (Some(label), Some(fstype)) = (child.label.as_ref(), child.fstype.as_ref()) {
+        if label.as_str().trim() == LABEL && fstype.as_str().trim() == FSTYPE {
+            let devpath = dev.path();
+            return Some(devpath);
+        }
+    }
+    None
+}
+
+/// The SCSI resource ("temporary") disk as linked by the udev rules
+/// shipped by WALinuxAgent/cloud-init (and azure-vm-utils); preferred,
+/// since it doesn't depend on the disk still having its original NTFS
+/// filesystem (e.g. after a reboot).
+fn resource_disk_link() -> Result<Option<String>> {
+    match std::fs::canonicalize(RESOURCE_DISK_LINK) {
+        Ok(p) => Ok(Some(p.to_str().expect("utf8").to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Resolving {}", RESOURCE_DISK_LINK)),
+    }
+}
+
+/// The SCSI resource disk in `inventory`, found by its NTFS filesystem.
+pub(crate) fn resource_disk(inventory: &[Device]) -> Vec<String> {
+    // Note Azure helpfully sets it up as NTFS; that's wiped
+    // along with any other signatures before we use the device.
+    inventory
+        .iter()
+        .filter(|dev| {
+            dev.model
+                .as_ref()
+                .filter(|m| m.as_str().trim() == MODEL)
+                .is_some()
+        })
+        .filter_map(filtermap_child_ntfs)
+        .collect()
+}
+
+/// Local NVMe disks (e.g. Lsv3, or the temporary disk on v6 sizes) are
+/// used along with the SCSI resource disk, if both exist.  Newer sizes
+/// (e.g. Dv5/Ev5) have no temporary disk at all; IMDS tells us so, and
+/// also lets us complain if there should be one but we can't find it.
+pub(crate) fn devices(disks: Disks) -> Result<Vec<String>> {
+    let link = resource_disk_link()?;
+    Ok(select(disks, imds_compute(), link, &block::list()?))
+}
+
+/// The devices in `inventory` alone, as with neither IMDS nor the udev
+/// symlink available; for checking against fixtures.
+pub(crate) fn devices_in(disks: Disks, inventory: &[Device]) -> Vec<String> {
+    select(disks, None, None, inventory)
+}
+
+fn select(
+    disks: Disks,
+    compute: Option<Compute>,
+    resource_disk_link: Option<String>,
+    inventory: &[Device],
+) -> Vec<String> {
+    let resource_disk_mib = compute.as_ref().and_then(|c| c.resource_disk_mib());
+    let mut r = if resource_disk_mib == Some(0) || disks == Disks::Nvme {
+        Vec::new()
+    } else if let Some(link) = resource_disk_link {
+        vec![link]
+    } else {
+        resource_disk(inventory)
+    };
+    if disks != Disks::ResourceDisk {
+        r.extend(
+            inventory
+                .iter()
+                .filter(|dev| dev.model.as_deref().is_some_and(|m| m.trim() == NVME_MODEL))
+                .map(|dev| dev.path()),
+        );
+    }
+    if let (Some(c), true) = (compute.as_ref(), r.is_empty()) {
+        match resource_disk_mib {
+            Some(0) => println!("Azure VM size {} has no temporary disk", c.vm_size),
+            Some(mib) => eprintln!(
+                "warning: Azure VM size {} has a {} MiB temporary disk, but it was not found",
+                c.vm_size, mib
+            ),
+            None => {}
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed from IMDS on a Standard_D2s_v3; v5 sizes without a
+    /// temporary disk report a size of 0.
+    const IMDS_COMPUTE: &str = r#"{
+    "azEnvironment": "AzurePublicCloud",
+    "location": "westeurope",
+    "name": "worker-0",
+    "offer": "",
+    "osType": "Linux",
+    "priority": "Regular",
+    "provider": "Microsoft.Compute",
+    "resourceGroupName": "cluster-rg",
+    "storageProfile": {
+        "dataDisks": [],
+        "imageReference": {
+            "id": "/subscriptions/00000000-0000-0000-0000-000000000000/resourceGroups/cluster-rg/providers/Microsoft.Compute/images/rhcos",
+            "offer": "",
+            "publisher": "",
+            "sku": "",
+            "version": ""
+        },
+        "osDisk": {
+            "caching": "ReadWrite",
+            "createOption": "FromImage",
+            "diskSizeGB": "128",
+            "managedDisk": {
+                "id": "/subscriptions/00000000-0000-0000-0000-000000000000/resourceGroups/cluster-rg/providers/Microsoft.Compute/disks/worker-0_OSDisk",
+                "storageAccountType": "Premium_LRS"
+            },
+            "name": "worker-0_OSDisk",
+            "osType": "Linux",
+            "writeAcceleratorEnabled": "false"
+        },
+        "resourceDisk": {
+            "size": "16384"
+        }
+    },
+    "vmId": "02aab8a4-74ef-476e-8182-f6d2ba4166a6",
+    "vmSize": "Standard_D2s_v3",
+    "zone": "1"
+}"#;
+
+    #[test]
+    fn parse_imds_compute() {
+        let c: Compute = serde_json::from_str(IMDS_COMPUTE).unwrap();
+        assert_eq!(c.vm_size, "Standard_D2s_v3");
+        let disk = c.storage_profile.resource_disk.as_ref().unwrap();
+        assert_eq!(disk.size, "16384");
+        let c: Compute = serde_json::from_str(r#"{"vmSize": "Standard_D2s_v5"}"#).unwrap();
+        assert!(c.storage_profile.resource_disk.is_none());
+    }
+}
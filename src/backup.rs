@@ -0,0 +1,284 @@
+//! Copies of everything we destroy on the instance devices, so that
+//! if our device matching was wrong there's some hope of recovery.
+//!
+//! Backups are kept per provisioning generation, with a directory for
+//! each device named after its serial number and WWN: kernel names such
+//! as nvme1n1 can refer to a different device after a reboot.
+
+use super::*;
+use std::io::{Seek, SeekFrom, Write as IoWrite};
+use std::path::PathBuf;
+
+const SUBDIR: &str = "backup";
+/// Backups from older generations are removed.
+const KEEP_GENERATIONS: u64 = 3;
+/// Records which device a backup was taken from.
+const DEVICE_FILE: &str = "device.json";
+
+pub(crate) fn dir() -> PathBuf {
+    Path::new(STATE_DIR).join(SUBDIR)
+}
+
+/// The name of the backup directory for `rec`.
+fn device_key(rec: &state::RecordedDevice) -> String {
+    let key = match (rec.serial.as_deref(), rec.wwn.as_deref()) {
+        (None, None) => format!("name-{}", rec.path.trim_start_matches("/dev/")),
+        (serial, wwn) => [serial, wwn]
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>()
+            .join("-"),
+    };
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Whether the backups in directory `name` are old enough to be removed,
+/// when provisioning `generation`.
+fn expired(name: &str, generation: u64) -> bool {
+    name.parse::<u64>()
+        .is_ok_and(|g| g.saturating_add(KEEP_GENERATIONS) <= generation)
+}
+
+/// The device identity recorded for `path`, resolving symlinks.
+fn identify(path: &str, blockdevs: &[block::Device]) -> Result<state::RecordedDevice> {
+    let resolved = std::fs::canonicalize(path).with_context(|| format!("Resolving {}", path))?;
+    let resolved = resolved
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid device {:?}", resolved))?;
+    Ok(state::RecordedDevice::new(resolved, blockdevs))
+}
+
+/// Back up LVM metadata and signatures of `devices`, then wipe them.
+pub(crate) fn wipe_devices(devices: &[String]) -> Result<()> {
+    let generation = state::generation()? + 1;
+    let gendir = dir().join(generation.to_string());
+    std::fs::create_dir_all(&gendir).with_context(|| format!("Creating {:?}", gendir))?;
+    lvm::backup_metadata(devices, &gendir).context("Backing up LVM metadata")?;
+    let blockdevs = block::list()?;
+    failure::record_wipe();
+    for dev in devices {
+        let rec = identify(dev, &blockdevs)?;
+        let devdir = gendir.join(device_key(&rec));
+        std::fs::create_dir_all(&devdir).with_context(|| format!("Creating {:?}", devdir))?;
+        std::fs::write(devdir.join(DEVICE_FILE), serde_json::to_vec(&rec)?)?;
+        block::wipefs(&[dev], &devdir).with_context(|| format!("Wiping {}", dev))?;
+        events::log(
+            events::Event::WipedDevice,
+            &format!("Wiped {}", dev),
+            &[("DEVICE", dev)],
+        );
+    }
+    for entry in std::fs::read_dir(dir())? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_str()
+            .is_some_and(|n| expired(n, generation))
+        {
+            std::fs::remove_dir_all(entry.path())
+                .with_context(|| format!("Removing {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse a wipefs backup filename `wipefs-<devname>-<offset>.bak`.
+fn parse_signature_backup(name: &str) -> Option<(&str, u64)> {
+    let (devname, offset) = name
+        .strip_prefix("wipefs-")?
+        .strip_suffix(".bak")?
+        .rsplit_once('-')?;
+    let offset = u64::from_str_radix(offset.strip_prefix("0x")?, 16).ok()?;
+    Some((devname, offset))
+}
+
+/// Sorted entries of `dir`.
+fn entries(dir: &Path) -> Result<Vec<std::fs::DirEntry>> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Reading {:?}", dir))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    Ok(entries)
+}
+
+/// Find the most recent backup of `dev`; returns the generation directory
+/// and the device's directory in it.
+fn find_backup(dev: &block::Device) -> Result<(PathBuf, PathBuf)> {
+    let dir = dir();
+    let mut generations = Vec::new();
+    if dir.exists() {
+        for entry in entries(&dir)? {
+            if let Some(g) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                generations.push((g, entry.path()));
+            }
+        }
+    }
+    generations.sort();
+    let path = dev.path();
+    // The most recent backup taken under the device's current name, if
+    // it's from another device; only used to explain why we refuse.
+    let mut other = None;
+    for (_, gendir) in generations.into_iter().rev() {
+        for entry in entries(&gendir)? {
+            let recpath = entry.path().join(DEVICE_FILE);
+            if !recpath.exists() {
+                continue;
+            }
+            let buf = std::fs::read(&recpath).with_context(|| format!("Reading {:?}", recpath))?;
+            let rec: state::RecordedDevice =
+                serde_json::from_slice(&buf).with_context(|| format!("Parsing {:?}", recpath))?;
+            if (rec.serial.is_some() || rec.wwn.is_some()) && rec.matches(dev) {
+                return Ok((gendir, entry.path()));
+            }
+            if rec.path == path && other.is_none() {
+                other = Some((rec, entry.path()));
+            }
+        }
+    }
+    match other {
+        Some((rec, backup)) if rec.serial.is_none() && rec.wwn.is_none() => bail!(
+            "{} had no serial number or WWN when backed up, so it can't be verified; \
+             restore manually from {:?} as described in wipefs(8)",
+            path,
+            backup
+        ),
+        Some((rec, _)) => bail!(
+            "The backup for {} is from a different device (serial {}, WWN {}); refusing to restore",
+            path,
+            rec.serial.as_deref().unwrap_or("none"),
+            rec.wwn.as_deref().unwrap_or("none")
+        ),
+        None => bail!(
+            "No backups found in {:?} for {} (serial {})",
+            dir,
+            path,
+            dev.serial.as_deref().map(str::trim).unwrap_or("none")
+        ),
+    }
+}
+
+/// Write signatures saved from `device` back onto it; this is
+/// the equivalent of the `dd` invocation documented in wipefs(8).
+/// The device is identified by its serial number and WWN, as its
+/// kernel name may since have been given to a different device.
+pub(crate) fn restore_signatures(device: &str) -> Result<()> {
+    let blockdevs = block::list()?;
+    let rec = identify(device, &blockdevs)?;
+    let dev = blockdevs
+        .iter()
+        .find(|d| d.path() == rec.path)
+        .ok_or_else(|| anyhow!("{} is not a whole block device", device))?;
+    let (gendir, devdir) = find_backup(dev)?;
+    let devpath = dev.path();
+    let mut n_restored = 0;
+    for entry in entries(&devdir)? {
+        let name = entry.file_name();
+        let offset = if let Some((_, offset)) = name.to_str().and_then(parse_signature_backup) {
+            offset
+        } else {
+            continue;
+        };
+        let buf = std::fs::read(entry.path())?;
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&devpath)
+            .with_context(|| format!("Opening {}", devpath))?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(&buf)?;
+        f.sync_all()?;
+        println!(
+            "Restored {} byte signature at offset {:#x} on {}",
+            buf.len(),
+            offset,
+            devpath
+        );
+        n_restored += 1;
+    }
+    println!("Restored {} signatures from {:?}", n_restored, devdir);
+    for entry in entries(&gendir)? {
+        let name = entry.file_name();
+        if let Some(vg) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("lvm-"))
+            .and_then(|n| n.strip_suffix(".vg"))
+        {
+            println!(
+                "LVM metadata for {}: restore with `vgcfgrestore -f {:?} {}`",
+                vg,
+                entry.path(),
+                vg
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_backup_names() {
+        assert_eq!(
+            parse_signature_backup("wipefs-nvme1n1-0x00000438.bak"),
+            Some(("nvme1n1", 0x438))
+        );
+        assert_eq!(
+            parse_signature_backup("wipefs-sda1-0x1fe.bak"),
+            Some(("sda1", 0x1fe))
+        );
+        // Only the last dash separates the offset.
+        assert_eq!(
+            parse_signature_backup("wipefs-dm-0-0x0.bak"),
+            Some(("dm-0", 0))
+        );
+        for name in [
+            "lvm-vg.vg",
+            "wipefs-sda-438.bak",
+            "wipefs-sda-0x438",
+            "wipefs-sda-0xzz.bak",
+            "wipefs-0x438.bak",
+        ] {
+            assert_eq!(parse_signature_backup(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn device_keys() {
+        let rec = |serial: Option<&str>, wwn: Option<&str>| state::RecordedDevice {
+            path: "/dev/nvme1n1".into(),
+            serial: serial.map(Into::into),
+            wwn: wwn.map(Into::into),
+        };
+        assert_eq!(
+            device_key(&rec(Some("AWS1234"), Some("eui.0123"))),
+            "AWS1234-eui.0123"
+        );
+        assert_eq!(device_key(&rec(Some("a b/c"), None)), "a_b_c");
+        assert_eq!(device_key(&rec(None, Some("0x5000"))), "0x5000");
+        assert_eq!(device_key(&rec(None, None)), "name-nvme1n1");
+    }
+
+    #[test]
+    fn expired_generations() {
+        assert!(expired("1", 4));
+        assert!(!expired("2", 4));
+        assert!(!expired("4", 4));
+        assert!(!expired("1", 1));
+        assert!(!expired("lvm-vg.vg", 10));
+        assert!(!expired("wipefs-sda-0x1fe.bak", 10));
+    }
+}
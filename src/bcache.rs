@@ -0,0 +1,140 @@
+use super::*;
+use std::path::PathBuf;
+
+const REGISTER_PATH: &str = "/sys/fs/bcache/register";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Settings {
+    /// The persistent device to cache; if it's blank, it's formatted
+    /// as a bcache backing device.
+    pub(crate) backing: String,
+    #[serde(default)]
+    cache_mode: CacheMode,
+    /// If set, mount the bcache device here, creating a filesystem
+    /// if it has none.
+    #[serde(rename = "where")]
+    pub(crate) where_: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CacheMode {
+    /// The backing device is always complete, so losing the cache is harmless.
+    #[default]
+    Writethrough,
+    Writeback,
+    Writearound,
+}
+
+impl CacheMode {
+    fn name(self) -> &'static str {
+        match self {
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::Writeback => "writeback",
+            CacheMode::Writearound => "writearound",
+        }
+    }
+}
+
+/// The bcache sysfs directory of a backing device.
+fn sysfs_dir(backing: &str) -> Result<PathBuf> {
+    let dev = std::fs::canonicalize(backing).with_context(|| format!("Resolving {}", backing))?;
+    let name = dev
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid device {:?}", dev))?;
+    Ok(Path::new("/sys/class/block").join(name).join("bcache"))
+}
+
+fn write_sysfs(path: &Path, value: &str) -> Result<()> {
+    std::fs::write(path, value).with_context(|| format!("Writing {} to {:?}", value, path))
+}
+
+/// Register a device with the kernel, in case udev didn't (yet).
+fn register(dev: &str) {
+    // Fails if it's registered already.
+    let _ = std::fs::write(REGISTER_PATH, dev);
+}
+
+fn cset_uuid(cache_dev: &str) -> Result<String> {
+    let o = Command::new("bcache-super-show").arg(cache_dev).output()?;
+    if !o.status.success() {
+        bail!("Failed to read bcache superblock of {}", cache_dev);
+    }
+    String::from_utf8_lossy(&o.stdout)
+        .lines()
+        .find_map(|l| l.strip_prefix("cset.uuid"))
+        .map(|v| v.trim().to_string())
+        .ok_or_else(|| anyhow!("No cache set UUID found on {}", cache_dev))
+}
+
+/// Create a cache set on `cache_dev` and attach it to the backing
+/// device; returns the path of the bcache device.
+pub(crate) fn attach(settings: &Settings, cache_dev: &str) -> Result<String> {
+    let backing = settings.backing.as_str();
+    match block::probe(backing, "TYPE")?.as_deref() {
+        None => Command::new("make-bcache").arg("-B").arg(backing).run()?,
+        Some("bcache") => {}
+        Some(t) => bail!("{} holds {}, not bcache; refusing to format it", backing, t),
+    }
+    Command::new("make-bcache").arg("-C").arg(cache_dev).run()?;
+    Command::new("udevadm").arg("settle").run()?;
+    register(backing);
+    register(cache_dev);
+    let dir = sysfs_dir(backing)?;
+    write_sysfs(&dir.join("attach"), &cset_uuid(cache_dev)?)?;
+    write_sysfs(&dir.join("cache_mode"), settings.cache_mode.name())?;
+    let dev = std::fs::read_link(dir.join("dev"))?;
+    let name = dev
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid bcache device {:?}", dev))?;
+    Ok(Path::new("/dev")
+        .join(name)
+        .to_str()
+        .expect("utf8")
+        .to_string())
+}
+
+/// A backing device whose cache is gone doesn't start on its own;
+/// force it to, and detach the cache.  This is best-effort; errors
+/// are only logged.
+pub(crate) fn detach(backing: &str) {
+    let r = sysfs_dir(backing).and_then(|dir| {
+        register(backing);
+        write_sysfs(&dir.join("running"), "1")?;
+        write_sysfs(&dir.join("detach"), "1")
+    });
+    if let Err(e) = r {
+        eprintln!("warning: Detaching cache from {}: {:#}", backing, e);
+    }
+}
+
+/// Write and enable a mount unit for the bcache device `dev`.  It's
+/// ordered after provisioning (and not part of local-fs.target), so
+/// that a lost cache has been detached first.
+pub(crate) fn mount(
+    dev: &str,
+    where_: &str,
+    filesystem: Filesystem,
+    install: systemd::Install,
+) -> Result<String> {
+    if block::probe(dev, "TYPE")?.is_none() {
+        throttle::apply(&mut filesystem.mkfs(None)).arg(dev).run()?;
+    }
+    let uuid =
+        block::probe(dev, "UUID")?.ok_or_else(|| anyhow!("No filesystem UUID found on {}", dev))?;
+    let fstype = block::probe(dev, "TYPE")?.unwrap_or_default();
+    let what = format!("/dev/disk/by-uuid/{}", uuid);
+    std::fs::create_dir_all(where_)?;
+    let wanted_by = ["multi-user.target".to_string()];
+    let after = [systemd::SERVICE_NAME.to_string()];
+    let unit = systemd::MountUnit {
+        wanted_by: &wanted_by,
+        after: &after,
+        ..systemd::MountUnit::new(&what, where_, &fstype)
+    }
+    .write(install.unit_dir)?;
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    install.enable(&unit, true)?;
+    Ok(unit)
+}
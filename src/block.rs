@@ -0,0 +1,386 @@
+use super::*;
+
+#[derive(Debug, Deserialize)]
+struct DevicesOutput {
+    blockdevices: Vec<Device>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Device {
+    pub(crate) name: String,
+    pub(crate) serial: Option<String>,
+    pub(crate) wwn: Option<String>,
+    pub(crate) vendor: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) label: Option<String>,
+    #[serde(default)]
+    pub(crate) partlabel: Option<String>,
+    pub(crate) fstype: Option<String>,
+    /// Logical sector size
+    #[serde(rename = "log-sec", default, deserialize_with = "de_opt_u32")]
+    pub(crate) log_sec: Option<u32>,
+    /// Physical sector size
+    #[serde(rename = "phy-sec", default, deserialize_with = "de_opt_u32")]
+    pub(crate) phy_sec: Option<u32>,
+    pub(crate) children: Option<Vec<Device>>,
+}
+
+/// Older lsblk versions (e.g. RHEL8) output all JSON values as strings.
+fn de_opt_u32<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumOrStr {
+        Num(u32),
+        Str(String),
+    }
+    match <Option<NumOrStr> as serde::Deserialize>::deserialize(d)? {
+        None => Ok(None),
+        Some(NumOrStr::Num(n)) => Ok(Some(n)),
+        Some(NumOrStr::Str(s)) => s.trim().parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// The sector sizes of a set of devices.
+#[derive(Debug, Default)]
+pub(crate) struct SectorSizes {
+    /// The largest logical sector size.
+    pub(crate) logical: u32,
+    /// The largest physical sector size.
+    pub(crate) physical: u32,
+    /// True if the devices differ in logical sector size (e.g. 4Kn and 512e).
+    pub(crate) mixed: bool,
+}
+
+pub(crate) fn sector_sizes(devices: &[String]) -> Result<SectorSizes> {
+    let all = list()?;
+    let mut r = SectorSizes::default();
+    let mut first_logical = None;
+    for dev in all.iter().filter(|d| devices.contains(&d.path())) {
+        let logical = dev.log_sec.unwrap_or(512);
+        let physical = dev.phy_sec.unwrap_or(logical);
+        if *first_logical.get_or_insert(logical) != logical {
+            r.mixed = true;
+        }
+        r.logical = r.logical.max(logical);
+        r.physical = r.physical.max(physical);
+    }
+    Ok(r)
+}
+
+impl Device {
+    // RHEL8's lsblk doesn't have PATH, so we do it
+    pub(crate) fn path(&self) -> String {
+        format!("/dev/{}", &self.name)
+    }
+
+    /// True if the device has no partitions, holders or filesystem signature.
+    pub(crate) fn is_unused(&self) -> bool {
+        self.fstype.is_none() && self.children.as_ref().is_none_or(|c| c.is_empty())
+    }
+}
+
+/// The entries of `inventory` (including nested ones, such as partitions or
+/// device-mapper targets) for `paths`, which may be symlinks to the devices.
+/// The returned entries are renamed as needed so that `Device::path` gives
+/// a usable path, e.g. `/dev/mapper/<name>` for device-mapper targets.
+pub(crate) fn resolve(inventory: &[Device], paths: &[String]) -> Result<Vec<Device>> {
+    fn find(devs: &[Device], target: &Path) -> Option<Device> {
+        devs.iter().find_map(|d| {
+            let candidates = vec![d.name.clone(), format!("mapper/{}", d.name)];
+            candidates
+                .into_iter()
+                .find(|n| {
+                    std::fs::canonicalize(Path::new("/dev").join(n)).is_ok_and(|p| p == target)
+                })
+                .map(|name| Device { name, ..d.clone() })
+                .or_else(|| find(d.children.as_deref().unwrap_or_default(), target))
+        })
+    }
+    paths
+        .iter()
+        .map(|path| {
+            let target =
+                std::fs::canonicalize(path).with_context(|| format!("Resolving {}", path))?;
+            find(inventory, &target).ok_or_else(|| anyhow!("{} not found by lsblk", path))
+        })
+        .collect()
+}
+
+/// The size of `dev` in bytes.
+pub(crate) fn capacity(dev: &str) -> Result<u64> {
+    let path = std::fs::canonicalize(dev).with_context(|| format!("Resolving {}", dev))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid device {:?}", path))?;
+    let sizepath = Path::new("/sys/class/block").join(name).join("size");
+    let sectors: u64 = std::fs::read_to_string(&sizepath)
+        .with_context(|| format!("Reading {:?}", sizepath))?
+        .trim()
+        .parse()?;
+    // Always in 512-byte units, regardless of the sector size.
+    Ok(sectors * 512)
+}
+
+const BY_ID_DIR: &str = "/dev/disk/by-id";
+
+/// A path for `dev` which is stable across boots; kernel names such
+/// as `/dev/nvme1n1` are replaced with a link in `/dev/disk/by-id`.
+pub(crate) fn stable_path(dev: &str) -> Result<String> {
+    // E.g. /dev/vg/lv or /dev/md/ccisp are stable already.
+    if dev.matches('/').count() != 2 {
+        return Ok(dev.to_string());
+    }
+    let target = std::fs::canonicalize(dev).with_context(|| format!("Resolving {}", dev))?;
+    let mut links: Vec<_> = match std::fs::read_dir(BY_ID_DIR) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| std::fs::canonicalize(p).is_ok_and(|p| p == target))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", BY_ID_DIR)),
+    };
+    links.sort();
+    Ok(links
+        .first()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| dev.to_string()))
+}
+
+/// The value of a blkid tag (e.g. `TYPE` or `UUID`) of `dev`, if any.
+pub(crate) fn probe(dev: &str, tag: &str) -> Result<Option<String>> {
+    let o = Command::new("blkid")
+        .args(["-s", tag, "-o", "value", dev])
+        .output()?;
+    let v = String::from_utf8_lossy(&o.stdout).trim().to_string();
+    Ok(Some(v).filter(|v| o.status.success() && !v.is_empty()))
+}
+
+/// Discard the entire contents of the given devices.
+pub(crate) fn discard(devs: &[String]) -> Result<()> {
+    for dev in devs {
+        throttle::apply(&mut Command::new("blkdiscard"))
+            .arg(dev)
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Mount points in `mountinfo` (as in /proc/self/mountinfo) of the
+/// devices numbered `devnums` (as `major:minor`), most recent first.
+fn mounts_of(mountinfo: &str, devnums: &[String]) -> Vec<String> {
+    let mut r: Vec<String> = mountinfo
+        .lines()
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split(' ').collect();
+            let (devnum, mountpoint) = (fields.get(2)?, fields.get(4)?);
+            devnums
+                .iter()
+                .any(|d| d == devnum)
+                .then(|| unescape_octal(mountpoint))
+        })
+        .collect();
+    r.reverse();
+    r
+}
+
+/// Undo the `\040`-style escaping of paths in /proc.
+fn unescape_octal(s: &str) -> String {
+    let mut r = Vec::new();
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        let code = b
+            .get(i + 1..i + 4)
+            .and_then(|o| std::str::from_utf8(o).ok())
+            .filter(|_| b[i] == b'\\')
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+        match code {
+            Some(c) => {
+                r.push(c);
+                i += 4;
+            }
+            None => {
+                r.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&r).into_owned()
+}
+
+/// Unmount filesystems and disable swap on `devs` and everything stacked
+/// on them (logical volumes, md arrays, dm-crypt), as left behind by an
+/// earlier attempt which failed before recording its state.  Otherwise
+/// they'd keep the devices busy, and wiping them would fail.
+pub(crate) fn release(devs: &[String]) -> Result<()> {
+    if devs.is_empty() {
+        return Ok(());
+    }
+    let o = Command::new("lsblk")
+        .args(["-nrpo", "NAME,MAJ:MIN"])
+        .args(devs)
+        .output()?;
+    if !o.status.success() {
+        bail!("Failed to list holders of {}", devs.join(" "));
+    }
+    let mut names = Vec::new();
+    let mut devnums = Vec::new();
+    for l in String::from_utf8_lossy(&o.stdout).lines() {
+        if let Some((name, devnum)) = l.split_once(' ') {
+            names.push(std::fs::canonicalize(name).unwrap_or_else(|_| name.into()));
+            devnums.push(devnum.trim().to_string());
+        }
+    }
+    let swaps = std::fs::read_to_string("/proc/swaps").context("Reading /proc/swaps")?;
+    for swap in swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+    {
+        let swap = unescape_octal(swap);
+        if std::fs::canonicalize(&swap).is_ok_and(|p| names.contains(&p)) {
+            println!("Disabling swap on {} from a previous attempt", swap);
+            Command::new("swapoff").arg(&swap).run()?;
+        }
+    }
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").context("Reading mountinfo")?;
+    for mountpoint in mounts_of(&mountinfo, &devnums) {
+        println!("Unmounting {} from a previous attempt", mountpoint);
+        Command::new("umount").arg(&mountpoint).run()?;
+    }
+    Ok(())
+}
+
+/// Erase all signatures from the given devices in a single invocation.
+/// A copy of each erased signature is saved into `backupdir`
+/// as `wipefs-<devname>-<offset>.bak`.
+pub(crate) fn wipefs<S: AsRef<str>>(devs: &[S], backupdir: &Path) -> Result<()> {
+    if devs.is_empty() {
+        return Ok(());
+    }
+    // Newer util-linux has --backup=<dir>, but RHEL8's only writes to $HOME.
+    Command::new("wipefs")
+        .args(["--all", "--backup"])
+        .args(devs.iter().map(|d| d.as_ref()))
+        .env("HOME", backupdir)
+        .run()?;
+    Ok(())
+}
+
+/// Set once we start modifying devices, from which point the
+/// snapshot in the discovery cache is never used.
+static MODIFYING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Called before we wipe, partition or format anything (or tear down
+/// a previous setup), as the cached snapshot would then be stale.
+pub(crate) fn start_modifying() {
+    MODIFYING.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// List block devices, using the snapshot from the discovery cache
+/// if it's still valid and we haven't modified any devices since.
+pub(crate) fn list() -> Result<Vec<Device>> {
+    if let Some(devs) = selftest::blockdevices() {
+        return Ok(devs);
+    }
+    if MODIFYING.load(std::sync::atomic::Ordering::SeqCst) {
+        // lsblk reads the udev database, which lags behind our changes.
+        Command::new("udevadm").arg("settle").run()?;
+        return lsblk();
+    }
+    if let Some(devs) = cache::load()?.and_then(|c| c.blockdevices) {
+        return Ok(devs);
+    }
+    let devs = lsblk()?;
+    cache::update(|c| c.blockdevices = Some(devs.clone()))?;
+    Ok(devs)
+}
+
+/// Paths of all devices whose model is exactly `model`.
+pub(crate) fn with_model(model: &str) -> Result<Vec<String>> {
+    Ok(list()?
+        .into_iter()
+        .filter(|dev| dev.model.as_ref().filter(|m| m.trim() == model).is_some())
+        .map(|dev| dev.path())
+        .collect())
+}
+
+/// Paths of all devices whose serial number starts with `prefix`.
+pub(crate) fn with_serial_prefix(prefix: &str) -> Result<Vec<String>> {
+    Ok(list()?
+        .into_iter()
+        .filter(|dev| {
+            dev.serial
+                .as_ref()
+                .filter(|serial| serial.trim().starts_with(prefix))
+                .is_some()
+        })
+        .map(|dev| dev.path())
+        .collect())
+}
+
+fn lsblk() -> Result<Vec<Device>> {
+    let o = Command::new("lsblk")
+        .args([
+            "-J",
+            "-o",
+            "NAME,SERIAL,WWN,VENDOR,MODEL,LABEL,PARTLABEL,FSTYPE,LOG-SEC,PHY-SEC",
+        ])
+        .output()?;
+    if !o.status.success() {
+        bail!("Failed to list block devices");
+    }
+    parse(&o.stdout)
+}
+
+/// Parse `lsblk -J` output.
+pub(crate) fn parse(buf: &[u8]) -> Result<Vec<Device>> {
+    let devs: DevicesOutput = serde_json::from_slice(buf)?;
+    Ok(devs
+        .blockdevices
+        .into_iter()
+        .filter(|d| !is_nvme_path_device(&d.name))
+        .collect())
+}
+
+/// With native NVMe multipath, each path to a namespace is a (normally
+/// hidden) `nvme<subsys>c<ctrl>n<ns>` device, with the usable head device
+/// being `nvme<subsys>n<ns>`.  Only the head should ever be used, otherwise
+/// we'd count the same namespace multiple times.
+fn is_nvme_path_device(name: &str) -> bool {
+    let rest = match name.strip_prefix("nvme") {
+        Some(r) => r,
+        None => return false,
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match rest
+        .split_once('c')
+        .map(|(subsys, r)| (subsys, r.split_once('n')))
+    {
+        Some((subsys, Some((ctrl, ns)))) => digits(subsys) && digits(ctrl) && digits(ns),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts() {
+        let mountinfo = "\
+22 1 252:1 / / rw,relatime shared:1 - xfs /dev/vda1 rw
+97 22 253:0 / /var/mnt/instance\\040storage rw,relatime shared:50 - xfs /dev/mapper/vg-lv rw
+98 22 253:0 /containers /var/lib/containers rw,relatime shared:50 - xfs /dev/mapper/vg-lv rw
+99 22 259:1 / /var/log rw,relatime shared:51 - ext4 /dev/nvme1n1 rw
+";
+        let devnums = ["253:0".to_string(), "259:0".to_string()];
+        assert_eq!(
+            mounts_of(mountinfo, &devnums),
+            ["/var/lib/containers", "/var/mnt/instance storage"]
+        );
+        assert!(mounts_of(mountinfo, &[]).is_empty());
+        assert_eq!(unescape_octal("a\\011b\\134c\\0"), "a\tb\\c\\0");
+    }
+}
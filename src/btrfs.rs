@@ -0,0 +1,61 @@
+//! Subvolumes and qgroups, with `filesystem: btrfs`.
+
+use super::*;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// The root directory of every subvolume has this inode number.
+const SUBVOLUME_INO: u64 = 256;
+
+fn is_subvolume(path: &Path) -> Result<bool> {
+    let m = std::fs::metadata(path).with_context(|| format!("Querying {:?}", path))?;
+    Ok(m.is_dir() && m.ino() == SUBVOLUME_INO)
+}
+
+/// Create a subvolume at `path`, unless a previous attempt already did.
+pub(crate) fn ensure_subvolume(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Command::new("btrfs")
+            .args(["subvolume", "create"])
+            .arg(path)
+            .run();
+    }
+    if !is_subvolume(path)? {
+        bail!("{:?} exists, but is not a subvolume", path);
+    }
+    Ok(())
+}
+
+/// Subvolumes directly below `dir`, by name.
+pub(crate) fn subvolumes(dir: &Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for e in std::fs::read_dir(dir).with_context(|| format!("Reading {:?}", dir))? {
+        let e = e?;
+        if is_subvolume(&e.path())? {
+            r.push(e.file_name().to_string_lossy().into_owned());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Apply the limits (in bytes) of each (directory, subvolume, limit)
+/// entry as qgroup limits; quotas are only enabled if needed, since
+/// they have a performance cost.
+pub(crate) fn limit(mountpoint: &str, dirs: &[(String, PathBuf, Option<u64>)]) -> Result<()> {
+    if dirs.iter().all(|(_, _, limit)| limit.is_none()) {
+        return Ok(());
+    }
+    Command::new("btrfs")
+        .args(["quota", "enable", mountpoint])
+        .run()?;
+    for (_, subvol, limit) in dirs {
+        if let Some(limit) = limit {
+            Command::new("btrfs")
+                .args(["qgroup", "limit", &limit.to_string()])
+                .arg(subvol)
+                .run()?;
+        }
+    }
+    Ok(())
+}
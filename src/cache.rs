@@ -0,0 +1,107 @@
+//! Discovery results (and the block device snapshot they were computed
+//! from) are cached in /run, so that repeated invocations during the same
+//! boot don't need to rescan.  The cache is invalidated by a new boot ID,
+//! by udev having processed events since it was written, or by a change
+//! to the config file, which can name devices explicitly.  Once provisioning
+//! starts modifying devices, the snapshot isn't used for the rest of the run.
+
+use super::*;
+
+const CACHE_FILE: &str = "discovery.json";
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+/// udev updates entries in this directory for every processed event.
+const UDEV_DATA_DIR: &str = "/run/udev/data";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Cache {
+    boot_id: String,
+    udev_stamp: Option<u128>,
+    config_digest: Option<String>,
+    pub(crate) blockdevices: Option<Vec<block::Device>>,
+    pub(crate) platform: Option<String>,
+    pub(crate) devices: Option<Vec<String>>,
+}
+
+fn current_key() -> Result<(String, Option<u128>, Option<String>)> {
+    let boot_id = std::fs::read_to_string(BOOT_ID_PATH)
+        .with_context(|| format!("Reading {}", BOOT_ID_PATH))?
+        .trim()
+        .to_string();
+    let udev_stamp = match std::fs::metadata(UDEV_DATA_DIR) {
+        Ok(m) => Some(
+            m.modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_nanos(),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    Ok((boot_id, udev_stamp, config_digest()?))
+}
+
+/// Load the cache, if it exists and is still valid.
+pub(crate) fn load() -> Result<Option<Cache>> {
+    let path = Path::new(RUN_DIR).join(CACHE_FILE);
+    let f = match std::fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Opening {:?}", path)),
+    };
+    // Events still queued would change the udev stamp once processed.
+    Command::new("udevadm").arg("settle").run()?;
+    // A corrupted cache is just a cache miss.
+    let cache: Cache = match serde_json::from_reader(std::io::BufReader::new(f)) {
+        Ok(c) => c,
+        Err(_) => return Ok(None),
+    };
+    let (boot_id, udev_stamp, config_digest) = current_key()?;
+    if cache.boot_id != boot_id
+        || cache.udev_stamp != udev_stamp
+        || cache.config_digest != config_digest
+    {
+        return Ok(None);
+    }
+    Ok(Some(cache))
+}
+
+/// Modify the cache (starting from scratch if it's invalid) and write it back.
+pub(crate) fn update(f: impl FnOnce(&mut Cache)) -> Result<()> {
+    let mut cache = load()?.unwrap_or_default();
+    let (boot_id, udev_stamp, config_digest) = current_key()?;
+    cache.boot_id = boot_id;
+    cache.udev_stamp = udev_stamp;
+    cache.config_digest = config_digest;
+    f(&mut cache);
+    std::fs::create_dir_all(RUN_DIR)?;
+    let dir = openat::Dir::open(RUN_DIR)?;
+    dir.write_file_with(CACHE_FILE, 0o644, |w| -> Result<()> {
+        serde_json::to_writer(w, &cache)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Find the instance devices for `platform`, using the cached result
+/// if possible.  `None` means the platform is not supported.
+pub(crate) fn discover(
+    platform: &str,
+    f: impl FnOnce(&str) -> Result<Option<Vec<String>>>,
+) -> Result<Option<Vec<String>>> {
+    if let Some(cache) = load()? {
+        if cache.platform.as_deref() == Some(platform) {
+            if let Some(devices) = cache.devices {
+                println!("Using cached device discovery");
+                return Ok(Some(devices));
+            }
+        }
+    }
+    let devices = f(platform)?;
+    if let Some(devices) = devices.as_ref() {
+        update(|c| {
+            c.platform = Some(platform.to_string());
+            c.devices = Some(devices.clone());
+        })?;
+    }
+    Ok(devices)
+}
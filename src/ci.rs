@@ -0,0 +1,74 @@
+//! With `ci`, the directories CI farm nodes churn through: root's home
+//! (where most CI agents run and cache things), `/var/cache`, and a
+//! workspace, with stale files cleaned up by systemd-tmpfiles-clean.timer.
+
+use super::*;
+
+const ROOTHOME: &str = "/var/roothome";
+const CACHE: &str = "/var/cache";
+const DEFAULT_WORKSPACE: &str = "/var/ci";
+const DEFAULT_MAX_AGE: &str = "7d";
+const TMPFILES_NAME: &str = "ccisp-ci.conf";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Settings {
+    /// The directory CI jobs work in; defaults to `/var/ci`.
+    workspace: Option<String>,
+    /// Files in the workspace and `/var/cache` unused for this long are
+    /// removed, in systemd-tmpfiles syntax; defaults to `7d`.
+    max_age: Option<String>,
+    /// With project-quota (or btrfs), limits for the workspace and
+    /// `/var/cache`, so neither can starve the other.
+    workspace_quota: Option<String>,
+    cache_quota: Option<String>,
+}
+
+impl Settings {
+    fn workspace(&self) -> &str {
+        self.workspace.as_deref().unwrap_or(DEFAULT_WORKSPACE)
+    }
+
+    /// The directories to redirect; quotas are only set if `quotas`
+    /// are supported.
+    pub(crate) fn directories(&self, quotas: bool) -> Vec<Directory> {
+        let quota = |q: &Option<String>| q.clone().filter(|_| quotas);
+        vec![
+            Directory {
+                path: ROOTHOME.to_string(),
+                ..Default::default()
+            },
+            Directory {
+                path: CACHE.to_string(),
+                quota: quota(&self.cache_quota),
+                ..Default::default()
+            },
+            Directory {
+                path: self.workspace().to_string(),
+                quota: quota(&self.workspace_quota),
+                ..Default::default()
+            },
+        ]
+    }
+}
+
+/// Install the cleanup policy.
+pub(crate) fn setup(settings: &Settings, runtime: bool) -> Result<()> {
+    let age = settings.max_age.as_deref().unwrap_or(DEFAULT_MAX_AGE);
+    let tmpfiles = format!(
+        "# Written by coreos-cloud-instance-store-provisioner\n\
+         e {cache} - - - {age}\n\
+         e {workspace} - - - {age}\n",
+        cache = CACHE,
+        workspace = settings.workspace(),
+        age = age
+    );
+    let dir = if runtime {
+        "/run/tmpfiles.d"
+    } else {
+        "/etc/tmpfiles.d"
+    };
+    std::fs::create_dir_all(dir)?;
+    openat::Dir::open(dir)?.write_file_contents(TMPFILES_NAME, 0o644, tmpfiles)?;
+    Ok(())
+}
@@ -0,0 +1,253 @@
+//! The command line interface.
+
+use super::*;
+use provision::ProvisionOpts;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+/// Set up instance-local storage for CoreOS
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Cmd {
+    /// Provision instance storage (the default)
+    Provision(ProvisionOpts),
+    /// Write signatures erased during provisioning back to their devices
+    RestoreSignatures {
+        /// The device to restore (e.g. /dev/nvme1n1); it must still have the
+        /// serial number and WWN it had when the signatures were saved
+        device: String,
+    },
+    /// Inspect project quotas
+    Quota(QuotaCmd),
+    /// Show how I/O is spread across the instance devices
+    Stats,
+    /// Manage snapshots of the instance storage (btrfs only)
+    Snapshot(SnapshotCmd),
+    /// Check device discovery for each platform against built-in fixtures
+    SelfTest,
+    /// Check that LVM, mkfs and mounting work, on a loopback file
+    Smoke,
+    /// Report SELinux denials involving the instance storage
+    CheckSelinux,
+    /// Show what the directory policy decides for each directory, without
+    /// changing anything
+    Plan,
+    /// Check the configuration, and report conflicts with cloud-init's mounts
+    /// for the devices and directories it would use
+    Validate,
+    /// Print the configuration as parsed, with all defaults
+    Config {
+        /// Also resolve the defaults which depend on the host, such as
+        /// the mountpoint and directories
+        #[structopt(long)]
+        effective: bool,
+        /// Print JSON instead of YAML
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Print the journal catalog for the events we log
+    Catalog,
+    /// Show the provisioning generation and whether it's from the current config
+    Status {
+        /// Print JSON
+        #[structopt(long)]
+        json: bool,
+        /// Print Prometheus metrics
+        #[structopt(long, conflicts_with = "json")]
+        prometheus: bool,
+    },
+    /// Write and enable a systemd unit running this program at boot
+    InstallService {
+        /// Only run on the first boot of the machine
+        #[structopt(long)]
+        first_boot_only: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum QuotaCmd {
+    /// Show per-directory usage and limits
+    Status,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum SnapshotCmd {
+    /// Take a read-only snapshot
+    Create { name: String },
+    /// List snapshots
+    List,
+    /// Delete a snapshot
+    Delete { name: String },
+}
+
+/// Parse the command line and run the requested command.
+pub fn run() -> Result<()> {
+    let opt = Opt::from_args();
+    match opt
+        .cmd
+        .unwrap_or_else(|| Cmd::Provision(Default::default()))
+    {
+        Cmd::Provision(opts) => {
+            // We may be triggered both at boot and by the path unit when the
+            // config file appears; serialize concurrent invocations.  Once the
+            // first one is done, the second will find the storage provisioned.
+            let _lock = lock()?;
+            failure::clear()?;
+            provision::run(&opts).inspect_err(|e| {
+                if let Err(report_err) = failure::write_report(e) {
+                    eprintln!("Failed to write failure report: {:#}", report_err);
+                }
+            })
+        }
+        Cmd::RestoreSignatures { device } => backup::restore_signatures(&device),
+        Cmd::Quota(QuotaCmd::Status) => {
+            let config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+            quota::status(&config, config.mountpoint(profile::detect()?))
+        }
+        Cmd::Stats => stats::print(),
+        Cmd::Snapshot(cmd) => {
+            let config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+            let mountpoint = config.mountpoint(profile::detect()?);
+            match cmd {
+                SnapshotCmd::Create { name } => snapshot::create(&config, mountpoint, &name),
+                SnapshotCmd::List => snapshot::list(&config, mountpoint),
+                SnapshotCmd::Delete { name } => snapshot::delete(&config, mountpoint, &name),
+            }
+        }
+        Cmd::SelfTest => selftest::run(),
+        Cmd::Smoke => smoke::run(),
+        Cmd::Status { json, prometheus } => state::status(json, prometheus),
+        Cmd::Catalog => {
+            print!("{}", events::catalog());
+            Ok(())
+        }
+        Cmd::CheckSelinux => {
+            let config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+            avc::check(&config, profile::detect()?)
+        }
+        Cmd::InstallService { first_boot_only } => install_service(first_boot_only),
+        Cmd::Plan => plan(),
+        Cmd::Validate => validate(),
+        Cmd::Config { effective, json } => print_config(effective, json),
+    }
+}
+
+fn print_config(effective: bool, json: bool) -> Result<()> {
+    let mut config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+    if effective {
+        config.validate()?;
+        let profile = profile::detect()?;
+        let mountpoint = config.mountpoint(profile).to_string();
+        config.mountpoint = Some(mountpoint);
+        config.label = Some(config.label().to_string());
+        config.directories.get_or_insert_with(|| {
+            profile
+                .default_directories
+                .iter()
+                .map(|s| DirectoryEntry::Path(s.to_string()))
+                .collect()
+        });
+        // Devices aren't discovered here, so only directories are checked.
+        let mut ours: Vec<&str> = config
+            .directories
+            .iter()
+            .flatten()
+            .map(|d| match d {
+                DirectoryEntry::Path(p) => p.as_str(),
+                DirectoryEntry::Directory(d) => d.path.as_str(),
+            })
+            .collect();
+        ours.push(config.mountpoint(profile));
+        for c in cloudinit::conflicts(&[], &ours)? {
+            eprintln!("conflict: {}", c);
+        }
+    }
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &config)?;
+        println!();
+    } else {
+        print!("{}", serde_yaml::to_string(&config)?);
+    }
+    Ok(())
+}
+
+fn plan() -> Result<()> {
+    let mut config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+    let profile = profile::detect()?;
+    let directories = if let Some(d) = provision::directories(&mut config, profile)? {
+        d
+    } else {
+        println!("No directories configured; provisioning is disabled.");
+        return Ok(());
+    };
+    config.validate()?;
+    for dir in directories.iter() {
+        let facts = policy::Facts::gather(Path::new(&dir.path))?;
+        let decision = policy::evaluate(&config.directory_policy, &facts, profile.selinux);
+        let action = match decision.action {
+            policy::Action::Replace => "replace",
+            policy::Action::Skip => "skip",
+            policy::Action::Fail => "fail",
+        };
+        println!("{}: {}", dir.path, action);
+        for line in decision.trace.iter() {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
+
+fn validate() -> Result<()> {
+    let mut config = load_config()?.ok_or_else(|| anyhow!("No configuration specified"))?;
+    let profile = profile::detect()?;
+    let directories = provision::directories(&mut config, profile)?.unwrap_or_default();
+    config.validate()?;
+    let platform = provision::platform(None, &config)?;
+    let devs = match provision::discover(&platform, &config)? {
+        Some(devs) => provision::filter(devs, &config)?,
+        None => {
+            println!("Unhandled platform: {}", platform);
+            Vec::new()
+        }
+    };
+    let mut ours: Vec<&str> = directories.iter().map(|d| d.path.as_str()).collect();
+    ours.push(config.mountpoint(profile));
+    cloudinit::check(config.cloud_init_conflicts, &devs, &ours)?;
+    println!("Configuration is valid");
+    Ok(())
+}
+
+fn install_service(first_boot_only: bool) -> Result<()> {
+    let profile = profile::detect()?;
+    let exe = std::env::current_exe().context("Finding our executable")?;
+    systemd::write_service(profile.unit_dir, &exe, first_boot_only)?;
+    for unit in [
+        systemd::SERVICE_NAME,
+        systemd::RECONFIGURE_SERVICE_NAME,
+        systemd::PATH_UNIT_NAME,
+    ] {
+        println!("Wrote {}/{}", profile.unit_dir, unit);
+    }
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    Command::new("systemctl")
+        .args(["enable", systemd::SERVICE_NAME, systemd::PATH_UNIT_NAME])
+        .run()?;
+    Ok(())
+}
+
+/// Take an exclusive lock on provisioning, held until the returned file is dropped.
+fn lock() -> Result<std::fs::File> {
+    std::fs::create_dir_all(RUN_DIR)?;
+    let path = Path::new(RUN_DIR).join("lock");
+    let f = std::fs::File::create(&path).with_context(|| format!("Opening {:?}", path))?;
+    f.lock().with_context(|| format!("Locking {:?}", path))?;
+    Ok(f)
+}
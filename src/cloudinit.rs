@@ -0,0 +1,127 @@
+//! Cooperation with cloud-init's mounts module, which writes fstab entries
+//! (tagged `comment=cloudconfig`), e.g. for the first ephemeral disk at
+//! `/mnt` on AWS.  Whichever of us mounts last would silently win.
+
+use super::*;
+
+const FSTAB: &str = "/etc/fstab";
+const TAG: &str = "comment=cloudconfig";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ConflictPolicy {
+    #[default]
+    Warn,
+    Fail,
+}
+
+/// An fstab entry written by cloud-init.
+struct Mount {
+    spec: String,
+    target: String,
+}
+
+fn mounts() -> Result<Vec<Mount>> {
+    let buf = match std::fs::read_to_string(FSTAB) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", FSTAB)),
+    };
+    Ok(buf
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.as_slice() {
+                [spec, target, _, options, ..] if options.split(',').any(|o| o == TAG) => {
+                    Some(Mount {
+                        spec: spec.to_string(),
+                        target: target.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// The device an fstab spec refers to, if it exists.
+fn resolve(spec: &str) -> Option<std::path::PathBuf> {
+    let links = [
+        ("LABEL=", "/dev/disk/by-label"),
+        ("UUID=", "/dev/disk/by-uuid"),
+        ("PARTLABEL=", "/dev/disk/by-partlabel"),
+    ];
+    let path = links
+        .iter()
+        .find_map(|(prefix, dir)| spec.strip_prefix(prefix).map(|v| Path::new(dir).join(v)))
+        .unwrap_or_else(|| spec.into());
+    std::fs::canonicalize(path).ok()
+}
+
+/// `path`, with symlinks such as CoreOS' `/mnt` resolved where it exists.
+fn real(path: &str) -> std::path::PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.into())
+}
+
+fn device_and_children(dev: &block::Device, r: &mut Vec<String>) {
+    r.push(dev.path());
+    for child in dev.children.iter().flatten() {
+        device_and_children(child, r);
+    }
+}
+
+/// Descriptions of cloud-init mounts using any of `devs` (or their
+/// partitions), or overlapping any of `dirs`.
+pub(crate) fn conflicts(devs: &[String], dirs: &[&str]) -> Result<Vec<String>> {
+    let mounts = mounts()?;
+    if mounts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut used = Vec::new();
+    if !devs.is_empty() {
+        for dev in block::list()?.iter().filter(|d| devs.contains(&d.path())) {
+            device_and_children(dev, &mut used);
+        }
+    }
+    let mut r = Vec::new();
+    for m in mounts.iter() {
+        if let Some(dev) = resolve(&m.spec) {
+            if used.iter().any(|u| Path::new(u) == dev) {
+                r.push(format!(
+                    "cloud-init mounts {} ({}) at {}, but it's instance storage we use",
+                    m.spec,
+                    dev.display(),
+                    m.target
+                ));
+            }
+        }
+        let target = real(&m.target);
+        for d in dirs {
+            let ours = real(d);
+            if target.starts_with(&ours) || ours.starts_with(&target) {
+                r.push(format!(
+                    "cloud-init mounts {} at {}, which overlaps {}",
+                    m.spec, m.target, d
+                ));
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// Report conflicts, failing with `ConflictPolicy::Fail`.
+pub(crate) fn check(policy: ConflictPolicy, devs: &[String], dirs: &[&str]) -> Result<()> {
+    let conflicts = conflicts(devs, dirs)?;
+    for c in conflicts.iter() {
+        eprintln!("warning: {}", c);
+    }
+    if !conflicts.is_empty() && policy == ConflictPolicy::Fail {
+        bail!(
+            "{} conflicts with cloud-init's mounts in {}; remove them from its `mounts` config",
+            conflicts.len(),
+            FSTAB
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! Integrations with the consumers of redirected directories, for what
+//! else needs adjusting when a directory moves to instance storage, and
+//! with those of the instance storage as a whole (swap, fstrim, ...).
+
+use super::*;
+
+/// What integrations have to work with.
+pub(crate) struct Env<'a> {
+    pub(crate) config: &'a Config,
+    pub(crate) selinux: bool,
+    pub(crate) install: systemd::Install<'a>,
+    pub(crate) mountpoint: &'a str,
+    /// The volume group, if we created one.
+    pub(crate) vg: Option<&'a str>,
+}
+
+pub(crate) trait Consumer: Sync {
+    fn name(&self) -> &'static str;
+    /// The directory the consumer uses; the integration applies when
+    /// it, or a parent of it, is redirected.  Integrations with the
+    /// instance storage as a whole have none, and check the config.
+    fn path(&self) -> Option<&'static str>;
+    /// Reported if `setup` fails.
+    fn stage(&self) -> &'static str {
+        "adjusting consumers"
+    }
+    /// Called once the redirected directory (or all of the instance
+    /// storage) is mounted; returns the units written, which are
+    /// recorded so that they're removed when reprovisioning.
+    fn setup(&self, env: &Env) -> Result<Vec<String>>;
+}
+
+/// Restart `unit` if it's already running, so it picks up the new
+/// (empty) directory; at boot, it usually hasn't started yet.
+fn try_restart(unit: &str) -> Result<Vec<String>> {
+    Command::new("systemctl")
+        .args(["try-restart", unit])
+        .run()?;
+    Ok(Vec::new())
+}
+
+/// Enable (and start) the unit we just wrote.
+fn enable(env: &Env, unit: String) -> Result<Vec<String>> {
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    env.install.enable(&unit, true)?;
+    Ok(vec![unit])
+}
+
+/// `var-tmp`: the mode, label and cleanup of a tmp area.
+struct VarTmp;
+
+impl Consumer for VarTmp {
+    fn name(&self) -> &'static str {
+        "var-tmp"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        Some(vartmp::PATH)
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        if env.config.var_tmp {
+            vartmp::setup(env.selinux, env.install.runtime)?;
+        }
+        Ok(Vec::new())
+    }
+}
+
+/// journald keeps writing to its files on the root disk otherwise.
+struct Journald;
+
+impl Consumer for Journald {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        Some("/var/log/journal")
+    }
+
+    fn setup(&self, _env: &Env) -> Result<Vec<String>> {
+        Command::new("systemd-tmpfiles")
+            .args(["--create", "--prefix", "/var/log/journal"])
+            .run()?;
+        Command::new("journalctl").arg("--flush").run()?;
+        Command::new("journalctl").arg("--rotate").run()?;
+        Ok(Vec::new())
+    }
+}
+
+/// CRI-O and podman storage.
+struct ContainerStorage;
+
+impl Consumer for ContainerStorage {
+    fn name(&self) -> &'static str {
+        "containers-storage"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        Some("/var/lib/containers/storage")
+    }
+
+    fn setup(&self, _env: &Env) -> Result<Vec<String>> {
+        try_restart("crio.service")
+    }
+}
+
+struct Kubelet;
+
+impl Consumer for Kubelet {
+    fn name(&self) -> &'static str {
+        "kubelet"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        Some("/var/lib/kubelet")
+    }
+
+    fn setup(&self, _env: &Env) -> Result<Vec<String>> {
+        try_restart("kubelet.service")
+    }
+}
+
+/// `ci`: cleanup of the build caches.
+struct Ci;
+
+impl Consumer for Ci {
+    fn name(&self) -> &'static str {
+        "ci"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        if let Some(ci) = env.config.ci.as_ref() {
+            ci::setup(ci, env.install.runtime)?;
+        }
+        Ok(Vec::new())
+    }
+}
+
+struct Fstrim;
+
+impl Consumer for Fstrim {
+    fn name(&self) -> &'static str {
+        "fstrim"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        if !env.config.fstrim {
+            return Ok(Vec::new());
+        }
+        enable(
+            env,
+            fstrim::write_units(env.install.unit_dir, env.mountpoint)?,
+        )
+    }
+}
+
+/// `avc-check`: periodically report SELinux denials.
+struct Avc;
+
+impl Consumer for Avc {
+    fn name(&self) -> &'static str {
+        "avc-check"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        if !(env.config.avc_check && env.selinux) {
+            return Ok(Vec::new());
+        }
+        let exe = std::env::current_exe().context("Finding our executable")?;
+        enable(env, avc::write_units(env.install.unit_dir, &exe)?)
+    }
+}
+
+/// Swap on a volume or file of the instance storage, or on partitions.
+struct Swap;
+
+impl Consumer for Swap {
+    fn name(&self) -> &'static str {
+        "swap"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn stage(&self) -> &'static str {
+        "enabling swap"
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        let mut units = Vec::new();
+        if let Some(swap) = env.config.swap.as_ref() {
+            let what = match swap.kind {
+                swap::Kind::Volume => {
+                    format!("/dev/{}/{}", env.vg.expect("volume group"), swap::LV_NAME)
+                }
+                swap::Kind::File => {
+                    swap::create_file(env.mountpoint, &swap.size, env.config.filesystem)?
+                }
+            };
+            units.extend(enable(
+                env,
+                swap::write_unit(env.install.unit_dir, &what, swap.priority)?,
+            )?);
+        }
+        for what in partition::swap_paths(&env.config.partitions).iter() {
+            units.extend(enable(
+                env,
+                swap::write_unit(env.install.unit_dir, what, None)?,
+            )?);
+        }
+        Ok(units)
+    }
+}
+
+/// Have systemd-oomd act on swap usage, once there is swap.
+struct Oomd;
+
+impl Consumer for Oomd {
+    fn name(&self) -> &'static str {
+        "systemd-oomd"
+    }
+
+    fn path(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn stage(&self) -> &'static str {
+        "setting up systemd-oomd"
+    }
+
+    fn setup(&self, env: &Env) -> Result<Vec<String>> {
+        let swap =
+            env.config.swap.is_some() || !partition::swap_paths(&env.config.partitions).is_empty();
+        if env.config.oomd && swap {
+            oomd::swap(env.install)?;
+        }
+        Ok(Vec::new())
+    }
+}
+
+/// In order of setup; oomd needs swap to be set up first.
+const CONSUMERS: &[&dyn Consumer] = &[
+    &VarTmp,
+    &Journald,
+    &ContainerStorage,
+    &Kubelet,
+    &Ci,
+    &Fstrim,
+    &Avc,
+    &Swap,
+    &Oomd,
+];
+
+/// The consumers affected by redirecting `dir`.
+pub(crate) fn for_directory(dir: &str) -> impl Iterator<Item = &'static dyn Consumer> + '_ {
+    CONSUMERS
+        .iter()
+        .copied()
+        .filter(move |c| c.path().is_some_and(|p| Path::new(p).starts_with(dir)))
+}
+
+/// Adjust the consumers of all `redirected` directories, then set up
+/// the integrations with the instance storage as a whole; returns
+/// the units written.
+pub(crate) fn setup(redirected: &[&str], env: &Env) -> Result<Vec<String>> {
+    let mut units = Vec::new();
+    for dir in redirected {
+        for consumer in for_directory(dir) {
+            println!("Adjusting {} for {}", consumer.name(), dir);
+            failure::stage(consumer.stage());
+            units.extend(
+                consumer
+                    .setup(env)
+                    .with_context(|| format!("Adjusting {}", consumer.name()))?,
+            );
+        }
+    }
+    for consumer in CONSUMERS.iter().filter(|c| c.path().is_none()) {
+        failure::stage(consumer.stage());
+        units.extend(
+            consumer
+                .setup(env)
+                .with_context(|| format!("Setting up {}", consumer.name()))?,
+        );
+    }
+    Ok(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(dir: &str) -> Vec<&'static str> {
+        for_directory(dir).map(|c| c.name()).collect()
+    }
+
+    #[test]
+    fn directory_lookup() {
+        assert_eq!(names("/var/lib/containers"), ["containers-storage"]);
+        assert_eq!(names("/var/lib/containers/storage"), ["containers-storage"]);
+        assert_eq!(names("/var/lib/"), ["containers-storage", "kubelet"]);
+        assert_eq!(names("/var/tmp"), ["var-tmp"]);
+        assert_eq!(names("/var/log"), ["journald"]);
+        assert_eq!(
+            names("/var"),
+            ["var-tmp", "journald", "containers-storage", "kubelet"]
+        );
+        // Only parents of a consumer's directory affect it.
+        assert!(names("/var/lib/containers/storage/overlay").is_empty());
+        assert!(names("/var/lib/containers-old").is_empty());
+        assert!(names("/srv").is_empty());
+    }
+}
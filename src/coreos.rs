@@ -0,0 +1,122 @@
+use super::*;
+
+/// Path to kernel command-line (requires procfs mount).
+const CMDLINE_PATH: &str = "/proc/cmdline";
+/// Platform key.
+const CMDLINE_PLATFORM_FLAG: &str = "ignition.platform.id";
+
+// Find OEM ID flag value in cmdline string.
+fn find_flag_value(flagname: &str, cmdline: &str) -> Option<String> {
+    // split the contents into elements and keep key-value tuples only.
+    let params: Vec<(&str, &str)> = cmdline
+        .split(' ')
+        .filter_map(|s| {
+            let kv: Vec<&str> = s.splitn(2, '=').collect();
+            match kv.len() {
+                2 => Some((kv[0], kv[1])),
+                _ => None,
+            }
+        })
+        .collect();
+
+    // find the oem flag
+    for (key, val) in params {
+        if key != flagname {
+            continue;
+        }
+        let bare_val = val.trim();
+        if !bare_val.is_empty() {
+            return Some(bare_val.to_string());
+        }
+    }
+    None
+}
+
+/// SMBIOS identification exported by the kernel.
+const DMI_DIR: &str = "/sys/class/dmi/id";
+
+fn read_dmi(name: &str) -> Option<String> {
+    std::fs::read_to_string(Path::new(DMI_DIR).join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Infer the platform from the DMI system vendor and product name,
+/// for images booted without `ignition.platform.id` (custom images,
+/// rebuilt kernels).
+fn platform_from_dmi() -> Option<&'static str> {
+    let vendor = read_dmi("sys_vendor")?;
+    let product = read_dmi("product_name").unwrap_or_default();
+    match (vendor.as_str(), product.as_str()) {
+        ("Amazon EC2", _) => Some("aws"),
+        // Older Xen-based instance types
+        ("Xen", "HVM domU")
+            if read_dmi("bios_version")
+                .map(|v| v.contains("amazon"))
+                .unwrap_or_default() =>
+        {
+            Some("aws")
+        }
+        ("Microsoft Corporation", "Virtual Machine") => Some("azure"),
+        ("Google", _) => Some("gcp"),
+        ("QEMU", _) => Some("qemu"),
+        _ => None,
+    }
+}
+
+/// The platform/OEM value from the cmdline file, if any.
+pub(crate) fn cmdline_platform() -> Result<Option<String>> {
+    let content = std::fs::read_to_string(CMDLINE_PATH)?;
+    Ok(find_flag_value(CMDLINE_PLATFORM_FLAG, &content))
+}
+
+/// Get platform/OEM value from cmdline file, falling back to DMI.
+pub fn get_platform() -> Result<String> {
+    if let Some(platform) = cmdline_platform()? {
+        return Ok(platform);
+    }
+    if let Some(platform) = afterburn::platform() {
+        println!(
+            "No '{}' in {}; using platform {} from Afterburn",
+            CMDLINE_PLATFORM_FLAG, CMDLINE_PATH, platform
+        );
+        return Ok(platform);
+    }
+    match platform_from_dmi() {
+        Some(platform) => {
+            println!(
+                "No '{}' in {}; detected platform {} from DMI",
+                CMDLINE_PLATFORM_FLAG, CMDLINE_PATH, platform
+            );
+            Ok(platform.to_string())
+        }
+        None => anyhow::bail!(
+            "Couldn't find flag '{}' in cmdline file ({}), and DMI did not identify the platform",
+            CMDLINE_PLATFORM_FLAG,
+            CMDLINE_PATH
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_values() {
+        let flag = CMDLINE_PLATFORM_FLAG;
+        let cmdline =
+            "BOOT_IMAGE=(hd0,gpt3)/ostree/vmlinuz rw ignition.platform.id=aws console=ttyS0\n";
+        assert_eq!(find_flag_value(flag, cmdline).as_deref(), Some("aws"));
+        let cmdline = "root=UUID=abc ignition.platform.id=gcp\n";
+        assert_eq!(find_flag_value(flag, cmdline).as_deref(), Some("gcp"));
+        for cmdline in [
+            "root=UUID=abc rw\n",
+            "ignition.platform.id= rw",
+            "ignition.platform.id rw",
+            "ignition.platform.id.other=aws",
+        ] {
+            assert_eq!(find_flag_value(flag, cmdline), None, "{:?}", cmdline);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! LUKS2 encryption of the instance storage.  By default the key is
+//! random and thrown away; with Clevis, it's bound to the TPM2 or a Tang
+//! server instead, so the storage can be unlocked again after a reboot.
+
+use super::*;
+use libsystemd::unit;
+use std::io::{Read, Write};
+
+const NAME: &str = "ccisp-crypt";
+const KEY_BYTES: usize = 64;
+pub(crate) const UNIT_NAME: &str = "ccisp-crypt.service";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Clevis {
+    /// E.g. `tpm2`, `tang` or `sss`.
+    pub(crate) pin: String,
+    /// The pin configuration as JSON, e.g. `{"pcr_ids":"7"}`.
+    #[serde(default = "empty_json")]
+    config: String,
+}
+
+fn empty_json() -> String {
+    "{}".to_string()
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", from = "EncryptRepr")]
+pub(crate) enum Encrypt {
+    #[default]
+    None,
+    /// LUKS2, optionally with Clevis.
+    Luks,
+    /// dm-crypt in plain mode, set up by systemd-cryptsetup as for
+    /// swap encryption; no header, and quicker to set up.
+    Plain,
+}
+
+impl Encrypt {
+    pub(crate) fn enabled(self) -> bool {
+        self != Encrypt::None
+    }
+}
+
+/// `encrypt` started out as a boolean for LUKS2.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EncryptRepr {
+    Bool(bool),
+    Named(Named),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Named {
+    None,
+    Luks,
+    Plain,
+}
+
+impl From<EncryptRepr> for Encrypt {
+    fn from(r: EncryptRepr) -> Self {
+        match r {
+            EncryptRepr::Bool(false) | EncryptRepr::Named(Named::None) => Encrypt::None,
+            EncryptRepr::Bool(true) | EncryptRepr::Named(Named::Luks) => Encrypt::Luks,
+            EncryptRepr::Named(Named::Plain) => Encrypt::Plain,
+        }
+    }
+}
+
+/// Older systemd has it only here, newer also in /usr/bin.
+const SYSTEMD_CRYPTSETUP: &[&str] = &[
+    "/usr/lib/systemd/systemd-cryptsetup",
+    "/usr/bin/systemd-cryptsetup",
+];
+/// As recommended for swap in crypttab(5).
+const PLAIN_OPTIONS: &str = "plain,cipher=aes-xts-plain64,size=512";
+
+fn systemd_cryptsetup() -> Result<&'static str> {
+    SYSTEMD_CRYPTSETUP
+        .iter()
+        .copied()
+        .find(|p| Path::new(p).exists())
+        .ok_or_else(|| anyhow!("systemd-cryptsetup not found"))
+}
+
+/// `systemd-cryptsetup attach` arguments for plain mode with a
+/// random key; attaching an active volume is a no-op.
+fn plain_attach(dev: &str) -> Result<String> {
+    Ok(format!(
+        "{} attach {} {} /dev/urandom {}",
+        systemd_cryptsetup()?,
+        NAME,
+        dev,
+        PLAIN_OPTIONS
+    ))
+}
+
+/// Open `dev` in plain mode with a random key, returning the path of
+/// the mapped device.
+pub(crate) fn open_plain(dev: &str) -> Result<String> {
+    Command::new(systemd_cryptsetup()?)
+        .args(["attach", NAME, dev, "/dev/urandom", PLAIN_OPTIONS])
+        .run()?;
+    Ok(mapped_path())
+}
+
+impl Clevis {
+    /// Whether unlocking needs the network, i.e. anything but the TPM.
+    pub(crate) fn needs_network(&self) -> bool {
+        self.pin != "tpm2"
+    }
+}
+
+fn mapped_path() -> String {
+    format!("/dev/mapper/{}", NAME)
+}
+
+pub(crate) fn is_open() -> bool {
+    Path::new(&mapped_path()).exists()
+}
+
+/// Close the mapping from a previous attempt; best-effort.
+pub(crate) fn close() {
+    if let Err(e) = Command::new("cryptsetup").arg("close").arg(NAME).run() {
+        eprintln!("warning: {:#}", e);
+    }
+}
+
+/// Run a command reading the key from stdin.
+fn with_key(c: &mut Command, key: &[u8]) -> Result<()> {
+    failure::record_command(c);
+    let mut child = c.stdin(std::process::Stdio::piped()).spawn()?;
+    child.stdin.take().expect("stdin").write_all(key)?;
+    let r = child.wait()?;
+    if !r.success() {
+        bail!("Child [{:?}] exited: {}", c, r);
+    }
+    Ok(())
+}
+
+/// Format `dev` with a random key and open it, returning the path of
+/// the mapped device.  The key only exists in our memory, and in
+/// the Clevis binding if configured.
+pub(crate) fn format_and_open(dev: &str, clevis: Option<&Clevis>) -> Result<String> {
+    let mut key = vec![0u8; KEY_BYTES];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+    with_key(
+        Command::new("cryptsetup")
+            .args(["luksFormat", "--type", "luks2", "--batch-mode"])
+            .arg("--key-file=-")
+            .arg(dev),
+        &key,
+    )?;
+    if let Some(clevis) = clevis {
+        // -y trusts the Tang server's advertisement as is.
+        with_key(
+            Command::new("clevis")
+                .args(["luks", "bind", "-y", "-k", "-", "-d"])
+                .arg(dev)
+                .arg(&clevis.pin)
+                .arg(&clevis.config),
+            &key,
+        )?;
+    }
+    with_key(
+        Command::new("cryptsetup")
+            .arg("open")
+            .arg("--key-file=-")
+            .arg(dev)
+            .arg(NAME),
+        &key,
+    )?;
+    Ok(mapped_path())
+}
+
+fn luks_uuid(dev: &str) -> Result<String> {
+    let o = Command::new("cryptsetup")
+        .arg("luksUUID")
+        .arg(dev)
+        .output()?;
+    if !o.status.success() {
+        bail!("Getting LUKS UUID of {}", dev);
+    }
+    Ok(String::from_utf8(o.stdout)?.trim().to_string())
+}
+
+/// Without Clevis, the mapping is opened by us during provisioning and
+/// this unit just closes it cleanly at shutdown.  With Clevis, it
+/// also unlocks the device at boot, before `mount_unit`.
+pub(crate) fn write_unit(
+    unit_dir: &str,
+    dev: &str,
+    mount_unit: &str,
+    encrypt: Encrypt,
+    clevis: Option<&Clevis>,
+) -> Result<String> {
+    let (deps, exec_start, exec_stop) = if encrypt == Encrypt::Plain {
+        let device = format!("{}.device", unit::escape_path(dev));
+        (
+            format!("BindsTo={device}\nAfter={device}\n", device = device),
+            plain_attach(dev)?,
+            format!("{} detach {}", systemd_cryptsetup()?, NAME),
+        )
+    } else if let Some(clevis) = clevis {
+        // The kernel name of the device may change across boots.
+        let path = format!("/dev/disk/by-uuid/{}", luks_uuid(dev)?);
+        let device = format!("{}.device", unit::escape_path(&path));
+        let network = if clevis.needs_network() {
+            "Wants=network-online.target\nAfter=network-online.target\n"
+        } else {
+            "After=tpm2.target\n"
+        };
+        (
+            format!(
+                "Requires={device}\nAfter={device}\n{network}",
+                device = device,
+                network = network
+            ),
+            format!("clevis luks unlock -d {} -n {}", path, NAME),
+            format!("cryptsetup close {}", NAME),
+        )
+    } else {
+        let device = format!("{}.device", unit::escape_path(&mapped_path()));
+        (
+            format!("BindsTo={device}\nAfter={device}\n", device = device),
+            "/bin/true".to_string(),
+            format!("cryptsetup close {}", NAME),
+        )
+    };
+    let dir = openat::Dir::open(unit_dir)?;
+    failure::record_unit(unit_dir, UNIT_NAME);
+    dir.write_file_contents(
+        UNIT_NAME,
+        0o644,
+        format!(
+            r##"[Unit]
+Description=Encryption of instance storage on {dev}
+DefaultDependencies=no
+{deps}Before={mount_unit} umount.target
+Conflicts=umount.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={exec_start}
+ExecStop={exec_stop}
+
+[Install]
+RequiredBy={mount_unit}
+"##,
+            dev = dev,
+            deps = deps,
+            exec_start = exec_start,
+            exec_stop = exec_stop,
+            mount_unit = mount_unit,
+        ),
+    )?;
+    Ok(UNIT_NAME.to_string())
+}
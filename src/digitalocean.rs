@@ -0,0 +1,28 @@
+use super::*;
+
+const VENDOR: &str = "DO";
+/// Volumes are network block storage that persist independently
+/// of the droplet; we must never treat them as scratch space.
+const VOLUME_MODEL: &str = "Volume";
+
+/// Droplets don't generally have local scratch disks today; if one
+/// is attached, it will have the DigitalOcean vendor string but not
+/// be a Volume.
+pub(crate) fn devices() -> Result<Vec<String>> {
+    Ok(block::list()?
+        .into_iter()
+        .filter(|dev| {
+            dev.vendor
+                .as_ref()
+                .filter(|vendor| vendor.trim() == VENDOR)
+                .is_some()
+        })
+        .filter(|dev| {
+            dev.model
+                .as_ref()
+                .filter(|model| model.trim() == VOLUME_MODEL)
+                .is_none()
+        })
+        .map(|dev| dev.path())
+        .collect())
+}
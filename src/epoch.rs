@@ -0,0 +1,72 @@
+//! A hidden file in the root of the filesystem we create, recording
+//! which machine created it and when.  This tells our own filesystem
+//! apart from data left behind by someone else.
+
+use super::*;
+
+const MARKER: &str = ".ccisp-epoch";
+const CHECK_MOUNTPOINT: &str = "epoch-check";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Marker {
+    machine_id: String,
+    /// Seconds since the Unix epoch at provisioning time.
+    epoch: u64,
+}
+
+pub(crate) fn write(mountpoint: &str) -> Result<()> {
+    let marker = Marker {
+        machine_id: identity::machine_id()?,
+        epoch: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+    let dir = openat::Dir::open(mountpoint)?;
+    dir.write_file_with(MARKER, 0o644, |w| -> Result<()> {
+        serde_json::to_writer(w, &marker)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn has_label(dev: &str, label: &str) -> Result<bool> {
+    let o = Command::new("blkid")
+        .args(["-s", "LABEL", "-o", "value", dev])
+        .output()?;
+    Ok(o.status.success() && String::from_utf8_lossy(&o.stdout).trim() == label)
+}
+
+fn read_marker(mnt: &Path) -> Option<Marker> {
+    let f = std::fs::File::open(mnt.join(MARKER)).ok()?;
+    serde_json::from_reader(std::io::BufReader::new(f)).ok()
+}
+
+/// If `dev` holds a filesystem labeled `label` which we created on
+/// this machine, return the epoch at which it was created.
+pub(crate) fn check(dev: &str, label: &str) -> Result<Option<u64>> {
+    if !Path::new(dev).exists() || !has_label(dev, label)? {
+        return Ok(None);
+    }
+    let mnt = Path::new(RUN_DIR).join(CHECK_MOUNTPOINT);
+    std::fs::create_dir_all(&mnt)?;
+    Command::new("mount")
+        .args(["-o", "ro"])
+        .arg(dev)
+        .arg(&mnt)
+        .run()?;
+    let marker = read_marker(&mnt);
+    Command::new("umount").arg(&mnt).run()?;
+    let machine_id = identity::machine_id()?;
+    match marker {
+        Some(m) if m.machine_id == machine_id => Ok(Some(m.epoch)),
+        Some(m) => {
+            println!("{} was provisioned by machine {}", dev, m.machine_id);
+            Ok(None)
+        }
+        None => {
+            println!("{} has no provisioning marker", dev);
+            Ok(None)
+        }
+    }
+}
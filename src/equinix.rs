@@ -0,0 +1,11 @@
+use super::*;
+
+/// The boot disks are partitioned (and usually RAIDed together); any
+/// other NVMe drives which are entirely unused are effectively instance-local.
+pub(crate) fn devices() -> Result<Vec<String>> {
+    Ok(block::list()?
+        .into_iter()
+        .filter(|dev| dev.name.starts_with("nvme") && dev.is_unused())
+        .map(|dev| dev.path())
+        .collect())
+}
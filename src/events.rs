@@ -0,0 +1,115 @@
+//! Key events, logged to the journal with stable MESSAGE_IDs so that log
+//! pipelines needn't match on the text.  `catalog` prints the matching
+//! journal catalog; install it into /usr/lib/systemd/catalog and run
+//! `journalctl --update-catalog`.
+
+use libsystemd::logging::{journal_send, Priority};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Event {
+    Provisioned,
+    SkippedNoDevices,
+    WipedDevice,
+    Degraded,
+    Teardown,
+}
+
+const ALL: &[Event] = &[
+    Event::Provisioned,
+    Event::SkippedNoDevices,
+    Event::WipedDevice,
+    Event::Degraded,
+    Event::Teardown,
+];
+
+impl Event {
+    fn id(self) -> &'static str {
+        match self {
+            Event::Provisioned => "69a462ff9f2a427cbec2db96bf70a6e4",
+            Event::SkippedNoDevices => "1dc7815edc374a76a51457f77292a782",
+            Event::WipedDevice => "66fe937bcd7d4841b8c7f1ed461095bf",
+            Event::Degraded => "bff46baea8e8428284569eb2e9670950",
+            Event::Teardown => "4f2525fef26943a48fb72a6fc435c8fa",
+        }
+    }
+
+    fn priority(self) -> Priority {
+        match self {
+            Event::Degraded => Priority::Warning,
+            Event::WipedDevice | Event::Teardown => Priority::Notice,
+            Event::Provisioned | Event::SkippedNoDevices => Priority::Info,
+        }
+    }
+
+    fn subject(self) -> &'static str {
+        match self {
+            Event::Provisioned => "Instance storage provisioned",
+            Event::SkippedNoDevices => "No instance storage found",
+            Event::WipedDevice => "Instance storage device @CCISP_DEVICE@ wiped",
+            Event::Degraded => "Instance storage device @CCISP_DEVICE@ is gone",
+            Event::Teardown => "Previous instance storage setup torn down",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Event::Provisioned => {
+                "The instance storage was set up (mode @CCISP_MODE@, generation \
+                 @CCISP_GENERATION@) in @CCISP_DURATION@ seconds."
+            }
+            Event::SkippedNoDevices => {
+                "No instance-local devices were found on platform \
+                 @CCISP_PLATFORM@, so nothing was set up."
+            }
+            Event::WipedDevice => {
+                "All signatures were erased from @CCISP_DEVICE@ before it \
+                 was used; backups are in /var/lib/ccisp/backup."
+            }
+            Event::Degraded => {
+                "The device @CCISP_DEVICE@ (serial @CCISP_SERIAL@), part of \
+                 the provisioned instance storage, is no longer present."
+            }
+            Event::Teardown => {
+                "The mount units and volumes set up by a previous run were \
+                 removed, so that the instance storage is set up again."
+            }
+        }
+    }
+}
+
+/// Print `message`, or with stdout connected to the journal, send it
+/// with the event's MESSAGE_ID and `fields`.  Field names are prefixed
+/// with `CCISP_`.
+pub(crate) fn log(event: Event, message: &str, fields: &[(&str, &str)]) {
+    if std::env::var_os("JOURNAL_STREAM").is_some() {
+        let vars = std::iter::once(("MESSAGE_ID".to_string(), event.id().to_string())).chain(
+            fields
+                .iter()
+                .map(|(k, v)| (format!("CCISP_{}", k), v.to_string())),
+        );
+        match journal_send(event.priority(), message, vars) {
+            Ok(()) => return,
+            Err(e) => eprintln!("warning: Logging to the journal: {}", e),
+        }
+    }
+    println!("{}", message);
+}
+
+/// The journal catalog entries for all events.
+pub(crate) fn catalog() -> String {
+    let mut r = String::new();
+    for event in ALL.iter() {
+        r.push_str(&format!(
+            "-- {}\n\
+             Subject: {}\n\
+             Defined-By: coreos-cloud-instance-store-provisioner\n\
+             Documentation: https://github.com/cgwalters/coreos-cloud-instance-store-provisioner\n\
+             \n\
+             {}\n\n",
+            event.id(),
+            event.subject(),
+            event.description()
+        ));
+    }
+    r
+}
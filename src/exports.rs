@@ -0,0 +1,92 @@
+//! Logical volumes carved out of the instance storage volume group and
+//! shared with e.g. sibling VMs, either over NFS or as a block device.
+//! We own everything created here, and remove it when reprovisioning.
+
+use super::*;
+
+const EXPORTS_FILE: &str = "/etc/exports.d/ccisp.exports";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Export {
+    /// The logical volume name.
+    pub(crate) name: String,
+    /// In `lvcreate --size` syntax, e.g. `100G`.
+    pub(crate) size: String,
+    /// If set, the volume gets an XFS filesystem which is exported
+    /// over NFS to these clients, in exports(5) syntax, e.g.
+    /// `192.168.122.0/24(rw,no_root_squash)`.
+    #[serde(default)]
+    nfs_clients: Vec<String>,
+    /// If set, a symlink to the block device is created here, e.g.
+    /// for use as a libvirt disk.
+    link: Option<String>,
+}
+
+/// Where NFS exports are mounted, alongside the instance storage.
+fn export_mountpoint(mountpoint: &str, name: &str) -> String {
+    format!("{}-exports/{}", mountpoint, name)
+}
+
+/// Set up the configured exports on `vg`; `format` is false if the
+/// volumes already existed.  Returns the mount units created and
+/// the symlinks, which are recorded so they can be removed later.
+pub(crate) fn setup(
+    exports: &[Export],
+    vg: &str,
+    mountpoint: &str,
+    install: systemd::Install,
+    format: bool,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut units = Vec::new();
+    let mut links = Vec::new();
+    let mut lines = String::new();
+    for export in exports {
+        let dev = format!("/dev/{}/{}", vg, export.name);
+        if !export.nfs_clients.is_empty() {
+            if format {
+                throttle::apply(&mut Command::new("mkfs.xfs"))
+                    .arg(&dev)
+                    .run()?;
+            }
+            let target = export_mountpoint(mountpoint, &export.name);
+            std::fs::create_dir_all(&target).with_context(|| format!("Creating {}", target))?;
+            let unit = systemd::MountUnit::new(&dev, &target, "xfs").write(install.unit_dir)?;
+            Command::new("systemctl").arg("daemon-reload").run()?;
+            install.enable(&unit, true)?;
+            units.push(unit);
+            lines.push_str(&format!("{} {}\n", target, export.nfs_clients.join(" ")));
+        }
+        if let Some(link) = export.link.as_deref() {
+            let _ = std::fs::remove_file(link);
+            std::os::unix::fs::symlink(&dev, link)
+                .with_context(|| format!("Creating symlink {}", link))?;
+            links.push(link.to_string());
+        }
+        println!("Set up export {} ({})", export.name, export.size);
+    }
+    if !lines.is_empty() {
+        let path = Path::new(EXPORTS_FILE);
+        std::fs::create_dir_all(path.parent().expect("parent"))?;
+        std::fs::write(path, lines).with_context(|| format!("Writing {}", EXPORTS_FILE))?;
+        Command::new("exportfs").arg("-ra").run()?;
+    }
+    Ok((units, links))
+}
+
+/// Remove the NFS exports and symlinks; the volumes themselves go
+/// along with the volume group.  Best-effort, like the latter.
+pub(crate) fn teardown(links: &[String]) {
+    if Path::new(EXPORTS_FILE).exists() {
+        if let Err(e) = std::fs::remove_file(EXPORTS_FILE) {
+            eprintln!("warning: Removing {}: {}", EXPORTS_FILE, e);
+        } else if let Err(e) = Command::new("exportfs").arg("-ra").run() {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+    for link in links {
+        if let Err(e) = std::fs::remove_file(link) {
+            eprintln!("warning: Removing {}: {}", link, e);
+        }
+    }
+}
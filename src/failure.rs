@@ -0,0 +1,107 @@
+//! If provisioning fails, we may leave the system in an intermediate state
+//! (possibly in the emergency shell on first boot).  We keep track of what
+//! we've done so far, and write a summary with recovery steps on failure.
+
+use super::*;
+use std::sync::Mutex;
+
+const FAILED_FILE: &str = "FAILED";
+
+struct Log {
+    stage: &'static str,
+    commands: Vec<String>,
+    /// Pairs of (unit directory, unit name)
+    units: Vec<(String, String)>,
+    directories: Vec<String>,
+    wiped: bool,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    stage: "startup",
+    commands: Vec::new(),
+    units: Vec::new(),
+    directories: Vec::new(),
+    wiped: false,
+});
+
+fn with_log(f: impl FnOnce(&mut Log)) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut log)
+}
+
+/// Note the start of a new stage of provisioning.
+pub(crate) fn stage(stage: &'static str) {
+    with_log(|l| l.stage = stage)
+}
+
+pub(crate) fn record_command(c: &Command) {
+    with_log(|l| l.commands.push(format!("{:?}", c)))
+}
+
+pub(crate) fn record_unit(unit_dir: &str, name: &str) {
+    with_log(|l| l.units.push((unit_dir.to_string(), name.to_string())))
+}
+
+pub(crate) fn record_directory(path: &str) {
+    with_log(|l| l.directories.push(path.to_string()))
+}
+
+pub(crate) fn record_wipe() {
+    with_log(|l| l.wiped = true)
+}
+
+fn render(log: &Log, err: &anyhow::Error) -> String {
+    let mut r = String::new();
+    r.push_str(&format!(
+        "Instance storage provisioning failed during: {}\nError: {:#}\n\n",
+        log.stage, err
+    ));
+    r.push_str("Commands executed:\n");
+    for c in log.commands.iter() {
+        r.push_str(&format!("  {}\n", c));
+    }
+    r.push_str("\nTo recover:\n");
+    for (dir, u) in log.units.iter() {
+        r.push_str(&format!(
+            "  systemctl disable --now {u} && rm {dir}/{u}\n",
+            u = u,
+            dir = dir
+        ));
+    }
+    for d in log.directories.iter() {
+        r.push_str(&format!(
+            "  {} was emptied; restore its contents from backups or let its owner recreate it\n",
+            d
+        ));
+    }
+    if log.wiped {
+        r.push_str(&format!(
+            "  Device signatures were backed up in {:?}; see `ccisp restore-signatures`\n",
+            backup::dir()
+        ));
+    }
+    r.push_str(&format!(
+        "  rm -f {}/state.json  # to retry on the next boot\n",
+        STATE_DIR
+    ));
+    r
+}
+
+/// Write the failure report to /run/ccisp/FAILED.
+pub(crate) fn write_report(err: &anyhow::Error) -> Result<()> {
+    let mut contents = String::new();
+    with_log(|l| contents = render(l, err));
+    std::fs::create_dir_all(RUN_DIR)?;
+    let path = Path::new(RUN_DIR).join(FAILED_FILE);
+    std::fs::write(&path, contents)?;
+    eprintln!("Wrote recovery instructions to {:?}", path);
+    Ok(())
+}
+
+/// Remove any failure report from a previous attempt in this boot.
+pub(crate) fn clear() -> Result<()> {
+    match std::fs::remove_file(Path::new(RUN_DIR).join(FAILED_FILE)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
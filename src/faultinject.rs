@@ -0,0 +1,57 @@
+//! Wraps the instance devices in device-mapper targets which inject
+//! faults, so integration tests can exercise our error paths.  Only
+//! built with the `fault-injection` feature; configured via the
+//! `CCISP_FAULT_INJECT` environment variable as one of:
+//!
+//! - `delay:<milliseconds>`: dm-delay all I/O
+//! - `flakey:<up seconds>:<down seconds>`: dm-flakey, failing all I/O while down
+
+use super::*;
+
+const ENV: &str = "CCISP_FAULT_INJECT";
+
+/// Build the dm target parameters (after the device and offset) for a spec.
+fn target_params(spec: &str) -> Result<(&'static str, String)> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    match parts.as_slice() {
+        ["delay", ms] => Ok(("delay", ms.parse::<u32>()?.to_string())),
+        ["flakey", up, down] => Ok((
+            "flakey",
+            format!("{} {}", up.parse::<u32>()?, down.parse::<u32>()?),
+        )),
+        _ => bail!("Invalid {}: {}", ENV, spec),
+    }
+}
+
+fn sectors(dev: &str) -> Result<u64> {
+    let o = Command::new("blockdev").arg("--getsz").arg(dev).output()?;
+    if !o.status.success() {
+        bail!("Failed to get size of {}", dev);
+    }
+    Ok(String::from_utf8(o.stdout)?.trim().parse()?)
+}
+
+/// If fault injection is requested, wrap each device and return
+/// the paths to the wrappers instead.
+pub(crate) fn wrap(devices: Vec<String>) -> Result<Vec<String>> {
+    let spec = match std::env::var(ENV) {
+        Ok(s) => s,
+        Err(_) => return Ok(devices),
+    };
+    let (target, params) = target_params(&spec)?;
+    devices
+        .iter()
+        .map(|dev| {
+            let name = format!(
+                "ccisp-fault-{}",
+                Path::new(dev).file_name().unwrap().to_string_lossy()
+            );
+            let table = format!("0 {} {} {} 0 {}", sectors(dev)?, target, dev, params);
+            Command::new("dmsetup")
+                .args(["create", name.as_str(), "--table", table.as_str()])
+                .run()?;
+            println!("Injecting faults into {} via {}: {}", dev, target, params);
+            Ok(format!("/dev/mapper/{}", name))
+        })
+        .collect()
+}
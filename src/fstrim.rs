@@ -0,0 +1,48 @@
+//! A timer trimming just the instance storage filesystem; unlike the
+//! distribution's `fstrim.timer`, this doesn't depend on fstab.
+
+use super::*;
+use std::io::Write as IoWrite;
+
+const SERVICE_NAME: &str = "ccisp-fstrim.service";
+const TIMER_NAME: &str = "ccisp-fstrim.timer";
+
+/// Write the units for `mountpoint`, returning the timer.
+pub(crate) fn write_units(unit_dir: &str, mountpoint: &str) -> Result<String> {
+    let dir = openat::Dir::open(unit_dir)?;
+    failure::record_unit(unit_dir, SERVICE_NAME);
+    dir.write_file_with(SERVICE_NAME, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Discard unused blocks on instance storage
+RequiresMountsFor={mountpoint}
+
+[Service]
+Type=oneshot
+ExecStart=/usr/sbin/fstrim --verbose {mountpoint}
+IOSchedulingClass=idle
+"##,
+            mountpoint = mountpoint,
+        )?;
+        Ok(())
+    })?;
+    failure::record_unit(unit_dir, TIMER_NAME);
+    dir.write_file_contents(
+        TIMER_NAME,
+        0o644,
+        r##"[Unit]
+Description=Discard unused blocks on instance storage once a week
+
+[Timer]
+OnCalendar=weekly
+AccuracySec=1h
+Persistent=true
+RandomizedDelaySec=100min
+
+[Install]
+WantedBy=timers.target
+"##,
+    )?;
+    Ok(TIMER_NAME.to_string())
+}
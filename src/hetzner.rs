@@ -0,0 +1,9 @@
+use super::*;
+
+/// The local NVMe disks on CCX (dedicated vCPU) instances; note
+/// Volumes (network storage) use `HC_Volume_` and are never matched.
+const PREFIX: &str = "HC_Local_";
+
+pub(crate) fn devices() -> Result<Vec<String>> {
+    block::with_serial_prefix(PREFIX)
+}
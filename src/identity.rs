@@ -0,0 +1,35 @@
+//! Identifiers for this machine, for use in templates.
+
+use super::*;
+
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+const UNKNOWN: &str = "unknown";
+
+pub(crate) fn machine_id() -> Result<String> {
+    Ok(std::fs::read_to_string(MACHINE_ID_PATH)
+        .with_context(|| format!("Reading {}", MACHINE_ID_PATH))?
+        .trim()
+        .to_string())
+}
+
+/// The cloud instance ID, if Afterburn has provided it.
+fn instance_id() -> Option<String> {
+    afterburn::attribute(&["_INSTANCE_ID"])
+}
+
+/// Substitute `{machine-id}`, `{instance-id}` and the provided
+/// extra variables in `template`.
+pub(crate) fn expand(template: &str, extra: &[(&str, &str)]) -> Result<String> {
+    let mut r = template.to_string();
+    if r.contains("{machine-id}") {
+        r = r.replace("{machine-id}", &machine_id()?);
+    }
+    if r.contains("{instance-id}") {
+        let id = instance_id().unwrap_or_else(|| UNKNOWN.to_string());
+        r = r.replace("{instance-id}", &id);
+    }
+    for (k, v) in extra {
+        r = r.replace(&format!("{{{}}}", k), v);
+    }
+    Ok(r)
+}
@@ -0,0 +1,142 @@
+//! Cooperation with Ignition's own disk provisioning: instance devices
+//! which the user's Ignition config partitions or formats are left alone.
+
+use super::*;
+
+/// The rendered config, which survives into the real root on first boot.
+const CONFIG_PATH: &str = "/run/ignition.json";
+/// Labels Ignition created, remembered for subsequent boots.
+const CLAIMED_FILE: &str = "ignition-claimed.json";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct IgnitionConfig {
+    storage: Storage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Storage {
+    disks: Vec<Disk>,
+    raid: Vec<Raid>,
+    filesystems: Vec<Filesystem>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Disk {
+    device: String,
+    partitions: Vec<Labeled>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Raid {
+    devices: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Filesystem {
+    device: String,
+    label: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Labeled {
+    label: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Claimed {
+    /// Device paths from the Ignition config; only known on first boot.
+    #[serde(skip)]
+    devices: Vec<String>,
+    labels: Vec<String>,
+}
+
+fn load() -> Result<Claimed> {
+    let mut claimed = match std::fs::read(Path::new(STATE_DIR).join(CLAIMED_FILE)) {
+        Ok(buf) => serde_json::from_slice(&buf)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Claimed::default(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", CLAIMED_FILE)),
+    };
+    let buf = match std::fs::read(CONFIG_PATH) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(claimed),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", CONFIG_PATH)),
+    };
+    let config: IgnitionConfig =
+        serde_json::from_slice(&buf).with_context(|| format!("Parsing {}", CONFIG_PATH))?;
+    let storage = config.storage;
+    let labels = storage
+        .disks
+        .iter()
+        .flat_map(|d| d.partitions.iter().filter_map(|p| p.label.clone()))
+        .chain(storage.filesystems.iter().filter_map(|f| f.label.clone()));
+    let mut changed = false;
+    for label in labels {
+        if !claimed.labels.contains(&label) {
+            claimed.labels.push(label);
+            changed = true;
+        }
+    }
+    if changed {
+        std::fs::create_dir_all(STATE_DIR)?;
+        openat::Dir::open(STATE_DIR)?.write_file_with(CLAIMED_FILE, 0o644, |w| -> Result<()> {
+            serde_json::to_writer(w, &claimed)?;
+            Ok(())
+        })?;
+    }
+    claimed.devices = storage
+        .disks
+        .into_iter()
+        .map(|d| d.device)
+        .chain(storage.raid.into_iter().flat_map(|r| r.devices))
+        .chain(storage.filesystems.into_iter().map(|f| f.device))
+        .filter(|d| !d.is_empty())
+        .filter_map(|d| std::fs::canonicalize(d).ok())
+        .map(|d| d.to_string_lossy().into_owned())
+        .collect();
+    Ok(claimed)
+}
+
+fn is_claimed(dev: &block::Device, claimed: &Claimed) -> bool {
+    let labeled = [&dev.label, &dev.partlabel]
+        .iter()
+        .filter_map(|l| l.as_deref())
+        .any(|l| claimed.labels.iter().any(|c| c == l));
+    labeled
+        || claimed.devices.contains(&dev.path())
+        || dev
+            .children
+            .iter()
+            .flatten()
+            .any(|child| is_claimed(child, claimed))
+}
+
+/// Remove the devices Ignition has claimed, either directly in its
+/// config or via the labels of what it created.
+pub(crate) fn filter(devs: Vec<String>) -> Result<Vec<String>> {
+    if selftest::active() {
+        return Ok(devs);
+    }
+    let claimed = load()?;
+    if claimed.devices.is_empty() && claimed.labels.is_empty() {
+        return Ok(devs);
+    }
+    let blockdevs = block::list()?;
+    Ok(devs
+        .into_iter()
+        .filter(|path| {
+            let dev = blockdevs.iter().find(|d| &d.path() == path);
+            let r = dev.is_some_and(|d| is_claimed(d, &claimed));
+            if r {
+                println!("Skipping {}, which is provisioned by Ignition", path);
+            }
+            !r
+        })
+        .collect())
+}
@@ -0,0 +1,49 @@
+//! With `inhibit-updates`, keep update orchestrators from rebooting the
+//! node while devices are being wiped and formatted.  Besides a logind
+//! inhibitor lock (which `systemctl reboot` honours), `/run/ccisp/reprovisioning`
+//! holds our PID for orchestrators which check for it, e.g. from a
+//! bootc or Zincati pre-reboot hook.
+
+use super::*;
+use std::process::{Child, Stdio};
+
+const FLAG_FILE: &str = "reprovisioning";
+
+/// Releases the lock when dropped.  The inhibitor is held by a
+/// `systemd-inhibit` child reading from a pipe, so it's also released
+/// if we die without unwinding.
+pub(crate) struct Guard {
+    child: Option<Child>,
+}
+
+pub(crate) fn take() -> Result<Guard> {
+    std::fs::create_dir_all(RUN_DIR)?;
+    let flag = Path::new(RUN_DIR).join(FLAG_FILE);
+    std::fs::write(&flag, format!("{}\n", std::process::id()))
+        .with_context(|| format!("Writing {:?}", flag))?;
+    let child = Command::new("systemd-inhibit")
+        .args([
+            "--what=shutdown:sleep",
+            "--who=coreos-cloud-instance-store-provisioner",
+            "--why=Provisioning instance storage",
+            "--mode=block",
+            "cat",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .inspect_err(|e| eprintln!("warning: Running systemd-inhibit: {}", e))
+        .ok();
+    Ok(Guard { child })
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // Closing stdin ends `cat`, and with it the lock.
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_file(Path::new(RUN_DIR).join(FLAG_FILE));
+    }
+}
@@ -0,0 +1,77 @@
+//! dm-integrity beneath the filesystem, with `integrity: true`; instance
+//! storage has no checksumming of its own.  Like `crypt`, the mapping is
+//! opened during provisioning, and by a unit of ours at boot.
+
+use super::*;
+use libsystemd::unit;
+
+const NAME: &str = "ccisp-integrity";
+const UNIT_NAME: &str = "ccisp-integrity.service";
+
+fn mapped_path() -> String {
+    format!("/dev/mapper/{}", NAME)
+}
+
+pub(crate) fn is_open() -> bool {
+    Path::new(&mapped_path()).exists()
+}
+
+/// Close the mapping from a previous attempt; best-effort.
+pub(crate) fn close() {
+    if let Err(e) = Command::new("integritysetup").arg("close").arg(NAME).run() {
+        eprintln!("warning: {:#}", e);
+    }
+}
+
+/// Format `dev` and open it, returning the path of the mapped device.
+/// Formatting initializes the checksums of the whole device, so
+/// unwritten sectors read back without errors; this takes a while.
+pub(crate) fn format_and_open(dev: &str) -> Result<String> {
+    throttle::apply(Command::new("integritysetup").args(["format", "--batch-mode"]))
+        .arg(dev)
+        .run()?;
+    Command::new("integritysetup")
+        .arg("open")
+        .arg(dev)
+        .arg(NAME)
+        .run()?;
+    Ok(mapped_path())
+}
+
+/// Write the unit opening the mapping at boot, before (and required
+/// by) `before`, which is the encryption or mount unit.
+pub(crate) fn write_unit(unit_dir: &str, dev: &str, before: &str) -> Result<String> {
+    let dev = block::stable_path(dev)?;
+    let device = format!("{}.device", unit::escape_path(&dev));
+    let dir = openat::Dir::open(unit_dir)?;
+    failure::record_unit(unit_dir, UNIT_NAME);
+    dir.write_file_contents(
+        UNIT_NAME,
+        0o644,
+        format!(
+            r##"[Unit]
+Description=Integrity protection of instance storage on {dev}
+DefaultDependencies=no
+Requires={device}
+After={device}
+Before={before} umount.target
+Conflicts=umount.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/bin/sh -c 'test -e {mapped} || integritysetup open {dev} {name}'
+ExecStop=integritysetup close {name}
+
+[Install]
+RequiredBy={before}
+"##,
+            dev = dev,
+            device = device,
+            before = before,
+            mapped = mapped_path(),
+            name = NAME,
+        ),
+    )?;
+    Ok(UNIT_NAME.to_string())
+}
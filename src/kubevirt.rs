@@ -0,0 +1,11 @@
+use super::*;
+
+const DEFAULT_PREFIX: &str = "scratch";
+
+pub(crate) fn devices(config: &Config) -> Result<Vec<String>> {
+    let prefix = config
+        .kubevirt_serial_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_PREFIX);
+    block::with_serial_prefix(prefix)
+}
@@ -0,0 +1,766 @@
+//! Automatically set up a filesystem for instance-local storage
+//! and redirect desired directory paths to it.  Good examples
+//! for this are /var/lib/containers, /var/log, etc.
+//! https://github.com/coreos/ignition/issues/1126
+
+use anyhow::{anyhow, bail, Context, Result};
+use openat_ext::OpenatDirExt;
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+/// Default filesystem label of the instance storage.
+const LABEL: &str = "ccisp-store";
+const CONFIG_PATH: &str = "/etc/ccisp/config.yaml";
+/// Still read if `CONFIG_PATH` doesn't exist, so deployed configs keep working.
+const LEGACY_CONFIG_PATH: &str = "/etc/coreos-cloud-instance-store-provisioner.yaml";
+/// Persistent state; lives on the root disk, not the instance store.
+const STATE_DIR: &str = "/var/lib/ccisp";
+/// Runtime state, scoped to the current boot.
+const RUN_DIR: &str = "/run/ccisp";
+/// Name of the directory (under the mountpoint) for container workloads.
+const WORKLOADS_DIR: &str = "workloads";
+/// Name of the directory (under the mountpoint) holding bind mounted files.
+const FILES_DIR: &str = "files";
+/// Default names used when striping multiple devices together.
+const VG_NAME: &str = "coreos-instance-vg";
+const LV_NAME: &str = "striped";
+/// Default description of generated units.
+const UNIT_DESCRIPTION: &str = "Instance storage for {where}";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Config {
+    /// If unset, the defaults from the distribution profile are used.
+    directories: Option<Vec<DirectoryEntry>>,
+    /// Use this platform ID instead of the one from the kernel command line.
+    platform: Option<String>,
+    /// Explicit device paths or globs (e.g. `/dev/disk/by-id/nvme-*scratch*`);
+    /// if set, platform detection is skipped entirely.
+    #[serde(default)]
+    devices: Vec<String>,
+    /// Only use detected devices matching one of these: paths or globs
+    /// as for `devices`, or otherwise globs over the serial or model.
+    #[serde(default)]
+    only_devices: Vec<String>,
+    /// Never use detected devices matching one of these, as above; e.g.
+    /// to reserve a device for something else.
+    #[serde(default)]
+    exclude_devices: Vec<String>,
+    /// Only use detected devices whose PCI address (e.g. `0000:3b:00.0`,
+    /// globs allowed) matches one of these; e.g. to pool some slots only.
+    #[serde(default)]
+    pci_allowlist: Vec<String>,
+    /// Never use detected devices whose PCI address matches one of these,
+    /// e.g. NVMe controllers dedicated to SPDK or passthrough.
+    #[serde(default)]
+    pci_denylist: Vec<String>,
+    /// On vSphere, disks can't be identified as instance-local by their
+    /// model; these are serial numbers (UUIDs, with `disk.EnableUUID`)
+    /// or device paths (e.g. under `/dev/disk/by-path`) to use.
+    #[serde(default)]
+    vmware_disks: Vec<String>,
+    /// On KubeVirt, disks whose serial (as set in the VMI spec) starts
+    /// with this are used; defaults to `scratch`.
+    kubevirt_serial_prefix: Option<String>,
+    /// On Azure, which kinds of local disks to use.
+    #[serde(default)]
+    azure_disks: azure::Disks,
+    /// Put `/var/tmp` on the instance storage (unless it's listed in
+    /// `directories` already), labeled and cleaned up as a tmp area.
+    #[serde(default)]
+    var_tmp: bool,
+    /// A preset for CI farm nodes: root's home, `/var/cache` and a
+    /// workspace on the instance storage, with cleanup of old files.
+    ci: Option<ci::Settings>,
+    /// Archive the content of each directory here (as a zstd-compressed
+    /// tarball) before it's emptied, e.g. `/var/backups/ccisp`.
+    directory_backup: Option<String>,
+    /// Individual files to bind mount from the instance storage, e.g. a
+    /// large database file whose directory stays on the root disk.
+    #[serde(default)]
+    files: Vec<BindFile>,
+    /// Mount the filesystem with XFS project quotas, with one project
+    /// per redirected directory; see `ccisp quota status`.
+    #[serde(default)]
+    project_quota: bool,
+    /// Additional settings for the instance storage mount unit.
+    mount_unit: Option<UnitSettings>,
+    /// Templates for the names and descriptions of what we create.
+    #[serde(default)]
+    naming: Naming,
+    /// Rules deciding how to handle each directory, evaluated before
+    /// the built-in ones.
+    #[serde(default)]
+    directory_policy: Vec<policy::Rule>,
+    /// What to do if the devices we provisioned have disappeared or been
+    /// replaced since; by default, provision the new ones.
+    #[serde(default)]
+    device_change_policy: DeviceChangePolicy,
+    /// What to do if cloud-init's mounts module also uses one of our
+    /// devices or directories: `warn` (the default) or `fail`.
+    #[serde(default)]
+    cloud_init_conflicts: cloudinit::ConflictPolicy,
+    /// Create a `workloads` directory on the instance storage which
+    /// containers can use via a hostPath volume.
+    #[serde(default)]
+    workloads_directory: bool,
+    /// Whether to reuse a filesystem we created earlier which survived
+    /// (e.g. a reboot on a platform where instance storage persists,
+    /// but where our state on the root disk was lost).
+    #[serde(default)]
+    reprovision: Reprovision,
+    /// Discard the space of logical volumes we remove when reprovisioning,
+    /// so the NVMe device knows it's free.  Off by default since it can
+    /// take a while on large devices.
+    #[serde(default)]
+    lvm_discards: bool,
+    /// The layout of logical volumes across the devices.
+    #[serde(default)]
+    lvm_type: lvm::LvType,
+    /// For striped layouts, the stripe size (e.g. `128k`); LVM defaults to 64k.
+    lvm_stripe_size: Option<String>,
+    /// For striped layouts, the number of stripes; by default, all devices
+    /// (or half of them for raid10).
+    lvm_stripes: Option<u32>,
+    /// Detect silent corruption with dm-integrity beneath the filesystem
+    /// (and encryption); with the raid1 and raid10 LVM types, LVM's own
+    /// integrity is used, which can also repair it from the other copy.
+    #[serde(default)]
+    integrity: bool,
+    /// Put the shared filesystem and `backing: lv` directories on thin
+    /// volumes in a pool over the instance storage, so their sizes can
+    /// add up to more than there is.
+    #[serde(default)]
+    thin_pool: bool,
+    /// Pick the quickest options for getting to usable storage, e.g. skip
+    /// discards; for autoscaling fleets where node-ready latency matters.
+    #[serde(default)]
+    fast_boot: bool,
+    /// Hold a shutdown inhibitor lock (and write a flag file) while
+    /// wiping and creating filesystems, so update orchestrators don't
+    /// reboot the node midway.
+    #[serde(default)]
+    inhibit_updates: bool,
+    /// Set up systemd-oomd policies for the swap or tmpfs we provision.
+    #[serde(default)]
+    oomd: bool,
+    /// What to do on instance types without instance storage.
+    #[serde(default)]
+    fallback: Fallback,
+    /// The size of each tmpfs with `fallback: tmpfs`, e.g. `2G` or `10%`
+    /// (of RAM).
+    fallback_size: Option<String>,
+    /// Fail unless at least this many instance devices are found; with
+    /// `provision --wait-for-device`, this is also how many to wait for.
+    min_devices: Option<u32>,
+    /// Discard the whole devices before creating anything on them, which
+    /// helps the steady-state write performance of NVMe devices.  Ignored
+    /// with `fast_boot`.
+    #[serde(default)]
+    blkdiscard: bool,
+    /// Securely erase the devices before use, and optionally when tearing
+    /// down what we provisioned on them.
+    scrub: Option<scrub::Settings>,
+    /// Trim the instance storage filesystem weekly, with a timer of its own.
+    #[serde(default)]
+    fstrim: bool,
+    /// Periodically check the audit log for SELinux denials involving the
+    /// instance storage, failing a unit if there are any.
+    #[serde(default)]
+    avc_check: bool,
+    /// Volumes carved out of the instance storage for others to use,
+    /// e.g. nested VMs on bare metal.
+    #[serde(default)]
+    exports: Vec<exports::Export>,
+    /// The filesystem to create on the instance storage.
+    #[serde(default)]
+    filesystem: Filesystem,
+    /// Extra arguments for mkfs, e.g. `-K` to skip discarding; an XFS
+    /// `su=` here replaces the alignment derived from the LVM stripes.
+    #[serde(default)]
+    mkfs_options: Vec<String>,
+    /// Treat `directories: []` as disabling provisioning, rather than
+    /// as an error; for configs generated from templates.
+    #[serde(default)]
+    allow_empty_directories: bool,
+    /// Encrypt the instance storage (`true` for LUKS2, or `plain`), using a
+    /// random key which is never written anywhere.  The storage is set up
+    /// from scratch on every boot, and all generated units only last
+    /// until shutdown.
+    #[serde(default)]
+    encrypt: crypt::Encrypt,
+    /// With `encrypt: true`, bind the key with Clevis so that the storage
+    /// persists across reboots.
+    clevis: Option<crypt::Clevis>,
+    /// Carve a swap volume out of the instance storage.
+    swap: Option<swap::Swap>,
+    /// Limits for heavy commands such as mkfs.
+    #[serde(default)]
+    heavy_commands: throttle::Settings,
+    /// What to use the instance storage for.
+    #[serde(default)]
+    mode: Mode,
+    /// Partition a single device, e.g. into swap and the filesystem,
+    /// instead of using it whole.
+    #[serde(default)]
+    partitions: Vec<partition::Partition>,
+    /// Combine multiple devices with mdraid, instead of LVM striping.
+    raid: Option<mdraid::Raid>,
+    /// If the instance devices are already assembled into an md array
+    /// (e.g. by the image), use the array rather than its members.
+    #[serde(default)]
+    adopt_existing_raid: bool,
+    /// Required for `mode: cache`.
+    cache: Option<lvmcache::Settings>,
+    /// Required for `mode: bcache`.
+    bcache: Option<bcache::Settings>,
+    /// The filesystem label; defaults to `ccisp-store`.
+    label: Option<String>,
+    /// Where to mount the instance storage, instead of the profile default.
+    mountpoint: Option<String>,
+}
+
+impl Config {
+    fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(LABEL)
+    }
+
+    fn mountpoint<'a>(&'a self, profile: &'a profile::Profile) -> &'a str {
+        self.mountpoint.as_deref().unwrap_or(profile.mountpoint)
+    }
+
+    /// Checks which don't depend on the host.
+    fn validate(&self) -> Result<()> {
+        let label = self.label();
+        if label.is_empty() || label.contains(|c: char| c == '/' || c.is_whitespace()) {
+            bail!("Invalid label: {:?}", label);
+        }
+        if let Some(m) = self.mountpoint.as_deref() {
+            if !m.starts_with('/') || m.trim_end_matches('/').is_empty() {
+                bail!("mountpoint must be an absolute path below /: {:?}", m);
+            }
+        }
+        if let Some(f) = self.files.iter().find(|f| !f.path.starts_with('/')) {
+            bail!("files must be absolute paths: {:?}", f.path);
+        }
+        if let Some(b) = self.directory_backup.as_deref() {
+            if !b.starts_with('/') {
+                bail!("directory-backup must be an absolute path: {:?}", b);
+            }
+        }
+        if self.clevis.is_some() && self.encrypt != crypt::Encrypt::Luks {
+            bail!("clevis requires encrypt: true");
+        }
+        if let Some(swap) = self.swap.as_ref() {
+            if swap.kind == swap::Kind::File && swap.size.ends_with('%') {
+                bail!("A swap file needs an absolute size, not {}", swap.size);
+            }
+        }
+        if let Some(raid) = self.raid.as_ref() {
+            raid.validate()?;
+        }
+        if self.thin_pool && !matches!(self.lvm_type, lvm::LvType::Striped | lvm::LvType::Linear) {
+            bail!("thin-pool requires lvm-type striped or linear");
+        }
+        if self.fallback == Fallback::Tmpfs && self.fallback_size.is_none() {
+            bail!("fallback: tmpfs requires fallback-size");
+        }
+        if !self.partitions.is_empty() {
+            partition::validate(&self.partitions)?;
+        }
+        match self.mode {
+            Mode::Cache if self.cache.is_none() => bail!("mode: cache requires cache settings"),
+            Mode::Bcache if self.bcache.is_none() => {
+                bail!("mode: bcache requires bcache settings")
+            }
+            Mode::Cache | Mode::Bcache if self.encrypt.enabled() => {
+                bail!("Caching modes do not support encrypt")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Templates in which `{machine-id}` and `{instance-id}` are substituted,
+/// e.g. to tell where disks came from when they're examined elsewhere.
+/// In unit descriptions, `{where}` is the mount point.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+struct Naming {
+    unit_description: String,
+    vg_name: String,
+    lv_name: String,
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Self {
+            unit_description: UNIT_DESCRIPTION.to_string(),
+            vg_name: VG_NAME.to_string(),
+            lv_name: LV_NAME.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum DeviceChangePolicy {
+    /// Set up the storage from scratch.
+    #[default]
+    Reprovision,
+    /// Fail, disabling the mount units so that whatever is on
+    /// the new devices isn't used.  For environments where a changed
+    /// disk needs to be investigated.
+    Alert,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Reprovision {
+    /// Always wipe the devices and create a new filesystem.
+    #[default]
+    Always,
+    /// Keep an existing filesystem if its epoch marker shows it was
+    /// created on this machine; anything else is wiped.
+    IfUnformatted,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    /// A filesystem of its own, which directories are redirected to.
+    #[default]
+    Filesystem,
+    /// An LVM cache in front of a logical volume on persistent storage;
+    /// nothing is lost when the instance storage is.
+    Cache,
+    /// Likewise, but a bcache cache set in front of a block device.
+    Bcache,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Filesystem {
+    #[default]
+    Xfs,
+    Ext4,
+    Btrfs,
+    F2fs,
+}
+
+impl Filesystem {
+    /// As used for `Type=` in mount units.
+    fn name(self) -> &'static str {
+        match self {
+            Filesystem::Xfs => "xfs",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Btrfs => "btrfs",
+            Filesystem::F2fs => "f2fs",
+        }
+    }
+
+    /// Options making mkfs as quick as possible: no discard of the device
+    /// (instance storage is normally delivered trimmed), and lazy
+    /// initialization where supported.
+    fn fast_options(self) -> &'static [&'static str] {
+        match self {
+            Filesystem::Xfs => &["-K"],
+            Filesystem::Ext4 => &["-E", "nodiscard,lazy_itable_init=1,lazy_journal_init=1"],
+            Filesystem::Btrfs => &["--nodiscard"],
+            Filesystem::F2fs => &["-t", "0"],
+        }
+    }
+
+    /// A mkfs invocation creating a filesystem, labeled `label` if set;
+    /// the device is appended by the caller.
+    fn mkfs(self, label: Option<&str>) -> Command {
+        let mut c = Command::new(format!("mkfs.{}", self.name()));
+        match (self, label) {
+            (_, None) => &mut c,
+            // mkfs.f2fs uses -l for the label, and -L for something else
+            (Filesystem::F2fs, Some(label)) => c.args(["-l", label]),
+            (_, Some(label)) => c.args(["-L", label]),
+        };
+        c
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Backing {
+    /// A bind mount of a directory on the instance storage.
+    #[default]
+    Bind,
+    /// A fixed-size filesystem image on the instance storage, mounted
+    /// via a loop device; this gives a hard cap on usage.
+    File,
+    /// A dedicated logical volume with its own filesystem, e.g. to keep
+    /// log growth from affecting container storage.
+    Lv,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Fallback {
+    /// Leave the directories on the root disk.
+    #[default]
+    None,
+    /// Mount a tmpfs of `fallback-size` on each directory.
+    Tmpfs,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum DirectoryMode {
+    /// The directory starts out empty.
+    #[default]
+    Replace,
+    /// An overlayfs with the original content as the lower layer, and
+    /// the instance storage taking all writes; e.g. to keep container
+    /// images pre-pulled into the base image.  Only for `backing: bind`.
+    Overlay,
+}
+
+/// Parse a size such as `512M` or `10G` (binary units) into bytes.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix("iB").unwrap_or(s);
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => bail!("Invalid size suffix in {:?}", s),
+            };
+            (&s[..i], shift)
+        }
+        _ => (s, 0),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size {:?}", s))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size too large: {:?}", s))
+}
+
+/// Extra settings passed through to a generated mount unit, e.g. to
+/// allow more time for slow-attaching devices.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct UnitSettings {
+    /// Appended to `Options=`, e.g. `x-systemd.device-timeout=5min`.
+    #[serde(default)]
+    options: Vec<String>,
+    /// Extra directives for the `[Unit]` section.
+    #[serde(default)]
+    unit: BTreeMap<String, String>,
+    /// Extra directives for the `[Mount]` section, e.g. `TimeoutSec`.
+    #[serde(default)]
+    mount: BTreeMap<String, String>,
+}
+
+/// The config file to use, if any; the legacy location is used (with
+/// a notice) if the current one doesn't exist.  Notices go to stderr,
+/// once per process, to keep machine-readable output on stdout intact.
+fn config_path() -> Option<&'static str> {
+    static NOTICE: std::sync::Once = std::sync::Once::new();
+    let legacy = Path::new(LEGACY_CONFIG_PATH).exists();
+    if Path::new(CONFIG_PATH).exists() {
+        if legacy {
+            NOTICE.call_once(|| {
+                eprintln!(
+                    "warning: Ignoring {} in favor of {}",
+                    LEGACY_CONFIG_PATH, CONFIG_PATH
+                )
+            });
+        }
+        Some(CONFIG_PATH)
+    } else if legacy {
+        NOTICE.call_once(|| {
+            eprintln!(
+                "note: {} is deprecated; move it to {}",
+                LEGACY_CONFIG_PATH, CONFIG_PATH
+            )
+        });
+        Some(LEGACY_CONFIG_PATH)
+    } else {
+        None
+    }
+}
+
+/// A fingerprint of the config file, recorded when provisioning so that
+/// fleet rollouts can tell whether a node's layout is from the current one.
+/// FNV-1a, as it's stable across builds, unlike `DefaultHasher`.
+fn config_digest() -> Result<Option<String>> {
+    let configpath = match config_path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let buf = std::fs::read(configpath).with_context(|| format!("Reading {}", configpath))?;
+    let hash = buf.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    Ok(Some(format!("{:016x}", hash)))
+}
+
+/// Load the config file, if it exists.
+fn load_config() -> Result<Option<Config>> {
+    let configpath = match config_path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let buf =
+        std::fs::read_to_string(configpath).with_context(|| format!("Reading {}", configpath))?;
+    if buf.trim().is_empty() {
+        bail!(
+            "{} exists but is empty; remove it, or use `{{}}` for the defaults",
+            configpath
+        );
+    }
+    // serde_yaml includes the line and column in its errors.
+    let config = serde_yaml::from_str(&buf).with_context(|| {
+        format!(
+            "Parsing {}; expected a YAML mapping with keys such as `directories` (see the README)",
+            configpath
+        )
+    })?;
+    Ok(Some(config))
+}
+
+/// A directory to redirect; either just its path, or a full `Directory`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum DirectoryEntry {
+    Path(String),
+    Directory(Box<Directory>),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Directory {
+    path: String,
+    /// Units which should pull in the bind mount; by default it's
+    /// part of `local-fs.target`.  If set, the mount is only activated
+    /// when one of these starts.
+    #[serde(default)]
+    wanted_by: Vec<String>,
+    /// How the directory is backed by instance storage.
+    #[serde(default)]
+    backing: Backing,
+    /// What happens to the existing content of the directory.
+    #[serde(default)]
+    mode: DirectoryMode,
+    /// Copy the existing content onto the instance storage instead of
+    /// deleting it.
+    #[serde(default)]
+    migrate: bool,
+    /// Ownership and permissions for the new directory, e.g. `postgres`,
+    /// `postgres` and `0700`; by default it's root's, with mode 0755.
+    /// Users and groups may be names or numeric IDs.
+    owner: Option<String>,
+    group: Option<String>,
+    permissions: Option<String>,
+    /// A persistent directory to copy the content back to at shutdown,
+    /// e.g. `/var/log-persistent` for `/var/log`, or a remote rsync
+    /// target such as `backup.example.com:/srv/logs`.
+    sync_back: Option<String>,
+    /// Also copy to `sync-back` this often, e.g. `15min`.
+    sync_interval: Option<String>,
+    /// Required for `backing: file` or `lv`, e.g. `10G`; for `lv`, this
+    /// may also be a percentage of the instance storage, e.g. `20%`.
+    size: Option<String>,
+    /// Only redirect this directory if the instance storage can still
+    /// hold this much after the directories listed before it, e.g. `50G`.
+    min_size: Option<String>,
+    /// With `project-quota` (or on btrfs, as a qgroup limit), a hard limit
+    /// on the space the directory may use on the shared filesystem, e.g. `50G`.
+    quota: Option<String>,
+    /// Extra ordering for the bind mount unit, e.g. `chronyd-wait.service`.
+    #[serde(default)]
+    before: Vec<String>,
+    #[serde(default)]
+    after: Vec<String>,
+    /// Additional settings for the bind mount unit.
+    mount_unit: Option<UnitSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BindFile {
+    path: String,
+    /// If set, the file is created sparse with this size, e.g. `10G`.
+    size: Option<String>,
+    /// As for directories.
+    #[serde(default)]
+    wanted_by: Vec<String>,
+}
+
+impl Directory {
+    fn size(&self) -> Result<&str> {
+        self.size
+            .as_deref()
+            .ok_or_else(|| anyhow!("{}: backing requires size", self.path))
+    }
+
+    fn has_ownership(&self) -> bool {
+        self.owner.is_some() || self.group.is_some() || self.permissions.is_some()
+    }
+
+    /// The name of the logical volume for `backing: lv`, e.g. `dir-log`.
+    fn lv_name(&self) -> Result<String> {
+        let name = Path::new(&self.path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Expected filename in {:?}", self.path))?;
+        Ok(format!("dir-{}", name))
+    }
+}
+
+impl From<DirectoryEntry> for Directory {
+    fn from(e: DirectoryEntry) -> Self {
+        match e {
+            DirectoryEntry::Path(path) => Directory {
+                path,
+                ..Default::default()
+            },
+            DirectoryEntry::Directory(d) => *d,
+        }
+    }
+}
+
+pub(crate) trait CommandRunExt {
+    fn run(&mut self) -> Result<()>;
+}
+
+impl CommandRunExt for Command {
+    fn run(&mut self) -> Result<()> {
+        failure::record_command(self);
+        let r = self.status()?;
+        if !r.success() {
+            bail!("Child [{:?}] exited: {}", self, r);
+        }
+        Ok(())
+    }
+}
+
+/// Run `f` with the filesystem on `what` mounted (with `options`) at
+/// `name` under our runtime directory.
+fn with_mounted<T>(
+    name: &str,
+    what: &Path,
+    options: &str,
+    f: impl FnOnce(&Path) -> Result<T>,
+) -> Result<T> {
+    let mnt = Path::new(RUN_DIR).join(name);
+    std::fs::create_dir_all(&mnt)?;
+    Command::new("mount")
+        .args(["-o", options])
+        .arg(what)
+        .arg(&mnt)
+        .run()?;
+    let r = f(&mnt);
+    Command::new("umount").arg(&mnt).run()?;
+    r
+}
+
+mod afterburn;
+mod archive;
+mod avc;
+mod aws;
+mod azure;
+mod backup;
+mod bcache;
+mod block;
+mod btrfs;
+mod cache;
+mod ci;
+mod cli;
+mod cloudinit;
+mod consumers;
+mod coreos;
+mod crypt;
+mod digitalocean;
+mod epoch;
+mod equinix;
+mod events;
+mod exports;
+mod failure;
+#[cfg(feature = "fault-injection")]
+mod faultinject;
+mod fstrim;
+mod hetzner;
+mod identity;
+mod ignition;
+mod inhibit;
+mod integrity;
+mod kubevirt;
+mod loopback;
+mod lvm;
+mod lvmcache;
+mod manual;
+mod mdraid;
+mod metadata;
+mod metal;
+mod migrate;
+mod oomd;
+mod ownership;
+mod partition;
+mod pci;
+mod plan;
+mod policy;
+mod preflight;
+mod profile;
+mod provision;
+mod qemu;
+mod quota;
+mod scaleway;
+mod scrub;
+mod selftest;
+mod selinux;
+mod smoke;
+mod snapshot;
+mod state;
+mod stats;
+mod swap;
+mod syncback;
+mod systemd;
+mod tencentcloud;
+mod throttle;
+mod tmpfs;
+mod vartmp;
+mod vmware;
+mod vultr;
+
+pub use cli::run;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        let cases = [
+            ("512", 512),
+            ("512M", 512 << 20),
+            ("10G", 10 << 30),
+            ("10g", 10 << 30),
+            ("1GiB", 1 << 30),
+            ("2T", 2 << 40),
+            (" 4K ", 4 << 10),
+            ("0", 0),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_size(s).unwrap(), expected, "{:?}", s);
+        }
+        for s in ["", "G", "10X", "1.5G", "-1G", "10GB", "17179869184G"] {
+            assert!(parse_size(s).is_err(), "{:?}", s);
+        }
+    }
+}
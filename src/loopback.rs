@@ -0,0 +1,20 @@
+//! Filesystem images on the instance storage, for directories with
+//! `backing: file`.
+
+use super::*;
+
+/// Create a sparse image of `size` bytes at `image` with an XFS
+/// filesystem.  If `label_from` is set, the root of the new filesystem
+/// gets the SELinux context the policy defines for it.
+pub(crate) fn create(image: &Path, size: u64, label_from: Option<&Path>) -> Result<()> {
+    let f = std::fs::File::create(image).with_context(|| format!("Creating {:?}", image))?;
+    f.set_len(size)?;
+    drop(f);
+    throttle::apply(&mut Command::new("mkfs.xfs"))
+        .arg(image)
+        .run()?;
+    if let Some(src) = label_from {
+        selinux::label_root_as(src, image, "loop")?;
+    }
+    Ok(())
+}
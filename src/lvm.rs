@@ -0,0 +1,507 @@
+use super::*;
+
+/// The first release with `--devices` and the devices file, which
+/// RHEL9 enables by default; devices missing from it are invisible
+/// unless named with `--devices`.
+const DEVICES_FILE_VERSION: (u32, u32, u32) = (2, 3, 12);
+
+/// What the host's LVM supports; the tools differ quite a bit
+/// between e.g. RHEL8 and RHEL9.
+#[derive(Debug)]
+struct Capabilities {
+    version: (u32, u32, u32),
+    /// From `lvm segtypes`, e.g. `raid10` or `thin-pool`.
+    segtypes: Vec<String>,
+}
+
+/// Parse e.g. `  LVM version:     2.03.14(2) (2021-10-20)`.
+fn parse_version(output: &str) -> Option<(u32, u32, u32)> {
+    let v = output
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("LVM version:"))?
+        .trim();
+    let v = v.split(|c: char| !c.is_ascii_digit() && c != '.').next()?;
+    let mut parts = v.split('.').map(|p| p.parse().ok());
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+fn probe() -> Result<Capabilities> {
+    let o = Command::new("lvm").arg("version").output()?;
+    if !o.status.success() {
+        bail!("lvm version: {}", String::from_utf8_lossy(&o.stderr).trim());
+    }
+    let out = String::from_utf8(o.stdout)?;
+    let version =
+        parse_version(&out).ok_or_else(|| anyhow!("Failed to parse lvm version from {:?}", out))?;
+    let o = Command::new("lvm").arg("segtypes").output()?;
+    if !o.status.success() {
+        bail!(
+            "lvm segtypes: {}",
+            String::from_utf8_lossy(&o.stderr).trim()
+        );
+    }
+    let segtypes = String::from_utf8(o.stdout)?
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect();
+    Ok(Capabilities { version, segtypes })
+}
+
+fn capabilities() -> Result<&'static Capabilities> {
+    static CAPS: std::sync::OnceLock<Capabilities> = std::sync::OnceLock::new();
+    if let Some(caps) = CAPS.get() {
+        return Ok(caps);
+    }
+    let caps = probe().context("Probing LVM capabilities")?;
+    Ok(CAPS.get_or_init(|| caps))
+}
+
+/// Fail early with a clear message if the host's LVM lacks
+/// any of `segtypes`.
+pub(crate) fn require(segtypes: &[&str]) -> Result<()> {
+    let caps = capabilities()?;
+    let missing: Vec<_> = segtypes
+        .iter()
+        .filter(|t| !caps.segtypes.iter().any(|s| s == *t))
+        .collect();
+    if !missing.is_empty() {
+        let (a, b, c) = caps.version;
+        bail!(
+            "LVM {}.{:02}.{:02} on this host does not support {:?}",
+            a,
+            b,
+            c,
+            missing
+        );
+    }
+    Ok(())
+}
+
+/// Restrict a command to `dev`, so that it's seen even if missing
+/// from the devices file.
+fn only_device(c: &mut Command, dev: &str) -> Result<()> {
+    if capabilities()?.version >= DEVICES_FILE_VERSION {
+        c.arg("--devices").arg(dev);
+    }
+    Ok(())
+}
+
+/// A single invocation for all devices, which is notably quicker
+/// than one per device.
+fn pvcreate(devs: &[String]) -> Result<()> {
+    Command::new("lvm").arg("pvcreate").args(devs).run()
+}
+
+/// Characters LVM allows in VG and LV names.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+}
+
+/// The device-mapper name of a logical volume: the VG and LV names
+/// joined by a dash, with dashes within each name doubled.  Dots,
+/// underscores and plus signs are kept as is.
+fn dm_name(vgname: &str, lvname: &str) -> Result<String> {
+    for name in [vgname, lvname] {
+        if !valid_name(name) {
+            bail!("Invalid LVM name: {:?}", name);
+        }
+    }
+    Ok(format!(
+        "{}-{}",
+        vgname.replace('-', "--"),
+        lvname.replace('-', "--")
+    ))
+}
+
+/// Ask LVM for the device-mapper path of a logical volume.
+fn lv_dm_path(vgname: &str, lvname: &str) -> Result<String> {
+    let o = Command::new("lvm")
+        .args(["lvs", "--noheadings", "-o", "lv_dm_path"])
+        .arg(format!("{}/{}", vgname, lvname))
+        .output()?;
+    if !o.status.success() {
+        bail!(
+            "Querying path of {}/{}: {}",
+            vgname,
+            lvname,
+            String::from_utf8_lossy(&o.stderr).trim()
+        );
+    }
+    let path = String::from_utf8(o.stdout)?.trim().to_string();
+    if path.is_empty() {
+        bail!("No path reported for {}/{}", vgname, lvname);
+    }
+    Ok(path)
+}
+
+/// If `mixed_sectors` is set, the devices have differing logical
+/// sector sizes, which LVM refuses by default.
+pub(crate) fn create_vg(vgname: &str, devices: &[String], mixed_sectors: bool) -> Result<()> {
+    if !valid_name(vgname) {
+        bail!("Invalid volume group name: {:?}", vgname);
+    }
+    pvcreate(devices)?;
+    let mut c = Command::new("lvm");
+    c.arg("vgcreate");
+    if mixed_sectors {
+        c.args(["--config", "devices { allow_mixed_block_sizes = 1 }"]);
+    }
+    c.arg(vgname).args(devices).run()?;
+    Ok(())
+}
+
+/// Add `devices` to an existing volume group; as with `create_vg`,
+/// `pvcreate` adds them to the devices file if one is in use.
+pub(crate) fn extend_vg(vgname: &str, devices: &[String]) -> Result<()> {
+    pvcreate(devices)?;
+    Command::new("lvm")
+        .arg("vgextend")
+        .arg(vgname)
+        .args(devices)
+        .run()
+}
+
+/// Drop physical volumes which have gone missing from `vgname`, along
+/// with any logical volumes on them.
+pub(crate) fn reduce_missing(vgname: &str) -> Result<()> {
+    Command::new("lvm")
+        .args(["vgreduce", "--removemissing", "--force"])
+        .arg(vgname)
+        .run()
+}
+
+/// LVM's default stripe size, which XFS needs to know for alignment.
+const DEFAULT_STRIPE_SIZE: &str = "64k";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LvType {
+    #[default]
+    Striped,
+    Raid0,
+    Raid1,
+    Raid10,
+    /// Concatenated across the devices, e.g. when their latencies
+    /// differ so much that striping hurts.
+    Linear,
+}
+
+/// How logical volumes are laid out across the physical volumes.
+#[derive(Debug)]
+pub(crate) struct Layout {
+    kind: LvType,
+    stripes: u32,
+    stripe_size: Option<String>,
+    integrity: bool,
+}
+
+impl Layout {
+    /// By default, stripe across all `pvs` (mirrored pairs for raid10).
+    pub(crate) fn new(
+        kind: LvType,
+        stripes: Option<u32>,
+        stripe_size: Option<&str>,
+        pvs: usize,
+    ) -> Self {
+        let pvs = pvs as u32;
+        let stripes = stripes.unwrap_or(match kind {
+            LvType::Striped | LvType::Raid0 => pvs,
+            LvType::Raid10 => pvs / 2,
+            LvType::Raid1 | LvType::Linear => 1,
+        });
+        Self {
+            kind,
+            stripes: stripes.max(1),
+            stripe_size: stripe_size.map(|s| s.to_string()),
+            integrity: false,
+        }
+    }
+
+    /// Add integrity to mirrored layouts, if `integrity` is set.
+    pub(crate) fn with_raid_integrity(mut self, integrity: bool) -> Self {
+        self.integrity = integrity && matches!(self.kind, LvType::Raid1 | LvType::Raid10);
+        self
+    }
+
+    /// Whether the logical volumes have integrity of their own.
+    pub(crate) fn raid_integrity(&self) -> bool {
+        self.integrity
+    }
+
+    /// The segment types needed for this layout, plus thin
+    /// provisioning if `thin`.
+    pub(crate) fn segtypes(&self, thin: bool) -> Vec<&'static str> {
+        let mut r = vec![self.kind_name()];
+        if self.integrity {
+            r.push("integrity");
+        }
+        if thin {
+            r.extend(["thin-pool", "thin"]);
+        }
+        r
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            LvType::Striped => "striped",
+            LvType::Raid0 => "raid0",
+            LvType::Raid1 => "raid1",
+            LvType::Raid10 => "raid10",
+            LvType::Linear => "linear",
+        }
+    }
+
+    fn is_striped(&self) -> bool {
+        matches!(self.kind, LvType::Striped | LvType::Raid0 | LvType::Raid10) && self.stripes > 1
+    }
+
+    fn args(&self, c: &mut Command) {
+        c.args(["--type", self.kind_name()]);
+        if matches!(self.kind, LvType::Raid1 | LvType::Raid10) {
+            c.args(["--mirrors", "1"]);
+        }
+        if self.integrity {
+            c.args(["--raidintegrity", "y"]);
+        }
+        self.stripe_args(c);
+    }
+
+    fn stripe_args(&self, c: &mut Command) {
+        if self.is_striped() {
+            c.arg("--stripes").arg(self.stripes.to_string());
+            if let Some(size) = self.stripe_size.as_deref() {
+                c.args(["--stripesize", size]);
+            }
+        }
+    }
+
+    /// The `-d` options for mkfs.xfs to align with the stripes, if any.
+    pub(crate) fn xfs_alignment(&self) -> Option<String> {
+        let su = self.stripe_size.as_deref().unwrap_or(DEFAULT_STRIPE_SIZE);
+        Some(format!("su={},sw={}", su, self.stripes)).filter(|_| self.is_striped())
+    }
+}
+
+/// Create a logical volume of `size` (in `lvcreate --size` syntax, or
+/// a percentage of the volume group such as `10%`), or using all
+/// remaining space, returning its path.
+pub(crate) fn create_lv(
+    vgname: &str,
+    lvname: &str,
+    size: Option<&str>,
+    layout: &Layout,
+) -> Result<String> {
+    let mut c = Command::new("lvm");
+    c.arg("lvcreate");
+    layout.args(&mut c);
+    match size {
+        Some(pct) if pct.ends_with('%') => c.arg("--extents").arg(format!("{}VG", pct)),
+        Some(size) => c.args(["--size", size]),
+        None => c.args(["--extents", "100%FREE"]),
+    };
+    let expected = format!("/dev/mapper/{}", dm_name(vgname, lvname)?);
+    c.arg(vgname).arg("--name").arg(lvname).run()?;
+    let path = lv_dm_path(vgname, lvname)?;
+    if path != expected {
+        eprintln!(
+            "warning: LVM reports path {} for {}/{}, expected {}",
+            path, vgname, lvname, expected
+        );
+    }
+    Ok(path)
+}
+
+/// The thin pool with `thin-pool: true`.
+pub(crate) const POOL_NAME: &str = "pool";
+
+/// Create a thin pool using all remaining space (including for its
+/// metadata), striped according to `layout`.
+pub(crate) fn create_thin_pool(vgname: &str, poolname: &str, layout: &Layout) -> Result<()> {
+    let mut c = Command::new("lvm");
+    c.args(["lvcreate", "--type", "thin-pool", "--extents", "100%FREE"]);
+    layout.stripe_args(&mut c);
+    c.arg(vgname).arg("--name").arg(poolname).run()
+}
+
+fn lv_size(vgname: &str, lvname: &str) -> Result<u64> {
+    let o = Command::new("lvm")
+        .args([
+            "lvs",
+            "--noheadings",
+            "--units",
+            "b",
+            "--nosuffix",
+            "-o",
+            "lv_size",
+        ])
+        .arg(format!("{}/{}", vgname, lvname))
+        .output()?;
+    if !o.status.success() {
+        bail!("Querying size of {}/{}", vgname, lvname);
+    }
+    Ok(String::from_utf8(o.stdout)?.trim().parse()?)
+}
+
+/// Create a thin volume in `poolname` with a virtual size of `size`,
+/// which may be a percentage of the pool; by default it's as large
+/// as the pool.  Returns its path.
+pub(crate) fn create_thin(
+    vgname: &str,
+    poolname: &str,
+    lvname: &str,
+    size: Option<&str>,
+) -> Result<String> {
+    let size = match size {
+        Some(pct) if pct.ends_with('%') => {
+            let pct: u64 = pct
+                .trim_end_matches('%')
+                .parse()
+                .with_context(|| format!("Invalid percentage {:?}", pct))?;
+            format!("{}b", lv_size(vgname, poolname)? * pct / 100)
+        }
+        Some(size) => size.to_string(),
+        None => format!("{}b", lv_size(vgname, poolname)?),
+    };
+    Command::new("lvm")
+        .args(["lvcreate", "--type", "thin", "--virtualsize", &size])
+        .arg("--thinpool")
+        .arg(poolname)
+        .arg(vgname)
+        .arg("--name")
+        .arg(lvname)
+        .run()?;
+    lv_dm_path(vgname, lvname)
+}
+
+pub(crate) fn vg_exists(vgname: &str) -> Result<bool> {
+    let o = Command::new("lvm")
+        .args(["vgs", "--noheadings"])
+        .arg(vgname)
+        .output()?;
+    Ok(o.status.success())
+}
+
+/// Tear down a volume group which may have lost some or all of its
+/// physical volumes.  This is best-effort; errors are only logged.
+/// With `discards`, the space of removed logical volumes is discarded
+/// on the remaining devices, as with `issue_discards = 1` in lvm.conf.
+pub(crate) fn remove_stale_vg(vgname: &str, discards: bool) {
+    let cmds: &[&[&str]] = &[
+        &["vgchange", "--activate", "n"],
+        &["vgreduce", "--removemissing", "--force"],
+        &["vgremove", "--force"],
+    ];
+    for args in cmds {
+        let mut c = Command::new("lvm");
+        c.args(*args);
+        if discards {
+            c.args(["--config", "devices { issue_discards = 1 }"]);
+        }
+        if let Err(e) = c.arg(vgname).run() {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+}
+
+/// Drop `dev` from the devices file, which `pvcreate` adds it to;
+/// best-effort, as the devices file may well not be in use.
+pub(crate) fn forget_device(dev: &str) {
+    if capabilities().is_ok_and(|c| c.version >= DEVICES_FILE_VERSION) {
+        let _ = Command::new("lvm")
+            .args(["lvmdevices", "--deldev"])
+            .arg(dev)
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+}
+
+/// Returns the name of the volume group `dev` belongs to, if any.
+fn vg_of(dev: &str) -> Result<Option<String>> {
+    let mut c = Command::new("lvm");
+    c.args(["pvs", "--noheadings", "-o", "vg_name"]);
+    only_device(&mut c, dev)?;
+    let o = c.arg(dev).output()?;
+    // Not being a PV at all is the common case.
+    if !o.status.success() {
+        return Ok(None);
+    }
+    let vg = String::from_utf8(o.stdout)?.trim().to_string();
+    Ok(Some(vg).filter(|vg| !vg.is_empty()))
+}
+
+/// Save the metadata of any volume groups on `devices` into `backupdir`
+/// as `lvm-<vgname>.vg`, suitable for `vgcfgrestore -f`.
+pub(crate) fn backup_metadata(devices: &[String], backupdir: &Path) -> Result<()> {
+    let mut vgs = Vec::new();
+    for dev in devices {
+        if let Some(vg) = vg_of(dev)? {
+            if !vgs.contains(&vg) {
+                vgs.push(vg);
+            }
+        }
+    }
+    for vg in vgs {
+        let dest = backupdir.join(format!("lvm-{}.vg", vg));
+        Command::new("lvm")
+            .arg("vgcfgbackup")
+            .arg("-f")
+            .arg(&dest)
+            .arg(&vg)
+            .run()?;
+        println!("Saved metadata for volume group {} to {:?}", vg, dest);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_names() {
+        for name in ["ccisp", "a-b", "a.b", "a_b", "a+b", "A9"] {
+            assert!(valid_name(name), "{:?}", name);
+        }
+        for name in ["", "-a", ".", "..", "a/b", "a b", "a:b", "\u{e4}"] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn dm_names() {
+        let cases = [
+            ("vg", "lv", "vg-lv"),
+            ("a-b", "c", "a--b-c"),
+            ("a", "b-", "a-b--"),
+            ("a.b", "c_d", "a.b-c_d"),
+            ("a+b", "c--d", "a+b-c----d"),
+        ];
+        for (vg, lv, expected) in cases {
+            assert_eq!(dm_name(vg, lv).unwrap(), expected, "{}/{}", vg, lv);
+        }
+        assert!(dm_name("a/b", "lv").is_err());
+        assert!(dm_name("vg", "..").is_err());
+        assert!(dm_name("vg", "").is_err());
+    }
+
+    #[test]
+    fn parse_versions() {
+        let rhel9 = "  LVM version:     2.03.14(2) (2021-10-20)
+  Library version: 1.02.181 (2021-10-20)
+  Driver version:  4.46.0
+";
+        assert_eq!(parse_version(rhel9), Some((2, 3, 14)));
+        assert!(parse_version(rhel9).unwrap() >= DEVICES_FILE_VERSION);
+        let rhel8 = "  LVM version:     2.03.09(2)-RHEL8 (2020-05-28)\n";
+        assert_eq!(parse_version(rhel8), Some((2, 3, 9)));
+        assert!(parse_version(rhel8).unwrap() < DEVICES_FILE_VERSION);
+        assert_eq!(parse_version("  Driver version:  4.46.0\n"), None);
+        assert_eq!(parse_version("  LVM version:     2.03\n"), None);
+    }
+}
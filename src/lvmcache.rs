@@ -0,0 +1,86 @@
+//! With `mode: cache`, an LVM cache (dm-cache or dm-writecache) on the
+//! instance storage in front of a persistent logical volume.
+
+use super::*;
+
+const CACHE_LV_NAME: &str = "ccisp-cache";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Settings {
+    /// The logical volume to cache, as `vg/lv`; the instance devices
+    /// are added to its volume group.
+    pub(crate) origin: String,
+    #[serde(default, rename = "type")]
+    kind: Kind,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    /// dm-cache in writethrough mode, so the origin is always complete.
+    #[default]
+    Cache,
+    /// dm-writecache; faster for writes, but the origin is incomplete
+    /// (and likely corrupt) if the instance storage is lost.
+    Writecache,
+}
+
+fn vg_of(origin: &str) -> Result<&str> {
+    match origin.split_once('/') {
+        Some((vg, lv)) if !vg.is_empty() && !lv.is_empty() => Ok(vg),
+        _ => bail!("Expected cache origin as vg/lv, not {:?}", origin),
+    }
+}
+
+/// Add `devs` to the origin's volume group, and attach them as cache.
+pub(crate) fn attach(settings: &Settings, devs: &[String]) -> Result<()> {
+    lvm::require(&[match settings.kind {
+        Kind::Cache => "cache",
+        Kind::Writecache => "writecache",
+    }])?;
+    let vg = vg_of(&settings.origin)?;
+    lvm::extend_vg(vg, devs)?;
+    Command::new("lvm")
+        .args([
+            "lvcreate",
+            "--yes",
+            "-n",
+            CACHE_LV_NAME,
+            "-l",
+            "100%PVS",
+            vg,
+        ])
+        .args(devs)
+        .run()?;
+    let mut c = Command::new("lvm");
+    c.args(["lvconvert", "--yes"]);
+    match settings.kind {
+        Kind::Cache => c.args(["--type", "cache", "--cachemode", "writethrough"]),
+        Kind::Writecache => c.args(["--type", "writecache"]),
+    };
+    c.args(["--cachevol", CACHE_LV_NAME])
+        .arg(&settings.origin)
+        .run()
+}
+
+/// Detach the cache from `origin`, drop the missing devices from
+/// its volume group, and forget the cache `devs`.  This is
+/// best-effort; errors are only logged.
+pub(crate) fn detach(origin: &str, devs: &[String]) {
+    let vg = match vg_of(origin) {
+        Ok(vg) => vg,
+        Err(e) => return eprintln!("warning: {:#}", e),
+    };
+    // The cache devices are likely gone, so nothing can be flushed.
+    let r = Command::new("lvm")
+        .args(["lvconvert", "--yes", "--uncache", "--force", origin])
+        .run()
+        .and_then(|_| lvm::reduce_missing(vg));
+    if let Err(e) = r {
+        eprintln!("warning: Detaching cache from {}: {:#}", origin, e);
+    }
+    for dev in devs {
+        lvm::forget_device(dev);
+    }
+}
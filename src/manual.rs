@@ -0,0 +1,109 @@
+//! Devices specified explicitly in the config; usable on any platform.
+
+use super::*;
+
+/// The platform name used when devices are configured explicitly.
+pub(crate) const PLATFORM: &str = "manual";
+
+/// Shell-style matching of `*` and `?`.
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Expand a pattern; wildcards are only supported in the final component.
+fn expand(pattern: &str) -> Result<Vec<std::path::PathBuf>> {
+    if !pattern.contains(['*', '?']) {
+        return Ok(vec![pattern.into()]);
+    }
+    let p = Path::new(pattern);
+    let (parent, filepat) = match (p.parent(), p.file_name().and_then(|f| f.to_str())) {
+        (Some(parent), Some(f)) if !parent.to_string_lossy().contains(['*', '?']) => (parent, f),
+        _ => bail!("Unsupported device pattern: {}", pattern),
+    };
+    let mut r = Vec::new();
+    for entry in std::fs::read_dir(parent).with_context(|| format!("Reading {:?}", parent))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if glob_match(filepat.as_bytes(), name.to_string_lossy().as_bytes()) {
+            r.push(entry.path());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Whether `path` matches any of `patterns`; those starting with `/`
+/// are resolved as for `devices`, the others are matched against
+/// the serial and model.
+fn matches_any(patterns: &[String], path: &str, blockdevs: &[block::Device]) -> Result<bool> {
+    let (paths, ids): (Vec<&String>, Vec<&String>) =
+        patterns.iter().partition(|p| p.starts_with('/'));
+    let paths: Vec<String> = paths.into_iter().cloned().collect();
+    if devices(&paths)?.iter().any(|p| p == path) {
+        return Ok(true);
+    }
+    let dev = match blockdevs.iter().find(|d| d.path() == path) {
+        Some(d) => d,
+        None => return Ok(false),
+    };
+    let fields = [dev.serial.as_deref(), dev.model.as_deref()];
+    Ok(ids.iter().any(|pat| {
+        fields
+            .iter()
+            .flatten()
+            .any(|f| glob_match(pat.as_bytes(), f.trim().as_bytes()))
+    }))
+}
+
+/// Apply `only-devices` and `exclude-devices` to the detected devices.
+pub(crate) fn filter(
+    devs: Vec<String>,
+    only: &[String],
+    exclude: &[String],
+) -> Result<Vec<String>> {
+    if only.is_empty() && exclude.is_empty() {
+        return Ok(devs);
+    }
+    let blockdevs = block::list()?;
+    let mut r = Vec::new();
+    for dev in devs {
+        if !only.is_empty() && !matches_any(only, &dev, &blockdevs)? {
+            println!("Skipping {}: not in only-devices", dev);
+        } else if matches_any(exclude, &dev, &blockdevs)? {
+            println!("Skipping {}: in exclude-devices", dev);
+        } else {
+            r.push(dev);
+        }
+    }
+    Ok(r)
+}
+
+/// Resolve the configured patterns to (deduplicated) device paths.
+pub(crate) fn devices(patterns: &[String]) -> Result<Vec<String>> {
+    let mut r: Vec<String> = Vec::new();
+    for pattern in patterns {
+        for path in expand(pattern)? {
+            let dev = match std::fs::canonicalize(&path) {
+                Ok(dev) => dev,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    println!("Configured device {:?} not found", path);
+                    continue;
+                }
+                Err(e) => return Err(e).with_context(|| format!("Resolving {:?}", path)),
+            };
+            let dev = dev.to_str().expect("utf8").to_string();
+            if !r.contains(&dev) {
+                r.push(dev);
+            }
+        }
+    }
+    Ok(r)
+}
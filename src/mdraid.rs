@@ -0,0 +1,119 @@
+use super::*;
+
+/// The array is named, so its device path is stable.
+const NAME: &str = "ccisp";
+pub(crate) const PATH: &str = "/dev/md/ccisp";
+const CONF_DIR: &str = "/etc/mdadm.conf.d";
+const CONF_NAME: &str = "ccisp.conf";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Raid {
+    /// 0, 1 or 10.
+    level: u32,
+}
+
+impl Raid {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if ![0, 1, 10].contains(&self.level) {
+            bail!("Unsupported RAID level: {}", self.level);
+        }
+        Ok(())
+    }
+}
+
+/// Create the array and record it in mdadm.conf, so it's assembled
+/// under the same name at boot.  The devices were just wiped, so
+/// there's nothing worth an initial resync.
+pub(crate) fn create(raid: &Raid, devices: &[String]) -> Result<()> {
+    Command::new("mdadm")
+        .args([
+            "--create",
+            PATH,
+            "--run",
+            "--assume-clean",
+            "--metadata=1.2",
+        ])
+        .arg(format!("--name={}", NAME))
+        .arg(format!("--level={}", raid.level))
+        .arg(format!("--raid-devices={}", devices.len()))
+        .args(devices)
+        .run()?;
+    let o = Command::new("mdadm")
+        .args(["--detail", "--brief", PATH])
+        .output()?;
+    if !o.status.success() {
+        bail!("Failed to query {}", PATH);
+    }
+    std::fs::create_dir_all(CONF_DIR)?;
+    openat::Dir::open(CONF_DIR)?.write_file_contents(CONF_NAME, 0o644, &o.stdout)?;
+    Command::new("udevadm").arg("settle").run()?;
+    Ok(())
+}
+
+pub(crate) fn exists() -> bool {
+    Path::new(PATH).exists()
+}
+
+fn sysfs_names(dev: &str, subdir: &str) -> Result<Vec<String>> {
+    let path = std::fs::canonicalize(dev).with_context(|| format!("Resolving {}", dev))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid device {:?}", path))?;
+    let dir = Path::new("/sys/class/block").join(name).join(subdir);
+    let mut r = Vec::new();
+    for e in std::fs::read_dir(&dir).with_context(|| format!("Reading {:?}", dir))? {
+        r.push(e?.file_name().to_string_lossy().into_owned());
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// The md array which consists of exactly `devices`, if any.
+pub(crate) fn existing_array(devices: &[String]) -> Result<Option<String>> {
+    let mut arrays = Vec::new();
+    for dev in devices {
+        let holders = sysfs_names(dev, "holders")?;
+        match holders.iter().find(|h| h.starts_with("md")) {
+            Some(md) => arrays.push(md.clone()),
+            None => return Ok(None),
+        }
+    }
+    arrays.sort_unstable();
+    arrays.dedup();
+    let md = match arrays.as_slice() {
+        [md] => format!("/dev/{}", md),
+        _ => {
+            println!("Instance devices are spread over md arrays {:?}", arrays);
+            return Ok(None);
+        }
+    };
+    let mut members: Vec<String> = devices
+        .iter()
+        .map(|d| Ok(std::fs::canonicalize(d)?.to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    members.sort();
+    let slaves: Vec<String> = sysfs_names(&md, "slaves")?
+        .into_iter()
+        .map(|n| format!("/dev/{}", n))
+        .collect();
+    if slaves != members {
+        bail!(
+            "{} consists of {:?}, not just the instance devices",
+            md,
+            slaves
+        );
+    }
+    Ok(Some(md))
+}
+
+/// Stop the array and forget about it.  This is best-effort; errors
+/// are only logged.
+pub(crate) fn stop() {
+    if exists() {
+        if let Err(e) = Command::new("mdadm").args(["--stop", PATH]).run() {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+    let _ = std::fs::remove_file(Path::new(CONF_DIR).join(CONF_NAME));
+}
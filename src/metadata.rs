@@ -0,0 +1,35 @@
+//! Cloud instance metadata services; these are queried on a best-effort
+//! basis since the network may not be available this early in boot.
+
+use super::*;
+
+/// How long we're willing to wait on a metadata service.
+const TIMEOUT_SECS: &str = "5";
+
+/// GET `url` with the given extra headers.
+pub(crate) fn fetch(url: &str, headers: &[&str]) -> Result<String> {
+    request("GET", url, headers)
+}
+
+/// Perform an HTTP request, returning the body.
+pub(crate) fn request(method: &str, url: &str, headers: &[&str]) -> Result<String> {
+    if selftest::active() {
+        bail!("No metadata service in self-test");
+    }
+    let mut c = Command::new("curl");
+    c.args(["--silent", "--show-error", "--fail", "--noproxy", "*"])
+        .args(["--max-time", TIMEOUT_SECS])
+        .args(["--request", method]);
+    for h in headers {
+        c.arg("-H").arg(h);
+    }
+    let o = c.arg(url).output()?;
+    if !o.status.success() {
+        bail!(
+            "Fetching {}: {}",
+            url,
+            String::from_utf8_lossy(&o.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(o.stdout)?)
+}
@@ -0,0 +1,53 @@
+//! Generic bare metal: any NVMe drive which isn't the root disk and
+//! is entirely unused.  Only used when explicitly configured, since on
+//! arbitrary hardware "unused" is not the same as "disposable".
+
+use super::*;
+
+pub(crate) const PLATFORM: &str = "metal";
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+const SWAPS_PATH: &str = "/proc/swaps";
+
+/// Block devices currently used as a mount source or swap.
+fn referenced_devices() -> Result<Vec<String>> {
+    if selftest::active() {
+        return Ok(Vec::new());
+    }
+    let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)
+        .with_context(|| format!("Reading {}", MOUNTINFO_PATH))?;
+    // The mount source follows the optional fields and filesystem type; see proc(5).
+    let mut r: Vec<String> = mountinfo
+        .lines()
+        .filter_map(|l| l.split(" - ").nth(1))
+        .filter_map(|l| l.split(' ').nth(1))
+        .filter(|src| src.starts_with("/dev/"))
+        .map(|src| src.to_string())
+        .collect();
+    if let Ok(swaps) = std::fs::read_to_string(SWAPS_PATH) {
+        r.extend(
+            swaps
+                .lines()
+                .skip(1)
+                .filter_map(|l| l.split_whitespace().next())
+                .map(|src| src.to_string()),
+        );
+    }
+    Ok(r)
+}
+
+/// The root disk is never unused (it has partitions, and possibly
+/// RAID/LUKS holders), but also explicitly exclude anything mounted
+/// directly, such as a whole-disk filesystem lsblk didn't identify.
+pub(crate) fn devices() -> Result<Vec<String>> {
+    let referenced = referenced_devices()?;
+    Ok(block::list()?
+        .into_iter()
+        .filter(|dev| dev.name.starts_with("nvme") && dev.is_unused())
+        .filter(|dev| {
+            let path = dev.path();
+            !referenced.iter().any(|r| r == &path)
+        })
+        .map(|dev| dev.path())
+        .collect())
+}
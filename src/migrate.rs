@@ -0,0 +1,23 @@
+//! With `migrate: true`, the existing content of a directory is copied
+//! onto the instance storage before it's replaced.
+
+use super::*;
+
+const MIGRATE_MOUNTPOINT: &str = "migrate";
+
+/// Copy the content of `src` into `dest`, preserving ownership, modes,
+/// timestamps, hardlinks, xattrs and SELinux labels.
+pub(crate) fn copy(src: &Path, dest: &Path) -> Result<()> {
+    println!("Migrating {:?} to {:?}", src, dest);
+    Command::new("cp")
+        .args(["-a", "--preserve=all", "--no-target-directory"])
+        .arg(src)
+        .arg(dest)
+        .run()
+        .with_context(|| format!("Migrating {:?}", src))
+}
+
+/// As `copy`, to the root of the filesystem on `what`.
+pub(crate) fn copy_to_root(src: &Path, what: &Path, options: &str) -> Result<()> {
+    with_mounted(MIGRATE_MOUNTPOINT, what, options, |mnt| copy(src, mnt))
+}
@@ -0,0 +1,49 @@
+//! With `oomd: true`, systemd-oomd policies consistent with what we set
+//! up: swap on instance storage means acting on swap usage, and tmpfs
+//! directories mean acting on memory pressure.
+
+use super::*;
+
+const DROPIN_NAME: &str = "ccisp-oomd.conf";
+const SERVICE_NAME: &str = "systemd-oomd.service";
+const DAEMON_PATH: &str = "/usr/lib/systemd/systemd-oomd";
+
+fn write_dropin(unit_dir: &str, unit: &str, directive: &str) -> Result<()> {
+    let dir = Path::new(unit_dir).join(format!("{}.d", unit));
+    std::fs::create_dir_all(&dir).with_context(|| format!("Creating {:?}", dir))?;
+    failure::record_unit(unit_dir, &format!("{}.d/{}", unit, DROPIN_NAME));
+    openat::Dir::open(&dir)?.write_file_contents(
+        DROPIN_NAME,
+        0o644,
+        format!(
+            "# Written by coreos-cloud-instance-store-provisioner\n[Slice]\n{}\n",
+            directive
+        ),
+    )?;
+    Ok(())
+}
+
+fn enable(install: systemd::Install) -> Result<()> {
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    if !Path::new(DAEMON_PATH).exists() {
+        eprintln!("warning: systemd-oomd is not installed; the policy has no effect");
+        return Ok(());
+    }
+    install.enable(SERVICE_NAME, true)
+}
+
+/// Kill based on swap usage, which is only monitored on the root slice.
+pub(crate) fn swap(install: systemd::Install) -> Result<()> {
+    write_dropin(install.unit_dir, "-.slice", "ManagedOOMSwap=kill")?;
+    enable(install)
+}
+
+/// Kill services under memory pressure, e.g. from filling a tmpfs.
+pub(crate) fn memory_pressure(install: systemd::Install) -> Result<()> {
+    write_dropin(
+        install.unit_dir,
+        "system.slice",
+        "ManagedOOMMemoryPressure=kill",
+    )?;
+    enable(install)
+}
@@ -0,0 +1,28 @@
+//! With `owner`, `group` and `permissions`, for non-root services using
+//! a redirected directory.
+
+use super::*;
+
+const OWNERSHIP_MOUNTPOINT: &str = "ownership";
+
+/// Give `path` the ownership and permissions configured for `dir`.
+pub(crate) fn apply(dir: &Directory, path: &Path) -> Result<()> {
+    let spec = match (dir.owner.as_deref(), dir.group.as_deref()) {
+        (Some(o), Some(g)) => Some(format!("{}:{}", o, g)),
+        (Some(o), None) => Some(o.to_string()),
+        (None, Some(g)) => Some(format!(":{}", g)),
+        (None, None) => None,
+    };
+    if let Some(spec) = spec {
+        Command::new("chown").arg(spec).arg(path).run()?;
+    }
+    if let Some(mode) = dir.permissions.as_deref() {
+        Command::new("chmod").arg(mode).arg(path).run()?;
+    }
+    Ok(())
+}
+
+/// As `apply`, to the root of the filesystem on `what`.
+pub(crate) fn apply_to_root(dir: &Directory, what: &Path, options: &str) -> Result<()> {
+    with_mounted(OWNERSHIP_MOUNTPOINT, what, options, |mnt| apply(dir, mnt))
+}
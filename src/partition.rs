@@ -0,0 +1,99 @@
+//! A GPT partition layout on a single instance device.
+
+use super::*;
+use std::io::Write as IoWrite;
+use std::process::Stdio;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Partition {
+    /// The partition label, which must be unique on the machine.
+    name: String,
+    /// E.g. `16G`; the last partition may omit it to take the rest.
+    size: Option<String>,
+    #[serde(rename = "use")]
+    usage: Usage,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Usage {
+    /// The instance storage filesystem; exactly one partition.
+    Filesystem,
+    Swap,
+    /// Left alone, for something else to use.
+    Raw,
+}
+
+pub(crate) fn validate(parts: &[Partition]) -> Result<()> {
+    for (i, p) in parts.iter().enumerate() {
+        if p.name.is_empty() || p.name.contains(['"', '/']) {
+            bail!("Invalid partition name: {:?}", p.name);
+        }
+        if parts[..i].iter().any(|q| q.name == p.name) {
+            bail!("Duplicate partition name: {}", p.name);
+        }
+        if p.size.is_none() && i + 1 != parts.len() {
+            bail!("Only the last partition may omit its size");
+        }
+    }
+    let filesystems = parts
+        .iter()
+        .filter(|p| p.usage == Usage::Filesystem)
+        .count();
+    if filesystems != 1 {
+        bail!("Expected exactly one partition with use: filesystem");
+    }
+    Ok(())
+}
+
+fn path(p: &Partition) -> String {
+    format!("/dev/disk/by-partlabel/{}", p.name)
+}
+
+/// The partition holding the filesystem.
+pub(crate) fn filesystem_path(parts: &[Partition]) -> String {
+    path(
+        parts
+            .iter()
+            .find(|p| p.usage == Usage::Filesystem)
+            .expect("validated"),
+    )
+}
+
+pub(crate) fn swap_paths(parts: &[Partition]) -> Vec<String> {
+    parts
+        .iter()
+        .filter(|p| p.usage == Usage::Swap)
+        .map(path)
+        .collect()
+}
+
+/// Write the partition table and set up swap partitions.
+pub(crate) fn create(dev: &str, parts: &[Partition]) -> Result<()> {
+    let mut script = String::from("label: gpt\n");
+    for p in parts {
+        if let Some(size) = p.size.as_deref() {
+            script.push_str(&format!("size={}, ", size));
+        }
+        script.push_str(&format!("name=\"{}\"\n", p.name));
+    }
+    let mut c = Command::new("sfdisk");
+    c.arg(dev).stdin(Stdio::piped());
+    failure::record_command(&c);
+    let mut child = c.spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(script.as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("Failed to partition {}", dev);
+    }
+    // For the by-partlabel links.
+    Command::new("udevadm").arg("settle").run()?;
+    for swap in swap_paths(parts) {
+        Command::new("mkswap").arg(swap).run()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Choosing devices by the PCI address of their controller, for bare
+//! metal where physical slots are dedicated to other software.
+
+use super::*;
+
+/// Whether `s` looks like a PCI address, `domain:bus:device.function`.
+fn is_address(s: &str) -> bool {
+    let parts: Vec<&str> = s.split([':', '.']).collect();
+    parts.len() == 4
+        && [4, 2, 2, 1]
+            .iter()
+            .zip(parts.iter())
+            .all(|(len, p)| p.len() == *len && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The PCI address of the device behind `dev`: the last one in its
+/// sysfs path, e.g. `0000:3b:00.0` in
+/// `/sys/devices/pci0000:3a/0000:3a:00.0/0000:3b:00.0/nvme/nvme0/nvme0n1`.
+fn address(dev: &str) -> Result<Option<String>> {
+    let name = Path::new(dev)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Expected filename in {:?}", dev))?;
+    let path = std::fs::canonicalize(Path::new("/sys/block").join(name))
+        .with_context(|| format!("Resolving sysfs path of {}", dev))?;
+    Ok(path
+        .iter()
+        .rev()
+        .filter_map(|c| c.to_str())
+        .find(|c| is_address(c))
+        .map(|c| c.to_string()))
+}
+
+fn matches_any(patterns: &[String], address: &str) -> bool {
+    patterns
+        .iter()
+        .any(|p| manual::glob_match(p.to_ascii_lowercase().as_bytes(), address.as_bytes()))
+}
+
+/// Apply `pci-allowlist` and `pci-denylist` to the detected devices;
+/// devices without a PCI address only pass without an allowlist.
+pub(crate) fn filter(devs: Vec<String>, allow: &[String], deny: &[String]) -> Result<Vec<String>> {
+    if allow.is_empty() && deny.is_empty() {
+        return Ok(devs);
+    }
+    let mut r = Vec::new();
+    for dev in devs {
+        match address(&dev)? {
+            None if !allow.is_empty() => {
+                println!("Skipping {}: no PCI address, with pci-allowlist", dev);
+            }
+            Some(a) if !allow.is_empty() && !matches_any(allow, &a) => {
+                println!("Skipping {}: {} not in pci-allowlist", dev, a);
+            }
+            Some(a) if matches_any(deny, &a) => {
+                println!("Skipping {}: {} in pci-denylist", dev, a);
+            }
+            _ => r.push(dev),
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        for s in ["0000:3b:00.0", "0000:3B:00.7", "10de:ff:1f.f"] {
+            assert!(is_address(s), "{:?}", s);
+        }
+        for s in [
+            "3b:00.0",
+            "0000:3b:00.00",
+            "0000:3g:00.0",
+            "0000:3b:00",
+            "nvme0",
+        ] {
+            assert!(!is_address(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn address_patterns() {
+        let patterns = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(matches_any(&patterns(&["0000:3b:00.0"]), "0000:3b:00.0"));
+        assert!(matches_any(
+            &patterns(&["0000:5e:*", "0000:3b:*"]),
+            "0000:3b:00.0"
+        ));
+        // Patterns are case insensitive; sysfs addresses are lowercase.
+        assert!(matches_any(&patterns(&["0000:3B:00.0"]), "0000:3b:00.0"));
+        assert!(!matches_any(&patterns(&["0000:5e:00.0"]), "0000:3b:00.0"));
+        assert!(!matches_any(&[], "0000:3b:00.0"));
+    }
+}
@@ -0,0 +1,406 @@
+//! The layout of the instance storage, computed from the configuration
+//! and the discovered devices alone, without side effects.
+
+use super::*;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    Export,
+    Swap,
+    /// For a directory with `backing: lv`.
+    Directory,
+    /// With `thin-pool`, what the directories and the shared
+    /// filesystem are carved from.
+    Pool,
+    /// The shared filesystem.
+    Main,
+}
+
+#[derive(Debug)]
+pub(crate) struct Volume {
+    pub(crate) name: String,
+    /// As for `lvm::create_lv`; `None` is all remaining space.
+    pub(crate) size: Option<String>,
+    pub(crate) purpose: Purpose,
+    /// A thin volume in the pool, with `size` being its virtual size.
+    pub(crate) thin: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Plan<'a> {
+    /// If set, the instance devices are first combined into an md array.
+    pub(crate) raid: Option<&'a mdraid::Raid>,
+    /// What the volume group (or the filesystem directly) goes on.
+    pub(crate) storage_devs: Vec<String>,
+    pub(crate) vg: Option<String>,
+    /// Logical volumes, in order of creation; the main one comes last,
+    /// so that it gets the remaining space.
+    pub(crate) volumes: Vec<Volume>,
+    /// The device holding the shared filesystem.
+    pub(crate) main_dev: String,
+}
+
+/// Plan the layout on the instance `devices` found on `platform`.
+pub(crate) fn compute<'a>(
+    platform: &str,
+    devices: &[block::Device],
+    config: &'a Config,
+    directories: &[Directory],
+    adopted: Option<&str>,
+    vg_name: &str,
+    lv_name: &str,
+) -> Result<Plan<'a>> {
+    if devices.is_empty() {
+        bail!("No instance devices to plan for on {}", platform);
+    }
+    let instance_devs: Vec<String> = devices.iter().map(|d| d.path()).collect();
+    let mut sorted: Vec<&str> = instance_devs.iter().map(|d| d.as_str()).collect();
+    sorted.sort_unstable();
+    if let Some(w) = sorted.windows(2).find(|w| w[0] == w[1]) {
+        bail!("Device {} found more than once on {}", w[0], platform);
+    }
+    // With mdraid, the array takes the place of the devices from here on;
+    // there's nothing to combine with a single device.
+    let raid = config
+        .raid
+        .as_ref()
+        .filter(|_| adopted.is_none() && instance_devs.len() > 1);
+    let storage_devs = match (adopted, raid) {
+        (Some(md), _) => vec![md.to_string()],
+        (None, Some(_)) => vec![mdraid::PATH.to_string()],
+        (None, None) => instance_devs,
+    };
+    let lv_dirs: Vec<&Directory> = directories
+        .iter()
+        .filter(|d| d.backing == Backing::Lv)
+        .collect();
+    // If there's just one block device, we use it directly; if there are
+    // more than one, we default to creating a striped LVM volume across them.
+    // Exports, swap and per-directory volumes need LVM in any case.
+    let swap_volume = config
+        .swap
+        .as_ref()
+        .filter(|s| s.kind == swap::Kind::Volume);
+    let use_lvm = storage_devs.len() > 1
+        || config.thin_pool
+        || !config.exports.is_empty()
+        || swap_volume.is_some()
+        || !lv_dirs.is_empty();
+    if !config.partitions.is_empty() {
+        if use_lvm || raid.is_some() {
+            bail!("partitions require a single device, without LVM");
+        }
+        return Ok(Plan {
+            raid,
+            main_dev: partition::filesystem_path(&config.partitions),
+            storage_devs,
+            vg: None,
+            volumes: Vec::new(),
+        });
+    }
+    if !use_lvm {
+        return Ok(Plan {
+            raid,
+            main_dev: storage_devs[0].clone(),
+            storage_devs,
+            vg: None,
+            volumes: Vec::new(),
+        });
+    }
+    // Exports are carved out first; the rest is ours.
+    let mut volumes: Vec<Volume> = config
+        .exports
+        .iter()
+        .map(|e| Volume {
+            name: e.name.clone(),
+            size: Some(e.size.clone()),
+            purpose: Purpose::Export,
+            thin: false,
+        })
+        .collect();
+    if let Some(swap) = swap_volume {
+        volumes.push(Volume {
+            name: swap::LV_NAME.to_string(),
+            size: Some(swap.size.clone()),
+            purpose: Purpose::Swap,
+            thin: false,
+        });
+    }
+    let thin = config.thin_pool;
+    if thin {
+        volumes.push(Volume {
+            name: lvm::POOL_NAME.to_string(),
+            size: None,
+            purpose: Purpose::Pool,
+            thin: false,
+        });
+    }
+    for dir in lv_dirs {
+        volumes.push(Volume {
+            name: dir.lv_name()?,
+            size: Some(dir.size()?.to_string()),
+            purpose: Purpose::Directory,
+            thin,
+        });
+    }
+    volumes.push(Volume {
+        name: lv_name.to_string(),
+        size: None,
+        purpose: Purpose::Main,
+        thin,
+    });
+    let mut names: Vec<&str> = volumes.iter().map(|v| v.name.as_str()).collect();
+    names.sort_unstable();
+    if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+        bail!("Multiple logical volumes would be named {}", w[0]);
+    }
+    Ok(Plan {
+        raid,
+        storage_devs,
+        vg: Some(vg_name.to_string()),
+        main_dev: format!("/dev/{}/{}", vg_name, lv_name),
+        volumes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn devices(paths: &[&str]) -> Vec<block::Device> {
+        paths
+            .iter()
+            .map(|p| block::Device {
+                name: p.trim_start_matches("/dev/").to_string(),
+                serial: None,
+                wwn: None,
+                vendor: None,
+                model: None,
+                label: None,
+                partlabel: None,
+                fstype: None,
+                log_sec: None,
+                phy_sec: None,
+                children: None,
+            })
+            .collect()
+    }
+
+    struct Case {
+        config: &'static str,
+        directories: &'static str,
+        devs: &'static [&'static str],
+        adopted: Option<&'static str>,
+        raid: bool,
+        storage_devs: &'static [&'static str],
+        vg: Option<&'static str>,
+        main_dev: &'static str,
+        volumes: &'static [(&'static str, Option<&'static str>, Purpose, bool)],
+    }
+
+    const TWO: &[&str] = &["/dev/nvme1n1", "/dev/nvme2n1"];
+
+    #[test]
+    fn compute() {
+        let cases = [
+            // A single device is used directly.
+            Case {
+                config: "{}",
+                directories: "[/var/lib/containers]",
+                devs: &["/dev/nvme1n1"],
+                adopted: None,
+                raid: false,
+                storage_devs: &["/dev/nvme1n1"],
+                vg: None,
+                main_dev: "/dev/nvme1n1",
+                volumes: &[],
+            },
+            // Multiple devices are striped with LVM.
+            Case {
+                config: "{}",
+                directories: "[/var/lib/containers]",
+                devs: TWO,
+                adopted: None,
+                raid: false,
+                storage_devs: TWO,
+                vg: Some("vg"),
+                main_dev: "/dev/vg/lv",
+                volumes: &[("lv", None, Purpose::Main, false)],
+            },
+            // Or combined with mdraid.
+            Case {
+                config: "raid: {level: 0}",
+                directories: "[/var/lib/containers]",
+                devs: TWO,
+                adopted: None,
+                raid: true,
+                storage_devs: &[mdraid::PATH],
+                vg: None,
+                main_dev: mdraid::PATH,
+                volumes: &[],
+            },
+            // A directory with its own logical volume needs LVM even
+            // on a single device.
+            Case {
+                config: "{}",
+                directories: "[{path: /var/log, backing: lv, size: 10G}, /var/lib/containers]",
+                devs: &["/dev/nvme1n1"],
+                adopted: None,
+                raid: false,
+                storage_devs: &["/dev/nvme1n1"],
+                vg: Some("vg"),
+                main_dev: "/dev/vg/lv",
+                volumes: &[
+                    ("dir-log", Some("10G"), Purpose::Directory, false),
+                    ("lv", None, Purpose::Main, false),
+                ],
+            },
+            Case {
+                config: "thin-pool: true",
+                directories: "[{path: /var/log, backing: lv, size: 10G}]",
+                devs: TWO,
+                adopted: None,
+                raid: false,
+                storage_devs: TWO,
+                vg: Some("vg"),
+                main_dev: "/dev/vg/lv",
+                volumes: &[
+                    (lvm::POOL_NAME, None, Purpose::Pool, false),
+                    ("dir-log", Some("10G"), Purpose::Directory, true),
+                    ("lv", None, Purpose::Main, true),
+                ],
+            },
+            // An adopted array replaces the devices, and isn't
+            // created again.
+            Case {
+                config: "raid: {level: 0}",
+                directories: "[/var/lib/containers]",
+                devs: TWO,
+                adopted: Some("/dev/md127"),
+                raid: false,
+                storage_devs: &["/dev/md127"],
+                vg: None,
+                main_dev: "/dev/md127",
+                volumes: &[],
+            },
+        ];
+        for case in cases {
+            let config: Config = serde_yaml::from_str(case.config).unwrap();
+            let directories: Vec<Directory> =
+                serde_yaml::from_str::<Vec<DirectoryEntry>>(case.directories)
+                    .unwrap()
+                    .into_iter()
+                    .map(Directory::from)
+                    .collect();
+            let devs = devices(case.devs);
+            let plan = super::compute(
+                "aws",
+                &devs,
+                &config,
+                &directories,
+                case.adopted,
+                "vg",
+                "lv",
+            )
+            .unwrap();
+            let what = (case.config, case.directories, case.devs);
+            assert_eq!(plan.raid.is_some(), case.raid, "{:?}", what);
+            assert_eq!(plan.storage_devs, case.storage_devs, "{:?}", what);
+            assert_eq!(plan.vg.as_deref(), case.vg, "{:?}", what);
+            assert_eq!(plan.main_dev, case.main_dev, "{:?}", what);
+            let volumes: Vec<_> = plan
+                .volumes
+                .iter()
+                .map(|v| (v.name.as_str(), v.size.as_deref(), &v.purpose, v.thin))
+                .collect();
+            let expected: Vec<_> = case
+                .volumes
+                .iter()
+                .map(|(name, size, purpose, thin)| (*name, *size, purpose, *thin))
+                .collect();
+            assert_eq!(volumes, expected, "{:?}", what);
+        }
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let config: Config = serde_yaml::from_str("{}").unwrap();
+        let directories = vec![Directory {
+            path: "/var/lv".to_string(),
+            backing: Backing::Lv,
+            size: Some("10G".to_string()),
+            ..Default::default()
+        }];
+        let devs = devices(&["/dev/nvme1n1"]);
+        assert!(super::compute("aws", &devs, &config, &directories, None, "vg", "dir-lv").is_err());
+    }
+
+    const CONFIGS: &[&str] = &[
+        "{}",
+        "raid: {level: 0}",
+        "thin-pool: true",
+        "swap: {type: volume, size: 1G}",
+        "exports: [{name: scratch, size: 1G}]",
+    ];
+    const DIRECTORIES: &[&str] = &[
+        "[/var/lib/containers]",
+        "[{path: /var/log, backing: lv, size: 10G}, /var/lib/containers]",
+    ];
+
+    proptest! {
+        /// Whatever the configuration and devices, no device is used
+        /// twice, and only devices from the inventory are used (besides
+        /// an md array made of them).
+        #[test]
+        fn devices_from_inventory(
+            n_inventory in 1usize..6,
+            picks in proptest::collection::vec(0usize..6, 0..6),
+            config in 0..CONFIGS.len(),
+            directories in 0..DIRECTORIES.len(),
+            adopt in any::<bool>(),
+        ) {
+            let names: Vec<String> = (0..n_inventory).map(|i| format!("/dev/nvme{}n1", i)).collect();
+            let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+            let inventory = devices(&names);
+            let picked: Vec<block::Device> = picks
+                .iter()
+                .filter_map(|&i| inventory.get(i).cloned())
+                .collect();
+            let config: Config = serde_yaml::from_str(CONFIGS[config]).unwrap();
+            let directories: Vec<Directory> =
+                serde_yaml::from_str::<Vec<DirectoryEntry>>(DIRECTORIES[directories])
+                    .unwrap()
+                    .into_iter()
+                    .map(Directory::from)
+                    .collect();
+            let adopted = Some("/dev/md127").filter(|_| adopt);
+            let plan = match super::compute(
+                "aws",
+                &picked,
+                &config,
+                &directories,
+                adopted,
+                "vg",
+                "lv",
+            ) {
+                Ok(plan) => plan,
+                Err(_) => return Ok(()),
+            };
+            let mut used = plan.storage_devs.clone();
+            used.sort();
+            used.dedup();
+            prop_assert_eq!(used.len(), plan.storage_devs.len());
+            for dev in plan.storage_devs.iter() {
+                let in_inventory = inventory.iter().any(|d| &d.path() == dev);
+                let md = (plan.raid.is_some() && dev == mdraid::PATH) || Some(dev.as_str()) == adopted;
+                prop_assert!(in_inventory || md, "{} isn't in the inventory", dev);
+            }
+            if plan.raid.is_none() && adopted.is_none() {
+                let mut picked: Vec<String> = picked.iter().map(|d| d.path()).collect();
+                picked.sort();
+                prop_assert_eq!(used, picked);
+            }
+        }
+    }
+}
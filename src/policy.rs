@@ -0,0 +1,217 @@
+//! Decides what to do with each configured directory, based on a table
+//! of rules evaluated against facts about its current state.  The first
+//! matching rule wins; rules from the config file are evaluated before
+//! the built-in ones.
+
+use super::*;
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Action {
+    /// Remove the existing directory and bind mount instance storage over it.
+    Replace,
+    /// Leave the directory as is, on the root filesystem.
+    Skip,
+    /// Fail provisioning.
+    Fail,
+}
+
+/// Conditions on `Facts`; unset conditions always match.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Conditions {
+    exists: Option<bool>,
+    mounted: Option<bool>,
+    has_content: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Rule {
+    #[serde(default)]
+    when: Conditions,
+    action: Action,
+}
+
+/// What we know about a directory before touching it.
+#[derive(Debug)]
+pub(crate) struct Facts {
+    exists: bool,
+    mounted: bool,
+    has_content: bool,
+}
+
+fn is_mountpoint(path: &Path) -> Result<bool> {
+    let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)
+        .with_context(|| format!("Reading {}", MOUNTINFO_PATH))?;
+    let path = path.to_str().expect("utf8").trim_end_matches('/');
+    // The fifth field is the mount point; see proc(5).
+    Ok(mountinfo
+        .lines()
+        .filter_map(|l| l.split(' ').nth(4))
+        .any(|mnt| mnt == path))
+}
+
+impl Facts {
+    pub(crate) fn gather(path: &Path) -> Result<Self> {
+        let exists = path.exists();
+        let mounted = exists && is_mountpoint(path)?;
+        let has_content = exists
+            && path.is_dir()
+            && std::fs::read_dir(path)
+                .with_context(|| format!("Reading {:?}", path))?
+                .next()
+                .is_some();
+        Ok(Self {
+            exists,
+            mounted,
+            has_content,
+        })
+    }
+}
+
+impl Conditions {
+    /// Check each condition, describing the result of each in `trace`.
+    fn matches(&self, facts: &Facts, trace: &mut Vec<String>) -> bool {
+        let checks = [
+            ("exists", self.exists, facts.exists),
+            ("mounted", self.mounted, facts.mounted),
+            ("has-content", self.has_content, facts.has_content),
+        ];
+        let mut r = true;
+        for (name, want, have) in checks.iter() {
+            if let Some(want) = want {
+                let ok = want == have;
+                trace.push(format!(
+                    "  {}={} (want {}): {}",
+                    name,
+                    have,
+                    want,
+                    if ok { "ok" } else { "no match" }
+                ));
+                r &= ok;
+            }
+        }
+        r
+    }
+}
+
+/// The outcome of evaluating the rules for a directory.
+#[derive(Debug)]
+pub(crate) struct Decision {
+    pub(crate) action: Action,
+    /// Whether to give the new directory its policy-defined SELinux label.
+    pub(crate) label: bool,
+    /// A human readable description of how the decision was made.
+    pub(crate) trace: Vec<String>,
+}
+
+fn builtin_rules() -> Vec<Rule> {
+    vec![
+        // Removing the contents of some other mount would be very surprising.
+        Rule {
+            when: Conditions {
+                mounted: Some(true),
+                ..Default::default()
+            },
+            action: Action::Skip,
+        },
+        Rule {
+            when: Conditions::default(),
+            action: Action::Replace,
+        },
+    ]
+}
+
+pub(crate) fn evaluate(configured: &[Rule], facts: &Facts, selinux: bool) -> Decision {
+    let mut trace = vec![format!("facts: {:?}", facts)];
+    let builtin = builtin_rules();
+    let rules = configured
+        .iter()
+        .map(|r| ("config", r))
+        .chain(builtin.iter().map(|r| ("builtin", r)));
+    let mut action = Action::Replace;
+    for (i, (source, rule)) in rules.enumerate() {
+        trace.push(format!("rule {} ({}): {:?}", i, source, rule.action));
+        if rule.when.matches(facts, &mut trace) {
+            trace.push(format!("  => {:?}", rule.action));
+            action = rule.action;
+            break;
+        }
+    }
+    let label = action == Action::Replace && selinux;
+    if label {
+        trace.push("SELinux: labeling as defined by the policy".to_string());
+    }
+    Decision {
+        action,
+        label,
+        trace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(exists: bool, mounted: bool, has_content: bool) -> Facts {
+        Facts {
+            exists,
+            mounted,
+            has_content,
+        }
+    }
+
+    fn rules(yaml: &str) -> Vec<Rule> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn builtin() {
+        let d = evaluate(&[], &facts(true, true, true), false);
+        assert_eq!(d.action, Action::Skip);
+        let d = evaluate(&[], &facts(true, false, true), false);
+        assert_eq!(d.action, Action::Replace);
+        let d = evaluate(&[], &facts(false, false, false), false);
+        assert_eq!(d.action, Action::Replace);
+    }
+
+    #[test]
+    fn configured() {
+        let configured = rules(
+            "
+- when:
+    has-content: true
+  action: fail
+- when:
+    exists: false
+  action: skip
+",
+        );
+        let d = evaluate(&configured, &facts(true, false, true), false);
+        assert_eq!(d.action, Action::Fail);
+        let d = evaluate(&configured, &facts(false, false, false), false);
+        assert_eq!(d.action, Action::Skip);
+        // Falls through to the built-in rules.
+        let d = evaluate(&configured, &facts(true, false, false), false);
+        assert_eq!(d.action, Action::Replace);
+        // Configured rules come first, even for mounts.
+        let d = evaluate(&configured, &facts(true, true, true), false);
+        assert_eq!(d.action, Action::Fail);
+    }
+
+    #[test]
+    fn selinux_label() {
+        let d = evaluate(&[], &facts(true, false, true), true);
+        assert_eq!(d.action, Action::Replace);
+        assert!(d.label);
+        let d = evaluate(&[], &facts(true, false, true), false);
+        assert!(!d.label);
+        // Only directories we replace are labeled.
+        let d = evaluate(&[], &facts(true, true, true), true);
+        assert_eq!(d.action, Action::Skip);
+        assert!(!d.label);
+    }
+}
@@ -0,0 +1,82 @@
+//! Checks on the filesystem we're about to create directories on.
+//! `create_dir` failing with EROFS or ENOSPC gives little clue as to
+//! why, e.g. a read-only overlay or a full ostree deployment.
+
+use super::*;
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+/// We only create empty directories, so this is generous.
+const MIN_FREE_BYTES: u64 = 1024 * 1024;
+const MIN_FREE_INODES: u64 = 64;
+
+/// The mount containing `path`, as (mount point, type, source).
+fn mount_of(path: &Path) -> Result<(String, String, String)> {
+    let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)
+        .with_context(|| format!("Reading {}", MOUNTINFO_PATH))?;
+    let path = path.to_str().expect("utf8");
+    mountinfo
+        .lines()
+        .filter_map(|l| {
+            let (pre, post) = l.split_once(" - ")?;
+            let mnt = pre.split(' ').nth(4)?;
+            let mut post = post.split(' ');
+            let fstype = post.next()?;
+            let source = post.next()?;
+            Some((mnt, fstype, source))
+        })
+        .filter(|(mnt, _, _)| {
+            *mnt == "/"
+                || path == *mnt
+                || path
+                    .strip_prefix(mnt)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        // Later entries shadow earlier ones at the same mount point.
+        .max_by_key(|(mnt, _, _)| mnt.len())
+        .map(|(m, t, s)| (m.to_string(), t.to_string(), s.to_string()))
+        .ok_or_else(|| anyhow!("No mount found for {}", path))
+}
+
+/// Verify that we can (re)create `path`: its closest existing parent
+/// must be on a writable filesystem with some free space and inodes.
+pub(crate) fn check_creatable(path: &Path) -> Result<()> {
+    let parent = path
+        .ancestors()
+        .skip(1)
+        .find(|p| p.exists())
+        .ok_or_else(|| anyhow!("No existing parent for {:?}", path))?;
+    let cpath = std::ffi::CString::new(parent.to_str().expect("utf8"))?;
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut buf) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("statvfs({:?})", parent));
+    }
+    let (mnt, fstype, source) = mount_of(parent)?;
+    let describe = || format!("{:?} is on {} ({}, from {})", parent, mnt, fstype, source);
+    if buf.f_flag & libc::ST_RDONLY != 0 {
+        bail!(
+            "Cannot create {:?}: {}, which is read-only",
+            path,
+            describe()
+        );
+    }
+    let free = buf.f_bavail as u64 * buf.f_frsize as u64;
+    if free < MIN_FREE_BYTES {
+        bail!(
+            "Cannot create {:?}: {}, which has only {} bytes free",
+            path,
+            describe(),
+            free
+        );
+    }
+    // Some filesystems (e.g. btrfs) report no inode limits at all.
+    if buf.f_files > 0 && (buf.f_favail as u64) < MIN_FREE_INODES {
+        bail!(
+            "Cannot create {:?}: {}, which has only {} inodes free",
+            path,
+            describe(),
+            buf.f_favail
+        );
+    }
+    Ok(())
+}
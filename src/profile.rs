@@ -0,0 +1,122 @@
+//! Paths and conventions which differ between the operating systems
+//! we may be running on.  CoreOS is the primary target, but the tool
+//! is also usable on traditional and other rpm-ostree based hosts.
+
+use super::*;
+
+const OS_RELEASE_PATHS: &[&str] = &["/etc/os-release", "/usr/lib/os-release"];
+/// Created by ostree-prepare-root on all ostree-based systems.
+const OSTREE_BOOTED: &str = "/run/ostree-booted";
+
+#[derive(Debug)]
+pub(crate) struct Profile {
+    pub(crate) name: &'static str,
+    /// Where we write generated systemd units.
+    pub(crate) unit_dir: &'static str,
+    /// Where the instance storage filesystem is mounted.
+    pub(crate) mountpoint: &'static str,
+    /// Used if the config file doesn't specify `directories`.
+    pub(crate) default_directories: &'static [&'static str],
+    /// Whether we need to care about SELinux labeling.
+    pub(crate) selinux: bool,
+}
+
+/// Fedora CoreOS and RHEL CoreOS.
+pub(crate) const COREOS: Profile = Profile {
+    name: "coreos",
+    unit_dir: "/etc/systemd/system",
+    mountpoint: "/var/mnt/instance-storage",
+    default_directories: &["/var/lib/containers"],
+    selinux: true,
+};
+
+/// Other rpm-ostree systems, e.g. Fedora IoT; here `/mnt` is
+/// a symlink into `/var` too.
+pub(crate) const OSTREE: Profile = Profile {
+    name: "ostree",
+    unit_dir: "/etc/systemd/system",
+    mountpoint: "/var/mnt/instance-storage",
+    default_directories: &["/var/lib/containers"],
+    selinux: true,
+};
+
+/// Package-based Fedora, RHEL and derivatives.
+pub(crate) const FEDORA: Profile = Profile {
+    name: "fedora",
+    unit_dir: "/etc/systemd/system",
+    mountpoint: "/mnt/instance-storage",
+    default_directories: &["/var/lib/containers"],
+    selinux: true,
+};
+
+/// Anything else; we assume no SELinux.
+pub(crate) const GENERIC: Profile = Profile {
+    name: "generic",
+    unit_dir: "/etc/systemd/system",
+    mountpoint: "/mnt/instance-storage",
+    default_directories: &["/var/lib/containers"],
+    selinux: false,
+};
+
+/// Parse a single key from os-release(5) content.
+fn os_release_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        if k.trim() != key {
+            return None;
+        }
+        Some(v.trim().trim_matches(|c| c == '"' || c == '\''))
+    })
+}
+
+fn from_os_release(content: &str, ostree: bool) -> &'static Profile {
+    let id = os_release_value(content, "ID").unwrap_or("linux");
+    let variant = os_release_value(content, "VARIANT_ID");
+    let id_like = os_release_value(content, "ID_LIKE").unwrap_or("");
+    if id == "rhcos" || variant == Some("coreos") {
+        &COREOS
+    } else if ostree {
+        &OSTREE
+    } else if ["fedora", "rhel", "centos"].contains(&id)
+        || id_like
+            .split_whitespace()
+            .any(|l| l == "fedora" || l == "rhel")
+    {
+        &FEDORA
+    } else {
+        &GENERIC
+    }
+}
+
+/// Determine the profile for the running system.
+pub(crate) fn detect() -> Result<&'static Profile> {
+    let ostree = Path::new(OSTREE_BOOTED).exists();
+    for path in OS_RELEASE_PATHS {
+        match std::fs::read_to_string(path) {
+            Ok(content) => return Ok(from_os_release(&content, ostree)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path)),
+        }
+    }
+    Ok(&GENERIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release_values() {
+        let content = "NAME=\"Fedora Linux\"
+ID=fedora
+VARIANT_ID=coreos
+ID_LIKE='rhel centos'
+IDX=other
+";
+        assert_eq!(os_release_value(content, "NAME"), Some("Fedora Linux"));
+        assert_eq!(os_release_value(content, "ID"), Some("fedora"));
+        assert_eq!(os_release_value(content, "VARIANT_ID"), Some("coreos"));
+        assert_eq!(os_release_value(content, "ID_LIKE"), Some("rhel centos"));
+        assert_eq!(os_release_value(content, "VERSION_ID"), None);
+    }
+}
@@ -0,0 +1,977 @@
+//! Setting up the instance storage.
+
+use super::*;
+use structopt::StructOpt;
+
+#[derive(Debug, Default, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub(crate) struct ProvisionOpts {
+    /// Use this platform ID instead of the one from the kernel command line
+    #[structopt(long)]
+    platform: Option<String>,
+    /// Wait up to this many seconds for the instance devices to appear
+    #[structopt(long, value_name = "SECONDS")]
+    wait_for_device: Option<u64>,
+    /// Reprovision if the config file changed since the storage was set up
+    #[structopt(long)]
+    reconfigure: bool,
+}
+
+/// Find the instance-local devices for the given platform; returns
+/// `None` if the platform isn't supported.
+pub(crate) fn discover(platform: &str, config: &Config) -> Result<Option<Vec<String>>> {
+    let devs = match platform {
+        manual::PLATFORM => manual::devices(&config.devices)?,
+        metal::PLATFORM => metal::devices()?,
+        "aws" => aws::devices()?,
+        "azure" => azure::devices(config.azure_disks)?,
+        "digitalocean" => digitalocean::devices()?,
+        "equinixmetal" | "packet" => equinix::devices()?,
+        "hetzner" => hetzner::devices()?,
+        "kubevirt" => kubevirt::devices(config)?,
+        "qemu" => qemu::devices()?,
+        "scaleway" => scaleway::devices()?,
+        "tencentcloud" => tencentcloud::devices()?,
+        "vmware" => vmware::devices(config)?,
+        "vultr" => vultr::devices()?,
+        _ => return Ok(None),
+    };
+    Ok(Some(devs))
+}
+
+/// The platform given on the command line or in the config, else `manual`
+/// if devices are listed, else the one from the kernel command line.
+pub(crate) fn platform(requested: Option<&str>, config: &Config) -> Result<String> {
+    Ok(if let Some(p) = requested.or(config.platform.as_deref()) {
+        p.to_string()
+    } else if !config.devices.is_empty() {
+        manual::PLATFORM.to_string()
+    } else {
+        coreos::get_platform()?
+    })
+}
+
+/// Apply the configured device filters, and leave out devices Ignition set up.
+pub(crate) fn filter(devs: Vec<String>, config: &Config) -> Result<Vec<String>> {
+    let devs = manual::filter(devs, &config.only_devices, &config.exclude_devices)?;
+    let devs = pci::filter(devs, &config.pci_allowlist, &config.pci_denylist)?;
+    ignition::filter(devs)
+}
+
+/// Keep directories in order of priority as long as the sum of their
+/// `min-size` fits into `capacity` bytes; the rest are skipped.
+fn select_by_capacity(directories: Vec<Directory>, capacity: u64) -> Result<Vec<Directory>> {
+    let mut committed: u64 = 0;
+    let mut r = Vec::new();
+    for dir in directories {
+        let min_size = dir.min_size.as_deref().map(parse_size).transpose()?;
+        let needed = min_size.unwrap_or_default();
+        // A sum that overflows certainly doesn't fit.
+        if committed.checked_add(needed).is_none_or(|n| n > capacity) {
+            println!(
+                "Skipping {}: needs {} bytes, but only {} of {} are left",
+                dir.path,
+                needed,
+                capacity - committed,
+                capacity
+            );
+            continue;
+        }
+        committed += needed;
+        r.push(dir);
+    }
+    Ok(r)
+}
+
+/// The directories to redirect: those configured (or the profile's
+/// defaults), plus those implied by `var-tmp` and `ci`.  Returns `None` if
+/// provisioning is disabled by an empty list.
+pub(crate) fn directories(
+    config: &mut Config,
+    profile: &profile::Profile,
+) -> Result<Option<Vec<Directory>>> {
+    let mut directories: Vec<Directory> = match config.directories.take() {
+        Some(d) if d.is_empty() && config.allow_empty_directories => return Ok(None),
+        Some(d) if d.is_empty() => bail!(
+            "Specified directories list is empty; set allow-empty-directories to disable provisioning"
+        ),
+        Some(d) => d.into_iter().map(Directory::from).collect(),
+        None => profile
+            .default_directories
+            .iter()
+            .map(|s| DirectoryEntry::Path(s.to_string()).into())
+            .collect(),
+    };
+    if config.var_tmp && !directories.iter().any(|d| d.path == vartmp::PATH) {
+        directories.push(DirectoryEntry::Path(vartmp::PATH.to_string()).into());
+    }
+    if let Some(ci) = config.ci.as_ref() {
+        let quotas = config.project_quota || config.filesystem == Filesystem::Btrfs;
+        for dir in ci.directories(quotas) {
+            if !directories.iter().any(|d| d.path == dir.path) {
+                directories.push(dir);
+            }
+        }
+    }
+    Ok(Some(directories))
+}
+
+pub(crate) fn run(opts: &ProvisionOpts) -> Result<()> {
+    let started = std::time::Instant::now();
+    let digest = config_digest()?;
+    let mut config = if let Some(c) = load_config()? {
+        c
+    } else {
+        println!("No configuration specified.");
+        return Ok(());
+    };
+    let profile = profile::detect()?;
+    println!("Using {} profile", profile.name);
+    let mut directories = if let Some(d) = directories(&mut config, profile)? {
+        d
+    } else {
+        println!("No directories configured; provisioning is disabled.");
+        return Ok(());
+    };
+    config.validate()?;
+    // Network-bound unlocking can't happen before local-fs.target.
+    let network_unlock = config.clevis.as_ref().is_some_and(|c| c.needs_network());
+    if network_unlock && directories.iter().any(|d| d.wanted_by.is_empty()) {
+        bail!("With a network-bound clevis pin, all directories must specify wanted-by");
+    }
+    for dir in directories.iter().filter(|d| d.quota.is_some()) {
+        if !config.project_quota && config.filesystem != Filesystem::Btrfs {
+            bail!("{}: quota requires project-quota: true", dir.path);
+        }
+        if dir.backing != Backing::Bind {
+            bail!("{}: quota only applies to backing: bind", dir.path);
+        }
+    }
+    throttle::init(&config.heavy_commands)?;
+    let mountpoint = config.mountpoint(profile);
+    for dir in directories.iter() {
+        if let Some(p) = dir.permissions.as_deref() {
+            if !u32::from_str_radix(p, 8).is_ok_and(|m| m <= 0o7777) {
+                bail!(
+                    "{}: permissions must be an octal mode, not {:?}",
+                    dir.path,
+                    p
+                );
+            }
+        }
+        if dir.sync_interval.is_some() && dir.sync_back.is_none() {
+            bail!("{}: sync-interval requires sync-back", dir.path);
+        }
+        let local = dir.sync_back.as_deref().filter(|t| !syncback::is_remote(t));
+        if let Some(persist) = local.map(Path::new) {
+            if !persist.is_absolute()
+                || persist.starts_with(&dir.path)
+                || persist.starts_with(mountpoint)
+            {
+                bail!(
+                    "{}: sync-back must be an absolute path outside it and the instance storage",
+                    dir.path
+                );
+            }
+        }
+    }
+    // With a throwaway key, nothing we set up survives a reboot.
+    let ephemeral_key = config.encrypt.enabled() && config.clevis.is_none();
+    let install = systemd::Install {
+        unit_dir: if ephemeral_key {
+            systemd::RUNTIME_UNIT_DIR
+        } else {
+            profile.unit_dir
+        },
+        runtime: ephemeral_key,
+    };
+    if config.project_quota && config.filesystem != Filesystem::Xfs {
+        bail!(
+            "project-quota requires filesystem: xfs, not {}",
+            config.filesystem.name()
+        );
+    }
+
+    // Held until we return, however that happens.
+    let mut inhibitor = None;
+
+    // If we've already provisioned, there's nothing to do as long as
+    // the same devices are still there.
+    if let Some(state) = state::load()? {
+        let missing = state.missing_devices()?;
+        // Without Clevis, the key was only ever in memory; with it,
+        // unlocking at boot may have failed (e.g. changed PCRs).  Either
+        // way, it's scratch space, so start over.
+        let key_lost = config.encrypt.enabled() && !crypt::is_open();
+        let reconfigured = state.config_digest != digest;
+        if missing.is_empty() && !key_lost && !(reconfigured && opts.reconfigure) {
+            println!("Instance storage is already provisioned.");
+            if reconfigured {
+                println!("The config file has changed; use `provision --reconfigure` to apply it.");
+            }
+            return Ok(());
+        }
+        if missing.is_empty() && !key_lost {
+            println!("The config file has changed since provisioning");
+        }
+        if key_lost && config.clevis.is_some() {
+            println!(
+                "Failed to unlock encrypted instance storage; see {}",
+                crypt::UNIT_NAME
+            );
+        } else if key_lost {
+            println!("Encrypted instance storage can't be reopened after a reboot");
+        }
+        for dev in missing.iter() {
+            let serial = dev.serial.as_deref().unwrap_or("unknown");
+            events::log(
+                events::Event::Degraded,
+                &format!(
+                    "Previously provisioned device {} (serial: {}, wwn: {}) is gone",
+                    dev.path,
+                    serial,
+                    dev.wwn.as_deref().unwrap_or("unknown")
+                ),
+                &[("DEVICE", &dev.path), ("SERIAL", serial)],
+            );
+        }
+        if !missing.is_empty() && config.device_change_policy == DeviceChangePolicy::Alert {
+            for unit in state.units.iter() {
+                Command::new("systemctl")
+                    .args(["disable", "--now"])
+                    .arg(unit)
+                    .run()?;
+            }
+            bail!(
+                "Instance devices changed; disabled {} mount units. Remove {}/state.json to reprovision.",
+                state.units.len(),
+                STATE_DIR
+            );
+        }
+        println!("Reprovisioning instance storage");
+        block::start_modifying();
+        if config.inhibit_updates {
+            inhibitor = Some(inhibit::take()?);
+        }
+        let scrub = config.scrub.as_ref().filter(|s| s.at_teardown);
+        state::invalidate(&state, config.lvm_discards && !config.fast_boot, scrub)?;
+        events::log(
+            events::Event::Teardown,
+            "Tore down the previous instance storage setup",
+            &[],
+        );
+    }
+
+    // Find all instance-local devices
+    failure::stage("device discovery");
+    let platform = platform(opts.platform.as_deref(), &config)?;
+    if let Some(t) = afterburn::instance_type() {
+        println!("Platform {}, instance type {}", platform, t);
+    }
+    // Some platforms attach local disks a while after boot; the cache
+    // is invalidated by udev events, so polling discovery is cheap.
+    let min_devices = config.min_devices.unwrap_or(1) as usize;
+    let deadline = std::time::Duration::from_secs(opts.wait_for_device.unwrap_or(0));
+    let waiting = std::time::Instant::now();
+    let instance_devs = loop {
+        let instance_devs = match cache::discover(&platform, |p| discover(p, &config))? {
+            Some(devs) => devs,
+            None => {
+                println!("Unhandled platform: {}", platform);
+                return Ok(());
+            }
+        };
+        let instance_devs = filter(instance_devs, &config)?;
+        if instance_devs.len() >= min_devices || waiting.elapsed() >= deadline {
+            break instance_devs;
+        }
+        Command::new("udevadm").arg("settle").run()?;
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    };
+    if let Some(n) = config.min_devices {
+        if instance_devs.len() < n as usize {
+            bail!(
+                "Found {} instance devices, expected at least {}",
+                instance_devs.len(),
+                n
+            );
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    let instance_devs = faultinject::wrap(instance_devs)?;
+
+    // Not finding any devices isn't currently an error; we want to
+    // support being run from instance types that don't have any
+    // allocated.
+    if instance_devs.is_empty() {
+        if config.fallback == Fallback::Tmpfs {
+            println!("No ephemeral devices found; using tmpfs instead.");
+            let size = config.fallback_size.as_deref().expect("validated");
+            return tmpfs::setup(&config, &directories, size, profile.selinux);
+        }
+        events::log(
+            events::Event::SkippedNoDevices,
+            "No ephemeral devices found.",
+            &[("PLATFORM", &platform)],
+        );
+        return Ok(());
+    }
+
+    if directories.iter().any(|d| d.min_size.is_some()) {
+        let capacity = instance_devs
+            .iter()
+            .map(|d| block::capacity(d))
+            .sum::<Result<u64>>()?;
+        directories = select_by_capacity(directories, capacity)?;
+    }
+
+    let mut ours: Vec<&str> = directories.iter().map(|d| d.path.as_str()).collect();
+    ours.push(mountpoint);
+    cloudinit::check(config.cloud_init_conflicts, &instance_devs, &ours)?;
+
+    // Everything from here on is destructive.
+    block::start_modifying();
+    let _inhibitor = match inhibitor {
+        None if config.inhibit_updates => Some(inhibit::take()?),
+        inhibitor => inhibitor,
+    };
+
+    if let (Mode::Cache, Some(cache)) = (&config.mode, config.cache.as_ref()) {
+        failure::stage("wiping devices");
+        block::release(&instance_devs)?;
+        backup::wipe_devices(&instance_devs)?;
+        failure::stage("attaching cache");
+        lvmcache::attach(cache, &instance_devs)?;
+        failure::stage("recording state");
+        let mut state = state::State::new(&platform, &instance_devs, None, digest)?;
+        state.cache_origin = Some(cache.origin.clone());
+        state::write(&state)?;
+        let duration = format!("{:.1}", started.elapsed().as_secs_f64());
+        events::log(
+            events::Event::Provisioned,
+            &format!(
+                "Attached instance storage as cache for {} in {}s",
+                cache.origin, duration
+            ),
+            &[
+                ("MODE", "cache"),
+                ("DURATION", &duration),
+                ("GENERATION", &state.generation.to_string()),
+            ],
+        );
+        return Ok(());
+    }
+
+    let vg_name = identity::expand(&config.naming.vg_name, &[])?;
+    let lv_name = identity::expand(&config.naming.lv_name, &[])?;
+
+    if let (Mode::Bcache, Some(settings)) = (&config.mode, config.bcache.as_ref()) {
+        failure::stage("wiping devices");
+        block::release(&instance_devs)?;
+        backup::wipe_devices(&instance_devs)?;
+        failure::stage("setting up bcache");
+        // bcache can't make much use of multiple cache devices, so stripe them.
+        let vg = Some(vg_name.as_str()).filter(|_| instance_devs.len() > 1);
+        let cache_dev = match vg {
+            Some(vg) => {
+                let layout = lvm::Layout::new(
+                    config.lvm_type,
+                    config.lvm_stripes,
+                    config.lvm_stripe_size.as_deref(),
+                    instance_devs.len(),
+                );
+                lvm::require(&layout.segtypes(false))?;
+                lvm::create_vg(vg, &instance_devs, false)?;
+                lvm::create_lv(vg, &lv_name, None, &layout)?
+            }
+            None => instance_devs[0].clone(),
+        };
+        let dev = bcache::attach(settings, &cache_dev)?;
+        let mut state = state::State::new(&platform, &instance_devs, vg, digest)?;
+        state.bcache_backing = Some(settings.backing.clone());
+        if let Some(where_) = settings.where_.as_deref() {
+            failure::stage("mounting bcache device");
+            let unit = bcache::mount(&dev, where_, config.filesystem, install)?;
+            state.units.push(unit);
+        }
+        failure::stage("recording state");
+        state::write(&state)?;
+        let duration = format!("{:.1}", started.elapsed().as_secs_f64());
+        events::log(
+            events::Event::Provisioned,
+            &format!(
+                "Attached instance storage as cache for {} ({}) in {}s",
+                settings.backing, dev, duration
+            ),
+            &[
+                ("MODE", "bcache"),
+                ("DURATION", &duration),
+                ("GENERATION", &state.generation.to_string()),
+            ],
+        );
+        return Ok(());
+    }
+    let adopted = if config.adopt_existing_raid {
+        mdraid::existing_array(&instance_devs)?
+    } else {
+        None
+    };
+    if let Some(md) = adopted.as_deref() {
+        println!("Using existing md array {}", md);
+    }
+    let plan = plan::compute(
+        &platform,
+        &block::resolve(&block::list()?, &instance_devs)?,
+        &config,
+        &directories,
+        adopted.as_deref(),
+        &vg_name,
+        &lv_name,
+    )?;
+    let vg = plan.vg.as_deref();
+    let existing = &plan.main_dev;
+    let layout = lvm::Layout::new(
+        config.lvm_type,
+        config.lvm_stripes,
+        config.lvm_stripe_size.as_deref(),
+        plan.storage_devs.len(),
+    )
+    .with_raid_integrity(config.integrity && vg.is_some());
+    if vg.is_some() {
+        lvm::require(&layout.segtypes(config.thin_pool))?;
+    }
+    let reused = if config.reprovision == Reprovision::IfUnformatted && !config.encrypt.enabled() {
+        failure::stage("checking for an existing filesystem");
+        epoch::check(existing, config.label())?
+    } else {
+        None
+    };
+
+    let mut crypt_dev = None;
+    let mut integrity_dev = None;
+    if let Some(epoch) = reused {
+        println!(
+            "Reusing filesystem on {} provisioned by this machine at epoch {}",
+            existing, epoch
+        );
+    } else {
+        // Mounts left by a previous attempt would keep everything below busy.
+        block::release(&instance_devs)?;
+        // Likewise for an encrypted device, which would keep the LV busy.
+        if crypt::is_open() {
+            crypt::close();
+        }
+        if integrity::is_open() {
+            integrity::close();
+        }
+        // A previous attempt may have failed after creating the volume group;
+        // there's no state recorded in that case.
+        if lvm::vg_exists(&vg_name)? {
+            println!("Removing volume group {} from a previous attempt", vg_name);
+            lvm::remove_stale_vg(&vg_name, config.lvm_discards && !config.fast_boot);
+        }
+        if mdraid::exists() {
+            println!("Stopping {} from a previous attempt", mdraid::PATH);
+            mdraid::stop();
+        }
+
+        // Before we destroy anything, save what's there.  The members of
+        // an adopted array are busy, and we keep the array anyway.
+        failure::stage("wiping devices");
+        let wiped = if adopted.is_some() {
+            &plan.storage_devs
+        } else {
+            &instance_devs
+        };
+        backup::wipe_devices(wiped)?;
+        if let Some(scrub) = config.scrub.as_ref() {
+            failure::stage("scrubbing devices");
+            scrub::run(scrub, wiped)?;
+        } else if config.blkdiscard && !config.fast_boot {
+            failure::stage("discarding devices");
+            block::discard(wiped)?;
+        }
+
+        let sectors = block::sector_sizes(&instance_devs)?;
+        if sectors.mixed {
+            println!(
+                "Devices have mixed logical sector sizes; using {} bytes (physical: {})",
+                sectors.logical, sectors.physical
+            );
+        }
+
+        if let Some(raid) = plan.raid {
+            failure::stage("creating md array");
+            mdraid::create(raid, &instance_devs)?;
+        }
+        if !config.partitions.is_empty() {
+            failure::stage("partitioning");
+            partition::create(&plan.storage_devs[0], &config.partitions)?;
+        }
+
+        let dev = if let Some(vg) = vg {
+            failure::stage("creating LVM volume");
+            lvm::create_vg(vg, &plan.storage_devs, sectors.mixed)?;
+            let mut main = None;
+            for volume in plan.volumes.iter() {
+                let size = volume.size.as_deref();
+                if volume.purpose == plan::Purpose::Pool {
+                    lvm::create_thin_pool(vg, &volume.name, &layout)?;
+                    continue;
+                }
+                let dev = if volume.thin {
+                    lvm::create_thin(vg, lvm::POOL_NAME, &volume.name, size)?
+                } else {
+                    lvm::create_lv(vg, &volume.name, size, &layout)?
+                };
+                match volume.purpose {
+                    plan::Purpose::Export | plan::Purpose::Pool => {}
+                    plan::Purpose::Swap => Command::new("mkswap").arg(dev).run()?,
+                    plan::Purpose::Directory => {
+                        let mut mkfs = config.filesystem.mkfs(None);
+                        if config.fast_boot {
+                            mkfs.args(config.filesystem.fast_options());
+                        }
+                        throttle::apply(&mut mkfs).arg(dev).run()?;
+                    }
+                    plan::Purpose::Main => main = Some(dev),
+                }
+            }
+            Cow::Owned(main.expect("main volume"))
+        } else {
+            Cow::Borrowed(&plan.main_dev)
+        };
+        let dev = if config.integrity && !layout.raid_integrity() {
+            failure::stage("setting up dm-integrity");
+            integrity_dev = Some(dev.to_string());
+            Cow::Owned(integrity::format_and_open(&dev)?)
+        } else {
+            dev
+        };
+        let dev = match config.encrypt {
+            crypt::Encrypt::None => dev,
+            crypt::Encrypt::Luks => {
+                failure::stage("setting up encryption");
+                crypt_dev = Some(dev.to_string());
+                Cow::Owned(crypt::format_and_open(&dev, config.clevis.as_ref())?)
+            }
+            crypt::Encrypt::Plain => {
+                failure::stage("setting up encryption");
+                crypt_dev = Some(dev.to_string());
+                Cow::Owned(crypt::open_plain(&dev)?)
+            }
+        };
+
+        failure::stage("creating filesystem");
+        let mut mkfs = config.filesystem.mkfs(Some(config.label()));
+        throttle::apply(&mut mkfs);
+        // The LV reports the largest logical sector size of its PVs, but be
+        // explicit so the filesystem is usable on all of them.  The other
+        // filesystems use 4k blocks by default anyway.
+        if sectors.mixed && config.filesystem == Filesystem::Xfs {
+            mkfs.arg("-s").arg(format!("size={}", sectors.logical));
+        }
+        // mkfs.xfs rejects conflicting alignment, so the config wins.
+        let aligned = config.mkfs_options.iter().any(|o| o.contains("su="));
+        if let (Some(_), Filesystem::Xfs, false) = (vg, config.filesystem, aligned) {
+            if let Some(d) = layout.xfs_alignment() {
+                mkfs.arg("-d").arg(d);
+            }
+        }
+        if config.fast_boot {
+            mkfs.args(config.filesystem.fast_options());
+        }
+        mkfs.args(&config.mkfs_options);
+        mkfs.arg(dev.as_str()).run()?;
+    }
+
+    // Create the mountpoint and mount unit, and mount it
+    failure::stage("mounting instance storage");
+    preflight::check_creatable(Path::new(mountpoint))?;
+    std::fs::create_dir_all(mountpoint).context("creating mountpoint")?;
+    let dev = format!("/dev/disk/by-label/{}", config.label());
+    let mut mountunit = systemd::MountUnit::new(&dev, mountpoint, config.filesystem.name());
+    if config.project_quota {
+        mountunit.options.push(quota::MOUNT_OPTION);
+    }
+    let remote_fs = ["remote-fs.target".to_string()];
+    if network_unlock {
+        mountunit.wanted_by = &remote_fs;
+    }
+    mountunit.settings = config.mount_unit.as_ref();
+    mountunit.description = Some(identity::expand(
+        &config.naming.unit_description,
+        &[("where", mountpoint)],
+    )?);
+    let mountunit = mountunit
+        .write(install.unit_dir)
+        .context("failed to write mount unit")?;
+    if let Some(crypt_dev) = crypt_dev.as_deref() {
+        let unit = crypt::write_unit(
+            install.unit_dir,
+            crypt_dev,
+            &mountunit,
+            config.encrypt,
+            config.clevis.as_ref(),
+        )?;
+        Command::new("systemctl").arg("daemon-reload").run()?;
+        install.enable(&unit, true)?;
+    }
+    if let Some(integrity_dev) = integrity_dev.as_deref() {
+        // Beneath the encryption, if any.
+        let before = if crypt_dev.is_some() {
+            crypt::UNIT_NAME
+        } else {
+            mountunit.as_str()
+        };
+        let unit = integrity::write_unit(install.unit_dir, integrity_dev, before)?;
+        Command::new("systemctl").arg("daemon-reload").run()?;
+        install.enable(&unit, true)?;
+    }
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    install.enable(&mountunit, true)?;
+    if reused.is_none() {
+        epoch::write(mountpoint)?;
+    }
+    // We need to ensure it has a SELinux label.
+    if profile.selinux {
+        selinux::label_as(mountpoint, mountpoint)?;
+    }
+
+    // A stable location for pods to use via hostPath; it's labeled
+    // so that containers can write to it without relabeling.
+    if config.workloads_directory {
+        let workloads = Path::new(mountpoint).join(WORKLOADS_DIR);
+        std::fs::create_dir_all(&workloads).context("creating workloads dir")?;
+        if profile.selinux {
+            selinux::set_context(&workloads, selinux::CONTAINER_FILE_CONTEXT)?;
+        }
+        println!("Created {:?} for container workloads", workloads);
+    }
+
+    // Iterate over the desired directories (should be under /var)
+    // that we want to have mounted instance-local.  Software
+    // using these directories should ideally be prepared to start
+    // with it empty.
+    failure::stage("redirecting directories");
+    let root = openat::Dir::open("/").context("opening /")?;
+    let mut units = Vec::new();
+    let mut targets = Vec::new();
+    let mut redirected = Vec::new();
+    // Started mounts on a new filesystem, to relabel once they're in place.
+    let mut relabel = Vec::new();
+    for dir in directories.iter() {
+        let d = Path::new(&dir.path);
+        let d_utf8 = dir.path.as_str();
+        let name = d
+            .file_name()
+            .ok_or_else(|| anyhow!("Expected filename in {:?}", d))?;
+        if config.workloads_directory && name == WORKLOADS_DIR {
+            bail!("{} conflicts with the workloads directory", d_utf8);
+        }
+        if !config.files.is_empty() && name == FILES_DIR {
+            bail!("{} conflicts with the directory for files", d_utf8);
+        }
+        let facts = policy::Facts::gather(d)?;
+        let decision = policy::evaluate(&config.directory_policy, &facts, profile.selinux);
+        println!("Policy for {}:", d_utf8);
+        for line in decision.trace.iter() {
+            println!("  {}", line);
+        }
+        match decision.action {
+            policy::Action::Replace => {}
+            policy::Action::Skip => {
+                println!("Skipping {}", d_utf8);
+                continue;
+            }
+            policy::Action::Fail => bail!("Policy forbids redirecting {}", d_utf8),
+        }
+        let overlay = dir.mode == DirectoryMode::Overlay;
+        if overlay && dir.backing != Backing::Bind {
+            bail!("{}: mode overlay requires backing bind", d_utf8);
+        }
+        if overlay && dir.migrate {
+            bail!("{}: migrate is redundant with mode overlay", d_utf8);
+        }
+        // Only on a fresh filesystem; when reusing one, whatever is on it
+        // is newer than what's underneath.
+        let migrate = dir.migrate && reused.is_none() && d.exists();
+        let (target, what, fstype, options) = match dir.backing {
+            // The original directory stays in place underneath.
+            Backing::Bind if overlay => {
+                let target = Path::new(mountpoint).join(name);
+                if config.filesystem == Filesystem::Btrfs {
+                    btrfs::ensure_subvolume(&target)?;
+                }
+                let upper = target.join("upper");
+                let work = target.join("work");
+                std::fs::create_dir_all(&upper).context("creating upper dir")?;
+                std::fs::create_dir_all(&work).context("creating work dir")?;
+                if decision.label {
+                    selinux::label_as(d, &upper)?;
+                }
+                let options = format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    d_utf8,
+                    upper.display(),
+                    work.display()
+                );
+                (Some(target), "overlay".to_string(), "overlay", options)
+            }
+            // A subvolume per directory gives per-directory usage accounting
+            // and limits (via qgroups), which bind mounts can't.
+            Backing::Bind if config.filesystem == Filesystem::Btrfs => {
+                let target = Path::new(mountpoint).join(name);
+                btrfs::ensure_subvolume(&target)?;
+                if decision.label {
+                    selinux::label_as(d, &target)?;
+                }
+                let subvol = format!("subvol={}", name.to_str().expect("utf8"));
+                (Some(target), dev.clone(), "btrfs", subvol)
+            }
+            Backing::Bind => {
+                let target = Path::new(mountpoint).join(name);
+                // This may exist if a previous attempt failed after creating it.
+                std::fs::create_dir_all(&target).context("creating target dir")?;
+                if decision.label {
+                    selinux::label_as(d, &target)?;
+                }
+                let what = target.to_str().expect("utf8").to_string();
+                (Some(target), what, "none", "bind".to_string())
+            }
+            Backing::File => {
+                let size = dir.size()?;
+                let mut image = name.to_owned();
+                image.push(".img");
+                let image = Path::new(mountpoint).join(image);
+                if reused.is_none() || !image.exists() {
+                    let label_from = Some(d).filter(|_| decision.label);
+                    loopback::create(&image, parse_size(size)?, label_from)?;
+                }
+                (
+                    None,
+                    image.to_str().expect("utf8").to_string(),
+                    "xfs",
+                    "loop".to_string(),
+                )
+            }
+            Backing::Lv => {
+                let vg = vg.expect("volume group");
+                let dev = format!("/dev/{}/{}", vg, dir.lv_name()?);
+                if reused.is_none() && decision.label {
+                    selinux::label_root_as(d, Path::new(&dev), "defaults")?;
+                }
+                (None, dev, config.filesystem.name(), "defaults".to_string())
+            }
+        };
+        if migrate {
+            match target.as_ref() {
+                Some(target) => migrate::copy(d, target)?,
+                None => migrate::copy_to_root(d, Path::new(&what), &options)?,
+            }
+        }
+        if dir.has_ownership() {
+            match target.as_ref() {
+                // The root of an overlay is that of its upper directory.
+                Some(target) if overlay => ownership::apply(dir, &target.join("upper"))?,
+                Some(target) => ownership::apply(dir, target)?,
+                None if reused.is_none() => {
+                    ownership::apply_to_root(dir, Path::new(&what), &options)?
+                }
+                None => {}
+            }
+        }
+        preflight::check_creatable(d)?;
+        if let (false, Some(dest)) = (overlay, config.directory_backup.as_deref()) {
+            if Path::new(dest).starts_with(d) {
+                bail!("directory-backup {} is within {}", dest, d_utf8);
+            }
+            archive::create(Path::new(dest), d)?;
+        }
+        if !overlay {
+            failure::record_directory(d_utf8);
+            root.remove_all(d)
+                .with_context(|| format!("Removing {:?}", d))?;
+            std::fs::create_dir(d).with_context(|| format!("Creating {}", d_utf8))?;
+        } else if !d.exists() {
+            std::fs::create_dir_all(d).with_context(|| format!("Creating {}", d_utf8))?;
+        }
+        let requires = target
+            .as_ref()
+            .filter(|_| overlay)
+            .map(|t| t.to_str().expect("utf8"));
+        // Sadly crio on RHEL8 at least bails out if /var/lib/containers is a symlink.
+        // So we use bind mounts instead.
+        let unit = systemd::MountUnit {
+            options: vec![options.as_str()],
+            wanted_by: &dir.wanted_by,
+            before: &dir.before,
+            after: &dir.after,
+            settings: dir.mount_unit.as_ref(),
+            description: Some(identity::expand(
+                &config.naming.unit_description,
+                &[("where", d_utf8)],
+            )?),
+            requires,
+            ..systemd::MountUnit::new(&what, d_utf8, fstype)
+        }
+        .write(install.unit_dir)?;
+        if let Some(target) = dir.sync_back.as_deref() {
+            let interval = dir.sync_interval.as_deref();
+            for u in syncback::write_units(install.unit_dir, d_utf8, target, interval, &unit)? {
+                units.push((u, dir.wanted_by.is_empty()));
+            }
+        }
+        // Mounts with explicit consumers are only started along with them.
+        units.push((unit, dir.wanted_by.is_empty()));
+        // File-backed directories are capped already.
+        if let Some(target) = target {
+            let limit = dir.quota.as_deref().map(parse_size).transpose()?;
+            targets.push((dir.path.clone(), target, limit));
+        }
+        // Weakly activated mounts aren't there yet for consumers to adjust to.
+        if dir.wanted_by.is_empty() {
+            redirected.push(d_utf8);
+            // Relabeling through an overlay would copy everything up.
+            if !overlay && reused.is_none() {
+                relabel.push(d_utf8);
+            }
+        }
+        println!("Set up {:?} to use instance storage", d);
+    }
+    for file in config.files.iter() {
+        let dest = Path::new(&file.path);
+        let filesdir = Path::new(mountpoint).join(FILES_DIR);
+        std::fs::create_dir_all(&filesdir)?;
+        let target = filesdir.join(file.path.trim_matches('/').replace('/', "-"));
+        if !target.exists() {
+            let f =
+                std::fs::File::create(&target).with_context(|| format!("Creating {:?}", target))?;
+            if let Some(size) = file.size.as_deref() {
+                f.set_len(parse_size(size)?)?;
+            }
+            if profile.selinux && dest.exists() {
+                selinux::label_as(dest, &target)?;
+            }
+        }
+        // The mount point must be a file too.
+        if dest.is_dir() {
+            bail!("{} is a directory", file.path);
+        }
+        if !dest.exists() {
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(dest).with_context(|| format!("Creating {}", file.path))?;
+        }
+        let what = target.to_str().expect("utf8");
+        let unit = systemd::MountUnit {
+            options: vec!["bind"],
+            wanted_by: &file.wanted_by,
+            description: Some(identity::expand(
+                &config.naming.unit_description,
+                &[("where", &file.path)],
+            )?),
+            ..systemd::MountUnit::new(what, &file.path, "none")
+        }
+        .write(install.unit_dir)?;
+        units.push((unit, file.wanted_by.is_empty()));
+        println!("Set up {} to use instance storage", file.path);
+    }
+    if config.project_quota {
+        failure::stage("setting up project quotas");
+        quota::setup(mountpoint, &targets)?;
+    } else if config.filesystem == Filesystem::Btrfs {
+        failure::stage("setting up qgroup limits");
+        btrfs::limit(mountpoint, &targets)?;
+    }
+    // Enable (and start, unless weakly activated) all the mount units we set up
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    let mut all_units = vec![mountunit];
+    all_units.extend(units.iter().map(|(u, _)| u.clone()));
+    let (start, weak): (Vec<_>, Vec<_>) = units.into_iter().partition(|(_, start)| *start);
+    install.enable_all(&start.into_iter().map(|(u, _)| u).collect::<Vec<_>>(), true)?;
+    install.enable_all(&weak.into_iter().map(|(u, _)| u).collect::<Vec<_>>(), false)?;
+    if profile.selinux {
+        failure::stage("relabeling directories");
+        selinux::restore(&relabel)?;
+    }
+    let mut export_links = Vec::new();
+    if let (Some(vg), false) = (vg, config.exports.is_empty()) {
+        failure::stage("setting up exports");
+        let (units, links) =
+            exports::setup(&config.exports, vg, mountpoint, install, reused.is_none())?;
+        all_units.extend(units);
+        export_links = links;
+    }
+    let env = consumers::Env {
+        config: &config,
+        selinux: profile.selinux,
+        install,
+        mountpoint,
+        vg,
+    };
+    all_units.extend(consumers::setup(&redirected, &env)?);
+    failure::stage("recording state");
+    let mut state = state::State::new(&platform, &instance_devs, vg, digest)?;
+    state.units = all_units;
+    state.export_links = export_links;
+    state.raid = plan.raid.is_some();
+    state::write(&state)?;
+    let duration = format!("{:.1}", started.elapsed().as_secs_f64());
+    events::log(
+        events::Event::Provisioned,
+        &format!("Provisioned instance storage in {}s", duration),
+        &[
+            ("MODE", "filesystem"),
+            ("DURATION", &duration),
+            ("GENERATION", &state.generation.to_string()),
+        ],
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs(min_sizes: &[Option<&str>]) -> Vec<Directory> {
+        min_sizes
+            .iter()
+            .enumerate()
+            .map(|(i, min_size)| Directory {
+                path: format!("/var/dir{}", i),
+                min_size: min_size.map(|s| s.to_string()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn selected(min_sizes: &[Option<&str>], capacity: u64) -> Vec<String> {
+        select_by_capacity(dirs(min_sizes), capacity)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.path)
+            .collect()
+    }
+
+    #[test]
+    fn select_by_capacity_boundaries() {
+        let sizes = [Some("10G"), Some("20G")];
+        assert_eq!(selected(&sizes, 30 << 30), ["/var/dir0", "/var/dir1"]);
+        assert_eq!(selected(&sizes, (30 << 30) - 1), ["/var/dir0"]);
+        assert_eq!(selected(&sizes, 10 << 30), ["/var/dir0"]);
+        assert!(selected(&sizes, (10 << 30) - 1).is_empty());
+        // A skipped directory doesn't use up capacity for later ones.
+        let sizes = [Some("20G"), Some("10G")];
+        assert_eq!(selected(&sizes, 15 << 30), ["/var/dir1"]);
+        // Without min-size, a directory is always kept.
+        assert_eq!(selected(&[None, Some("1K")], 0), ["/var/dir0"]);
+        assert!(select_by_capacity(dirs(&[Some("lots")]), 1 << 40).is_err());
+        // Sizes summing past u64::MAX don't wrap around to fit.
+        let huge = (u64::MAX >> 10).to_string() + "K";
+        let sizes = [Some("1K"), Some(huge.as_str()), Some("1K")];
+        assert_eq!(selected(&sizes, u64::MAX), ["/var/dir0", "/var/dir2"]);
+    }
+}
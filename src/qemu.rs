@@ -0,0 +1,9 @@
+//! This one is totally made up for local testing; use e.g.
+
+use super::*;
+
+const PREFIX: &str = "CoreOSQEMUInstance";
+
+pub(crate) fn devices() -> Result<Vec<String>> {
+    block::with_serial_prefix(PREFIX)
+}
@@ -0,0 +1,63 @@
+//! XFS project quotas, used to account usage per redirected directory.
+//! We keep our own projects(5)/projid(5) files rather than
+//! modifying the ones in /etc.
+
+use super::*;
+use std::path::PathBuf;
+
+/// The mount option enabling project quota accounting.
+pub(crate) const MOUNT_OPTION: &str = "prjquota";
+const PROJECTS_FILE: &str = "projects";
+const PROJID_FILE: &str = "projid";
+
+/// e.g. `/var/lib/containers` becomes `ccisp-var-lib-containers`.
+fn project_name(dir: &str) -> String {
+    format!("ccisp-{}", dir.trim_matches('/').replace('/', "-"))
+}
+
+fn xfs_quota(mountpoint: &str, cmd: &str) -> Command {
+    let state = Path::new(STATE_DIR);
+    let mut c = Command::new("xfs_quota");
+    c.arg("-x")
+        .arg("-D")
+        .arg(state.join(PROJECTS_FILE))
+        .arg("-P")
+        .arg(state.join(PROJID_FILE))
+        .args(["-c", cmd])
+        .arg(mountpoint);
+    c
+}
+
+/// Set up a project for each (directory, target, limit) entry; the
+/// target is the directory's location on the instance storage, and
+/// the limit (in bytes) is enforced as a hard block limit.
+pub(crate) fn setup(mountpoint: &str, dirs: &[(String, PathBuf, Option<u64>)]) -> Result<()> {
+    let mut projects = String::new();
+    let mut projid = String::new();
+    for (i, (dir, target, _)) in dirs.iter().enumerate() {
+        let id = i + 1;
+        projects.push_str(&format!("{}:{}\n", id, target.display()));
+        projid.push_str(&format!("{}:{}\n", project_name(dir), id));
+    }
+    std::fs::create_dir_all(STATE_DIR)?;
+    let statedir = openat::Dir::open(STATE_DIR)?;
+    statedir.write_file_contents(PROJECTS_FILE, 0o644, projects.as_bytes())?;
+    statedir.write_file_contents(PROJID_FILE, 0o644, projid.as_bytes())?;
+    for (dir, _, limit) in dirs {
+        let name = project_name(dir);
+        xfs_quota(mountpoint, &format!("project -s {}", name)).run()?;
+        if let Some(limit) = limit {
+            xfs_quota(mountpoint, &format!("limit -p bhard={} {}", limit, name)).run()?;
+        }
+    }
+    Ok(())
+}
+
+/// Print usage and limits of each project.
+pub(crate) fn status(config: &Config, mountpoint: &str) -> Result<()> {
+    if !config.project_quota {
+        println!("Project quotas are not enabled");
+        return Ok(());
+    }
+    xfs_quota(mountpoint, "report -p -b -i -h").run()
+}
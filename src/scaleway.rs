@@ -0,0 +1,8 @@
+use super::*;
+
+/// Local volumes; network attached Block Storage uses `b_ssd`.
+const MODEL: &str = "l_ssd";
+
+pub(crate) fn devices() -> Result<Vec<String>> {
+    block::with_model(MODEL)
+}
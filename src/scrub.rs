@@ -0,0 +1,89 @@
+//! Secure erase of the instance devices, for compliance requirements
+//! around scratch devices which may have been used by someone else.
+//! Each erase is logged to the journal and recorded in a log file.
+
+use super::*;
+use std::io::Write as IoWrite;
+
+const LOG_FILE: &str = "scrub.log";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Method {
+    /// `nvme format` with a user data erase.
+    #[default]
+    NvmeFormat,
+    /// `nvme format` with a cryptographic erase, which is instant.
+    NvmeCryptoErase,
+    /// `blkdiscard --secure`, for any device supporting it.
+    BlkdiscardSecure,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Settings {
+    #[serde(default)]
+    method: Method,
+    /// Also erase the devices when tearing down, e.g. when they changed.
+    #[serde(default)]
+    pub(crate) at_teardown: bool,
+}
+
+fn erase(method: Method, dev: &str) -> Result<()> {
+    let mut c = match method {
+        Method::NvmeFormat | Method::NvmeCryptoErase => {
+            if !dev.starts_with("/dev/nvme") {
+                bail!("{} is not an NVMe device", dev);
+            }
+            let ses = if method == Method::NvmeFormat { 1 } else { 2 };
+            let mut c = Command::new("nvme");
+            c.arg("format")
+                .arg(dev)
+                .arg(format!("--ses={}", ses))
+                .arg("--force");
+            c
+        }
+        Method::BlkdiscardSecure => {
+            let mut c = Command::new("blkdiscard");
+            c.arg("--secure").arg(dev);
+            c
+        }
+    };
+    throttle::apply(&mut c).run()
+}
+
+fn record(method: Method, dev: &str) -> Result<()> {
+    let serial = block::list()?
+        .into_iter()
+        .find(|d| d.path() == dev)
+        .and_then(|d| d.serial)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let line = format!(
+        "{} {} serial={} method={:?}",
+        now.as_secs(),
+        dev,
+        serial,
+        method
+    );
+    println!("Scrubbed {} (serial: {}) with {:?}", dev, serial, method);
+    std::fs::create_dir_all(STATE_DIR)?;
+    let path = Path::new(STATE_DIR).join(LOG_FILE);
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Opening {:?}", path))?;
+    writeln!(f, "{}", line)?;
+    Ok(())
+}
+
+/// Erase all of `devices`.
+pub(crate) fn run(settings: &Settings, devices: &[String]) -> Result<()> {
+    for dev in devices {
+        erase(settings.method, dev).with_context(|| format!("Scrubbing {}", dev))?;
+        record(settings.method, dev)?;
+    }
+    Ok(())
+}
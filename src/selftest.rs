@@ -0,0 +1,154 @@
+//! Runs device discovery against lsblk output captured on each supported
+//! platform, compiled into the binary; this verifies a build's platform
+//! logic without needing access to any cloud.  While a fixture is active,
+//! it replaces the host's block devices, and metadata services are treated
+//! as unreachable.
+
+use super::*;
+use std::sync::Mutex;
+
+struct Fixture {
+    platform: &'static str,
+    /// Extra configuration, as YAML.
+    config: &'static str,
+    lsblk: &'static str,
+    expected: &'static [&'static str],
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        platform: "aws",
+        config: "{}",
+        lsblk: include_str!("fixtures/aws.json"),
+        expected: &["/dev/nvme1n1", "/dev/nvme2n1"],
+    },
+    Fixture {
+        platform: "azure",
+        config: "{}",
+        lsblk: include_str!("fixtures/azure.json"),
+        expected: &["/dev/sdb", "/dev/nvme0n1"],
+    },
+    Fixture {
+        platform: "azure",
+        config: "{}",
+        lsblk: include_str!("fixtures/azure-hpc.json"),
+        expected: &["/dev/sdb", "/dev/nvme0n1", "/dev/nvme1n1"],
+    },
+    Fixture {
+        platform: "azure",
+        config: "azure-disks: nvme",
+        lsblk: include_str!("fixtures/azure-hpc.json"),
+        expected: &["/dev/nvme0n1", "/dev/nvme1n1"],
+    },
+    Fixture {
+        platform: "digitalocean",
+        config: "{}",
+        lsblk: include_str!("fixtures/digitalocean.json"),
+        expected: &["/dev/sdb"],
+    },
+    Fixture {
+        platform: "equinixmetal",
+        config: "{}",
+        lsblk: include_str!("fixtures/equinixmetal.json"),
+        expected: &["/dev/nvme2n1"],
+    },
+    Fixture {
+        platform: "hetzner",
+        config: "{}",
+        lsblk: include_str!("fixtures/hetzner.json"),
+        expected: &["/dev/sda"],
+    },
+    Fixture {
+        platform: "kubevirt",
+        config: "{}",
+        lsblk: include_str!("fixtures/kubevirt.json"),
+        expected: &["/dev/vdb"],
+    },
+    Fixture {
+        platform: metal::PLATFORM,
+        config: "{}",
+        lsblk: include_str!("fixtures/metal.json"),
+        expected: &["/dev/nvme0n1"],
+    },
+    Fixture {
+        platform: "qemu",
+        config: "{}",
+        lsblk: include_str!("fixtures/qemu.json"),
+        expected: &["/dev/nvme0n1", "/dev/nvme1n1"],
+    },
+    Fixture {
+        platform: "scaleway",
+        config: "{}",
+        lsblk: include_str!("fixtures/scaleway.json"),
+        expected: &["/dev/sdb", "/dev/sdc"],
+    },
+    Fixture {
+        platform: "tencentcloud",
+        config: "{}",
+        lsblk: include_str!("fixtures/tencentcloud.json"),
+        expected: &["/dev/nvme0n1"],
+    },
+    Fixture {
+        platform: "vmware",
+        config: "vmware-disks: [6000c2912345678901234567890abcde]",
+        lsblk: include_str!("fixtures/vmware.json"),
+        expected: &["/dev/sdb"],
+    },
+    Fixture {
+        platform: "vultr",
+        config: "{}",
+        lsblk: include_str!("fixtures/vultr.json"),
+        expected: &["/dev/nvme0n1"],
+    },
+];
+
+static ACTIVE: Mutex<Option<Vec<block::Device>>> = Mutex::new(None);
+
+/// The block devices of the current fixture, if any.
+pub(crate) fn blockdevices() -> Option<Vec<block::Device>> {
+    ACTIVE.lock().expect("lock").clone()
+}
+
+pub(crate) fn active() -> bool {
+    ACTIVE.lock().expect("lock").is_some()
+}
+
+fn check(fixture: &Fixture) -> Result<Vec<String>> {
+    let config: Config = serde_yaml::from_str(fixture.config)?;
+    let devices = block::parse(fixture.lsblk.as_bytes())?;
+    *ACTIVE.lock().expect("lock") = Some(devices.clone());
+    let r = match fixture.platform {
+        // IMDS and the udev symlink would describe this machine.
+        "azure" => Ok(Some(azure::devices_in(config.azure_disks, &devices))),
+        platform => provision::discover(platform, &config),
+    };
+    *ACTIVE.lock().expect("lock") = None;
+    r?.ok_or_else(|| anyhow!("Unsupported platform"))
+}
+
+pub(crate) fn run() -> Result<()> {
+    let mut failed = 0;
+    for fixture in FIXTURES {
+        match check(fixture) {
+            Ok(devs) if devs == fixture.expected => {
+                println!("PASS {}: {}", fixture.platform, devs.join(" "));
+            }
+            Ok(devs) => {
+                failed += 1;
+                println!(
+                    "FAIL {}: expected {:?}, found {:?}",
+                    fixture.platform, fixture.expected, devs
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}: {:#}", fixture.platform, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} fixtures failed", failed, FIXTURES.len());
+    }
+    println!("All {} fixtures passed", FIXTURES.len());
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use super::*;
+
+/// The context the policy assigns to `path`, i.e. what `restorecon`
+/// would set; e.g. `container_var_lib_t` for `/var/lib/containers`.
+fn policy_context(path: &Path) -> Result<String> {
+    let o = Command::new("matchpathcon").arg("-n").arg(path).output()?;
+    if !o.status.success() {
+        bail!(
+            "Looking up the SELinux context for {:?}: {}",
+            path,
+            String::from_utf8_lossy(&o.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(o.stdout)?.trim().to_string())
+}
+
+/// Give `dest` the context the policy defines for `path`, where it's
+/// going to be mounted.
+pub(crate) fn label_as<P: AsRef<Path>, D: AsRef<Path>>(path: P, dest: D) -> Result<()> {
+    set_context(dest, &policy_context(path.as_ref())?)
+}
+
+/// Relabel the (mounted) `paths` and everything below them according
+/// to the policy, as `restorecon -R`.
+pub(crate) fn restore<S: AsRef<str>>(paths: &[S]) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    Command::new("restorecon")
+        .arg("-R")
+        .args(paths.iter().map(|p| p.as_ref()))
+        .run()
+}
+
+/// The label for content shared with (all) containers.
+pub(crate) const CONTAINER_FILE_CONTEXT: &str = "system_u:object_r:container_file_t:s0";
+/// The label of /tmp and /var/tmp, which pam_namespace also expects.
+pub(crate) const TMP_CONTEXT: &str = "system_u:object_r:tmp_t:s0";
+
+pub(crate) fn set_context<P: AsRef<Path>>(path: P, context: &str) -> Result<()> {
+    Command::new("chcon")
+        .arg(context)
+        .arg(path.as_ref())
+        .run()?;
+    Ok(())
+}
+
+/// The context of `path`, e.g. for a `rootcontext=` mount option.
+pub(crate) fn get_context(path: &Path) -> Result<String> {
+    let o = Command::new("stat").args(["-c", "%C"]).arg(path).output()?;
+    if !o.status.success() {
+        bail!("Failed to get the SELinux context of {:?}", path);
+    }
+    Ok(String::from_utf8_lossy(&o.stdout).trim().to_string())
+}
+
+const LABEL_MOUNTPOINT: &str = "label";
+
+/// Give the root of the (unmounted) filesystem on `what` the context
+/// the policy defines for `path`, mounting it temporarily with `options`.
+pub(crate) fn label_root_as(path: &Path, what: &Path, options: &str) -> Result<()> {
+    with_mounted(LABEL_MOUNTPOINT, what, options, |mnt| label_as(path, mnt))
+}
@@ -0,0 +1,124 @@
+//! An end-to-end check of the host's tools, on a loopback file rather
+//! than the instance storage: LVM, mkfs and a mount unit, set up and torn
+//! down the way provisioning does.  For image QA pipelines.
+
+use super::*;
+
+const IMAGE: &str = "smoke.img";
+const MOUNTPOINT: &str = "smoke";
+const VG_NAME: &str = "ccisp-smoke";
+const LV_NAME: &str = "smoke";
+/// Sparse, so this costs little even on tmpfs; mkfs.xfs needs 300M.
+const IMAGE_SIZE: u64 = 512 * 1024 * 1024;
+
+fn step(name: &str, f: impl FnOnce() -> Result<()>) -> Result<()> {
+    f().with_context(|| format!("smoke: {}", name))?;
+    println!("ok {}", name);
+    Ok(())
+}
+
+fn setup(loopdev: &mut Option<String>, unit: &mut Option<String>) -> Result<()> {
+    let image = Path::new(RUN_DIR).join(IMAGE);
+    step("creating loopback file", || {
+        std::fs::create_dir_all(RUN_DIR)?;
+        std::fs::File::create(&image)?.set_len(IMAGE_SIZE)?;
+        let o = Command::new("losetup")
+            .args(["--find", "--show"])
+            .arg(&image)
+            .output()?;
+        if !o.status.success() {
+            bail!("losetup: {}", String::from_utf8_lossy(&o.stderr).trim());
+        }
+        *loopdev = Some(String::from_utf8(o.stdout)?.trim().to_string());
+        Ok(())
+    })?;
+    let devs = [loopdev.clone().expect("loop device")];
+    let layout = lvm::Layout::new(lvm::LvType::Striped, None, None, devs.len());
+    let mut lv = String::new();
+    step("creating logical volume", || {
+        lvm::require(&layout.segtypes(false))?;
+        lvm::create_vg(VG_NAME, &devs, false)?;
+        lv = lvm::create_lv(VG_NAME, LV_NAME, None, &layout)?;
+        Ok(())
+    })?;
+    step("creating filesystem", || {
+        Filesystem::Xfs.mkfs(None).arg(&lv).run()
+    })?;
+    let mnt = Path::new(RUN_DIR).join(MOUNTPOINT);
+    let mnt = mnt.to_str().expect("utf8");
+    step("mounting via systemd", || {
+        std::fs::create_dir_all(mnt)?;
+        let name = systemd::MountUnit::new(&lv, mnt, Filesystem::Xfs.name())
+            .write(systemd::RUNTIME_UNIT_DIR)?;
+        *unit = Some(name.clone());
+        Command::new("systemctl").arg("daemon-reload").run()?;
+        Command::new("systemctl").arg("start").arg(&name).run()
+    })?;
+    step("writing a file", || {
+        let f = Path::new(mnt).join("smoke");
+        std::fs::write(&f, "smoke\n")?;
+        if std::fs::read_to_string(&f)? != "smoke\n" {
+            bail!("Read back something else from {:?}", f);
+        }
+        Ok(())
+    })
+}
+
+/// Undo whatever `setup` got to, returning whether all went well.
+fn teardown(loopdev: Option<String>, unit: Option<String>) -> bool {
+    let mut ok = true;
+    let mut check = |name: &str, r: Result<()>| match r {
+        Ok(()) => println!("ok {}", name),
+        Err(e) => {
+            ok = false;
+            println!("FAIL {}: {:#}", name, e);
+        }
+    };
+    if let Some(unit) = unit {
+        check(
+            "unmounting via systemd",
+            Command::new("systemctl").arg("stop").arg(&unit).run(),
+        );
+        let _ = std::fs::remove_file(Path::new(systemd::RUNTIME_UNIT_DIR).join(&unit));
+        let _ = Command::new("systemctl").arg("daemon-reload").run();
+    }
+    if let Some(dev) = loopdev {
+        if lvm::vg_exists(VG_NAME).unwrap_or(false) {
+            lvm::remove_stale_vg(VG_NAME, false);
+            check(
+                "removing volume group",
+                match lvm::vg_exists(VG_NAME) {
+                    Ok(false) => Ok(()),
+                    Ok(true) => Err(anyhow!("{} still exists", VG_NAME)),
+                    Err(e) => Err(e),
+                },
+            );
+        }
+        lvm::forget_device(&dev);
+        check(
+            "detaching loopback device",
+            Command::new("losetup").arg("--detach").arg(&dev).run(),
+        );
+    }
+    let _ = std::fs::remove_file(Path::new(RUN_DIR).join(IMAGE));
+    ok
+}
+
+pub(crate) fn run() -> Result<()> {
+    if lvm::vg_exists(VG_NAME)? {
+        bail!("Volume group {} exists already", VG_NAME);
+    }
+    let mut loopdev = None;
+    let mut unit = None;
+    let r = setup(&mut loopdev, &mut unit);
+    if let Err(e) = r.as_ref() {
+        println!("FAIL {:#}", e);
+    }
+    let torn_down = teardown(loopdev, unit);
+    r?;
+    if !torn_down {
+        bail!("smoke: teardown failed");
+    }
+    println!("All smoke steps passed");
+    Ok(())
+}
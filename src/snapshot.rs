@@ -0,0 +1,82 @@
+//! Snapshots of the instance storage, e.g. to roll back the state in
+//! `/var/lib/containers` after an experiment on a test node.  This
+//! requires a filesystem which supports them; for btrfs, snapshots
+//! are read-only subvolumes in a hidden directory.
+
+use super::*;
+use std::path::PathBuf;
+
+const SNAPSHOT_DIR: &str = ".snapshots";
+/// Name of the snapshot of the top-level subvolume, within each snapshot.
+const TOP_LEVEL: &str = ".top-level";
+
+fn snapshot_dir(config: &Config, mountpoint: &str) -> Result<PathBuf> {
+    if config.filesystem != Filesystem::Btrfs {
+        bail!(
+            "Snapshots require filesystem: btrfs, not {}",
+            config.filesystem.name()
+        );
+    }
+    Ok(Path::new(mountpoint).join(SNAPSHOT_DIR))
+}
+
+fn snapshot_path(config: &Config, mountpoint: &str, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        bail!("Invalid snapshot name: {:?}", name);
+    }
+    Ok(snapshot_dir(config, mountpoint)?.join(name))
+}
+
+pub(crate) fn create(config: &Config, mountpoint: &str, name: &str) -> Result<()> {
+    let path = snapshot_path(config, mountpoint, name)?;
+    std::fs::create_dir_all(snapshot_dir(config, mountpoint)?)?;
+    if path.exists() {
+        bail!("Snapshot {} already exists", name);
+    }
+    // Snapshots don't descend into nested subvolumes, so take one of
+    // the top level and one of each directory's subvolume.
+    let subvols = btrfs::subvolumes(Path::new(mountpoint))?;
+    std::fs::create_dir(&path)?;
+    Command::new("btrfs")
+        .args(["subvolume", "snapshot", "-r", mountpoint])
+        .arg(path.join(TOP_LEVEL))
+        .run()?;
+    for subvol in subvols {
+        Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(Path::new(mountpoint).join(&subvol))
+            .arg(path.join(&subvol))
+            .run()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn list(config: &Config, mountpoint: &str) -> Result<()> {
+    let dir = snapshot_dir(config, mountpoint)?;
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut names = std::fs::read_dir(&dir)
+        .with_context(|| format!("Reading {:?}", dir))?
+        .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+pub(crate) fn delete(config: &Config, mountpoint: &str, name: &str) -> Result<()> {
+    let path = snapshot_path(config, mountpoint, name)?;
+    if !path.exists() {
+        bail!("No snapshot named {}", name);
+    }
+    let mut c = Command::new("btrfs");
+    c.args(["subvolume", "delete"]);
+    for subvol in btrfs::subvolumes(&path)? {
+        c.arg(path.join(subvol));
+    }
+    c.run()?;
+    std::fs::remove_dir(&path).with_context(|| format!("Removing {:?}", path))
+}
@@ -0,0 +1,288 @@
+//! Records what we provisioned, so that subsequent boots can tell whether
+//! the instance storage is still the same.  On AWS for example, a stop/start
+//! cycle gives us fresh (and possibly differently enumerated) devices.
+
+use super::*;
+
+const STATE_FILE: &str = "state.json";
+/// Kept apart from the state, which is removed when reprovisioning.
+const GENERATION_FILE: &str = "generation";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RecordedDevice {
+    pub(crate) path: String,
+    pub(crate) serial: Option<String>,
+    /// Together with the serial, this fingerprints the physical device.
+    pub(crate) wwn: Option<String>,
+}
+
+impl RecordedDevice {
+    /// Record the identity of the device at `path`, as found in `blockdevs`.
+    pub(crate) fn new(path: &str, blockdevs: &[block::Device]) -> Self {
+        let dev = blockdevs.iter().find(|d| d.path() == path);
+        let field = |f: fn(&block::Device) -> &Option<String>| {
+            dev.and_then(|d| f(d).as_ref())
+                .map(|s| s.trim().to_string())
+        };
+        Self {
+            path: path.to_string(),
+            serial: field(|d| &d.serial),
+            wwn: field(|d| &d.wwn),
+        }
+    }
+
+    /// Whether `dev` is the same physical device we recorded.
+    pub(crate) fn matches(&self, dev: &block::Device) -> bool {
+        let trimmed = |s: &Option<String>| s.as_deref().map(|s| s.trim().to_string());
+        trimmed(&dev.serial) == self.serial && (self.wwn.is_none() || trimmed(&dev.wwn) == self.wwn)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct State {
+    pub(crate) platform: String,
+    pub(crate) devices: Vec<RecordedDevice>,
+    /// Set if we created a volume group.
+    pub(crate) vg: Option<String>,
+    /// The mount units we generated.
+    #[serde(default)]
+    pub(crate) units: Vec<String>,
+    /// Symlinks created for exports.
+    #[serde(default)]
+    pub(crate) export_links: Vec<String>,
+    /// Set if we created an md array.
+    #[serde(default)]
+    pub(crate) raid: bool,
+    /// With `mode: cache`, the logical volume we attached the cache to.
+    #[serde(default)]
+    pub(crate) cache_origin: Option<String>,
+    /// With `mode: bcache`, the backing device we attached the cache to.
+    #[serde(default)]
+    pub(crate) bcache_backing: Option<String>,
+    /// Counts provisionings of this machine, across reprovisioning.
+    #[serde(default)]
+    pub(crate) generation: u64,
+    /// Of the config file used.
+    #[serde(default)]
+    pub(crate) config_digest: Option<String>,
+}
+
+impl State {
+    /// This is for the next provisioning generation, which is only
+    /// taken once the state is written.
+    pub(crate) fn new(
+        platform: &str,
+        devices: &[String],
+        vg: Option<&str>,
+        config_digest: Option<String>,
+    ) -> Result<Self> {
+        let blockdevs = block::list()?;
+        let devices = devices
+            .iter()
+            .map(|path| RecordedDevice::new(path, &blockdevs))
+            .collect();
+        Ok(Self {
+            platform: platform.to_string(),
+            devices,
+            vg: vg.map(|s| s.to_string()),
+            units: Vec::new(),
+            export_links: Vec::new(),
+            raid: false,
+            cache_origin: None,
+            bcache_backing: None,
+            generation: generation()? + 1,
+            config_digest,
+        })
+    }
+
+    /// The current paths of the recorded devices which are still present.
+    pub(crate) fn present_devices(&self) -> Result<Vec<String>> {
+        let blockdevs = block::list()?;
+        Ok(self
+            .devices
+            .iter()
+            .filter_map(|rec| match rec.serial.as_deref() {
+                Some(_) => blockdevs.iter().find(|d| rec.matches(d)).map(|d| d.path()),
+                None => Some(rec.path.clone()).filter(|p| Path::new(p).exists()),
+            })
+            .collect())
+    }
+
+    /// Recorded devices which are no longer present; devices are
+    /// identified by their serial number and WWN where possible,
+    /// since the kernel names may change across boots.  On bare
+    /// metal, this also detects a replaced drive.
+    pub(crate) fn missing_devices(&self) -> Result<Vec<&RecordedDevice>> {
+        let blockdevs = block::list()?;
+        Ok(self
+            .devices
+            .iter()
+            .filter(|rec| match rec.serial.as_deref() {
+                Some(_) => !blockdevs.iter().any(|d| rec.matches(d)),
+                None => !Path::new(&rec.path).exists(),
+            })
+            .collect())
+    }
+}
+
+fn path() -> std::path::PathBuf {
+    Path::new(STATE_DIR).join(STATE_FILE)
+}
+
+/// The generation of the last provisioning, or 0 if there was none.
+pub(crate) fn generation() -> Result<u64> {
+    let path = Path::new(STATE_DIR).join(GENERATION_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("Parsing {:?}", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("Reading {:?}", path)),
+    }
+}
+
+/// Print the provisioning status, as text, JSON or Prometheus metrics
+/// (e.g. for node_exporter's textfile collector).
+pub(crate) fn status(json: bool, prometheus: bool) -> Result<()> {
+    let state = load()?;
+    let generation = state.as_ref().map_or(generation(), |s| Ok(s.generation))?;
+    let digest = config_digest()?;
+    let legacy_config = config_path() == Some(LEGACY_CONFIG_PATH);
+    let current = state
+        .as_ref()
+        .is_some_and(|s| s.config_digest.is_some() && s.config_digest == digest);
+    if json {
+        let status = serde_json::json!({
+            "provisioned": state.is_some(),
+            "generation": generation,
+            "config-digest": state.as_ref().and_then(|s| s.config_digest.as_deref()),
+            "config-current": current,
+            "config-path": config_path(),
+            "config-deprecated-path": legacy_config,
+            "platform": state.as_ref().map(|s| s.platform.as_str()),
+            "devices": state.as_ref().map(|s| s.devices.iter().map(|d| d.path.as_str()).collect::<Vec<_>>()),
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &status)?;
+        println!();
+    } else if prometheus {
+        let metrics = [
+            (
+                "provisioned",
+                "Whether the instance storage is provisioned.",
+                u64::from(state.is_some()),
+            ),
+            (
+                "generation",
+                "Provisioning generation of the instance storage.",
+                generation,
+            ),
+            (
+                "config_current",
+                "Whether the provisioned layout is from the current config file.",
+                u64::from(current),
+            ),
+            (
+                "config_deprecated_path",
+                "Whether the config file is still at its deprecated location.",
+                u64::from(legacy_config),
+            ),
+            (
+                "devices",
+                "Instance storage devices in use.",
+                state.as_ref().map_or(0, |s| s.devices.len() as u64),
+            ),
+        ];
+        for (name, help, value) in metrics {
+            println!("# HELP ccisp_{} {}", name, help);
+            println!("# TYPE ccisp_{} gauge", name);
+            println!("ccisp_{} {}", name, value);
+        }
+    } else if let Some(state) = state.as_ref() {
+        println!("Provisioned: generation {}", generation);
+        println!("Platform: {}", state.platform);
+        for dev in state.devices.iter() {
+            println!("Device: {}", dev.path);
+        }
+        println!(
+            "Config: {}",
+            if current {
+                "current"
+            } else {
+                "changed since provisioning"
+            }
+        );
+    } else {
+        println!("Not provisioned (last generation: {})", generation);
+    }
+    if legacy_config && !json && !prometheus {
+        println!(
+            "Config file {} is deprecated; move it to {}",
+            LEGACY_CONFIG_PATH, CONFIG_PATH
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn load() -> Result<Option<State>> {
+    let path = path();
+    let f = match std::fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Opening {:?}", path)),
+    };
+    let state = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {:?}", path))?;
+    Ok(Some(state))
+}
+
+pub(crate) fn write(state: &State) -> Result<()> {
+    std::fs::create_dir_all(STATE_DIR)?;
+    let dir = openat::Dir::open(STATE_DIR)?;
+    dir.write_file_with(STATE_FILE, 0o644, |w| -> Result<()> {
+        serde_json::to_writer_pretty(w, state)?;
+        Ok(())
+    })?;
+    dir.write_file_contents(GENERATION_FILE, 0o644, format!("{}\n", state.generation))?;
+    Ok(())
+}
+
+/// Remove the recorded state, tearing down anything stale it references.
+pub(crate) fn invalidate(
+    state: &State,
+    discards: bool,
+    scrub: Option<&scrub::Settings>,
+) -> Result<()> {
+    exports::teardown(&state.export_links);
+    // Active mounts and swap would keep the volume group busy; with
+    // `provision --reconfigure`, they're all still active.
+    for unit in state.units.iter().rev() {
+        if let Err(e) = Command::new("systemctl").arg("stop").arg(unit).run() {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+    // The cache may be striped over a volume group of its own.
+    if let Some(backing) = state.bcache_backing.as_deref() {
+        bcache::detach(backing);
+    }
+    if let Some(vg) = state.vg.as_deref() {
+        lvm::remove_stale_vg(vg, discards);
+    }
+    if state.raid {
+        mdraid::stop();
+    }
+    if let Some(origin) = state.cache_origin.as_deref() {
+        let devs: Vec<_> = state.devices.iter().map(|d| d.path.clone()).collect();
+        lvmcache::detach(origin, &devs);
+    }
+    if let Some(scrub) = scrub {
+        let devices = state.present_devices()?;
+        if let Err(e) = scrub::run(scrub, &devices) {
+            eprintln!("warning: {:#}", e);
+        }
+    }
+    std::fs::remove_file(path())?;
+    Ok(())
+}
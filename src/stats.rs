@@ -0,0 +1,114 @@
+//! Per-device I/O statistics, to check that I/O is actually being spread
+//! across all the devices we striped over.
+
+use super::*;
+
+/// Sectors in /sys/block/*/stat are always 512 bytes.
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Default)]
+struct DevStat {
+    read_ios: u64,
+    read_sectors: u64,
+    write_ios: u64,
+    write_sectors: u64,
+}
+
+/// Parse the first fields of a block device `stat` file; see
+/// Documentation/block/stat.rst in the kernel.
+fn parse_stat(content: &str) -> Result<DevStat> {
+    let fields = content
+        .split_whitespace()
+        .map(|f| f.parse::<u64>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if fields.len() < 7 {
+        bail!("Too few fields in stat: {}", content.trim());
+    }
+    Ok(DevStat {
+        read_ios: fields[0],
+        read_sectors: fields[2],
+        write_ios: fields[4],
+        write_sectors: fields[6],
+    })
+}
+
+fn read_stat(dev: &str) -> Result<DevStat> {
+    let path = std::fs::canonicalize(dev).with_context(|| format!("Resolving {}", dev))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid device path {:?}", path))?;
+    let statpath = Path::new("/sys/class/block").join(name).join("stat");
+    let content =
+        std::fs::read_to_string(&statpath).with_context(|| format!("Reading {:?}", statpath))?;
+    parse_stat(&content)
+}
+
+fn mib(sectors: u64) -> u64 {
+    sectors * SECTOR_SIZE / (1024 * 1024)
+}
+
+pub(crate) fn print() -> Result<()> {
+    let state = if let Some(state) = state::load()? {
+        state
+    } else {
+        println!("Instance storage is not provisioned");
+        return Ok(());
+    };
+    let stats = state
+        .devices
+        .iter()
+        .map(|d| Ok((d.path.as_str(), read_stat(&d.path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let total: u64 = stats
+        .iter()
+        .map(|(_, s)| s.read_sectors + s.write_sectors)
+        .sum();
+    println!(
+        "{:<24} {:>12} {:>12} {:>12} {:>12} {:>7}",
+        "DEVICE", "READ IOS", "READ MiB", "WRITE IOS", "WRITE MiB", "SHARE"
+    );
+    for (dev, s) in stats.iter() {
+        let share = if total > 0 {
+            100.0 * (s.read_sectors + s.write_sectors) as f64 / total as f64
+        } else {
+            0.0
+        };
+        println!(
+            "{:<24} {:>12} {:>12} {:>12} {:>12} {:>6.1}%",
+            dev,
+            s.read_ios,
+            mib(s.read_sectors),
+            s.write_ios,
+            mib(s.write_sectors),
+            share
+        );
+    }
+    if let Some(vg) = state.vg.as_deref() {
+        println!();
+        Command::new("lvm")
+            .args(["lvs", "--segments", "-o", "+devices,seg_pe_ranges"])
+            .arg(vg)
+            .run()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stats() {
+        let s = parse_stat(
+            "   12345      67   890123      456     7890       12   345678      901        0     1234     1357\n",
+        )
+        .unwrap();
+        assert_eq!(s.read_ios, 12345);
+        assert_eq!(s.read_sectors, 890123);
+        assert_eq!(s.write_ios, 7890);
+        assert_eq!(s.write_sectors, 345678);
+        assert!(parse_stat("1 2 3 4 5 6").is_err());
+        assert!(parse_stat("1 2 3 4 5 6 x").is_err());
+        assert!(parse_stat("").is_err());
+    }
+}
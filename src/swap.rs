@@ -0,0 +1,88 @@
+//! Swap on a logical volume in the instance storage volume group.
+
+use super::*;
+use libsystemd::unit;
+use std::io::Write as IoWrite;
+use std::os::unix::fs::OpenOptionsExt;
+
+pub(crate) const LV_NAME: &str = "swap";
+/// Name of the swap file, in the root of the instance storage.
+const FILE_NAME: &str = "swapfile";
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Kind {
+    /// A logical volume of its own.
+    #[default]
+    Volume,
+    /// A file on the instance storage filesystem.
+    File,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Swap {
+    #[serde(rename = "type", default)]
+    pub(crate) kind: Kind,
+    /// E.g. `8G`, or for volumes a percentage of the instance
+    /// storage like `10%`.
+    pub(crate) size: String,
+    /// Passed to swapon(8) as `--priority`.
+    pub(crate) priority: Option<i32>,
+}
+
+/// Create a fully allocated swap file on the mounted filesystem
+/// (unless it exists already), returning its path.
+pub(crate) fn create_file(mountpoint: &str, size: &str, filesystem: Filesystem) -> Result<String> {
+    let path = Path::new(mountpoint).join(FILE_NAME);
+    let path_utf8 = path.to_str().expect("utf8").to_string();
+    if path.exists() {
+        return Ok(path_utf8);
+    }
+    let size = parse_size(size)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Creating {:?}", path))?;
+    // Swap files can't be copy-on-write; this only works while empty.
+    if filesystem == Filesystem::Btrfs {
+        Command::new("chattr").arg("+C").arg(&path).run()?;
+    }
+    // Swap files mustn't have holes, so no sparse files here.
+    Command::new("fallocate")
+        .arg("--length")
+        .arg(size.to_string())
+        .arg(&path)
+        .run()?;
+    Command::new("mkswap").arg(&path).run()?;
+    Ok(path_utf8)
+}
+
+pub(crate) fn write_unit(unit_dir: &str, what: &str, priority: Option<i32>) -> Result<String> {
+    let name = format!("{}.swap", unit::escape_path(what));
+    let priority = priority
+        .map(|p| format!("Priority={}\n", p))
+        .unwrap_or_default();
+    let dir = openat::Dir::open(unit_dir)?;
+    failure::record_unit(unit_dir, &name);
+    dir.write_file_with(&name, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Swap on instance storage
+
+[Swap]
+What={what}
+{priority}
+[Install]
+WantedBy=swap.target
+"##,
+            what = what,
+            priority = priority,
+        )?;
+        Ok(())
+    })?;
+    Ok(name)
+}
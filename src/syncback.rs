@@ -0,0 +1,166 @@
+//! With `sync-back`, a directory's content is copied to persistent storage
+//! at shutdown, e.g. to keep the last boot's logs while logging to fast
+//! local storage, and with `sync-interval`, periodically too.  Nothing
+//! happens on a crash or when the instance is terminated, of course.
+
+use super::*;
+use libsystemd::unit;
+use std::io::Write as IoWrite;
+
+/// A remote rsync target, `host:path` or `rsync://host/path`, rather
+/// than a local directory.
+pub(crate) fn is_remote(target: &str) -> bool {
+    target.starts_with("rsync://")
+        || target
+            .split('/')
+            .next()
+            .is_some_and(|host| host.contains(':'))
+}
+
+/// Files are never deleted from `target`: after the instance storage
+/// is lost, `dir` starts out empty.
+fn rsync(dir: &str, target: &str) -> String {
+    format!(
+        "/usr/bin/rsync --archive --hard-links --acls --xattrs {}/ {}/",
+        dir, target
+    )
+}
+
+/// Dependencies on `target`: the network, or the mount holding it.
+fn target_deps(target: &str) -> String {
+    if is_remote(target) {
+        "Wants=network-online.target\nAfter=network-online.target\n".to_string()
+    } else {
+        format!("RequiresMountsFor={}\n", target)
+    }
+}
+
+/// Write a service which is started along with `mount_unit` and
+/// copies `dir` to `target` when stopped, before the mount is; with
+/// `interval`, also a timer copying it every `interval` (in systemd
+/// time span syntax, e.g. `15min`).  Returns the units to enable.
+pub(crate) fn write_units(
+    unit_dir: &str,
+    dir: &str,
+    target: &str,
+    interval: Option<&str>,
+    mount_unit: &str,
+) -> Result<Vec<String>> {
+    let escaped = unit::escape_path(dir);
+    let d = openat::Dir::open(unit_dir)?;
+    let mkdir = if is_remote(target) {
+        String::new()
+    } else {
+        format!("ExecStartPre=/usr/bin/mkdir -p {}\n", target)
+    };
+    let name = format!("ccisp-sync-{}.service", escaped);
+    failure::record_unit(unit_dir, &name);
+    d.write_file_with(&name, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Copy {dir} back to {target} at shutdown
+After={mount_unit}
+{deps}
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+{mkdir}ExecStart=/bin/true
+ExecStop={rsync}
+TimeoutStopSec=5min
+
+[Install]
+WantedBy={mount_unit}
+"##,
+            dir = dir,
+            target = target,
+            mount_unit = mount_unit,
+            deps = target_deps(target),
+            mkdir = mkdir,
+            rsync = rsync(dir, target),
+        )?;
+        Ok(())
+    })?;
+    let mut units = vec![name];
+    if let Some(interval) = interval {
+        let periodic = format!("ccisp-sync-periodic-{}", escaped);
+        let service = format!("{}.service", periodic);
+        failure::record_unit(unit_dir, &service);
+        d.write_file_with(&service, 0o644, |f| -> Result<()> {
+            write!(
+                f,
+                r##"[Unit]
+Description=Copy {dir} to {target}
+Requisite={mount_unit}
+After={mount_unit}
+{deps}
+[Service]
+Type=oneshot
+{mkdir}ExecStart={rsync}
+IOSchedulingClass=idle
+"##,
+                dir = dir,
+                target = target,
+                mount_unit = mount_unit,
+                deps = target_deps(target),
+                mkdir = mkdir,
+                rsync = rsync(dir, target),
+            )?;
+            Ok(())
+        })?;
+        let timer = format!("{}.timer", periodic);
+        failure::record_unit(unit_dir, &timer);
+        d.write_file_with(&timer, 0o644, |f| -> Result<()> {
+            write!(
+                f,
+                r##"[Unit]
+Description=Copy {dir} to {target} every {interval}
+After={mount_unit}
+
+[Timer]
+OnActiveSec={interval}
+OnUnitActiveSec={interval}
+
+[Install]
+WantedBy={mount_unit}
+"##,
+                dir = dir,
+                target = target,
+                interval = interval,
+                mount_unit = mount_unit,
+            )?;
+            Ok(())
+        })?;
+        units.push(timer);
+    }
+    Ok(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote() {
+        for t in [
+            "backup.example.com:/srv/logs",
+            "user@backup:logs",
+            "[fd00::1]:/srv/logs",
+            "rsync://backup.example.com/logs",
+        ] {
+            assert!(is_remote(t), "{:?}", t);
+        }
+        // As for rsync, a colon after the first slash is part of a path.
+        for t in [
+            "/var/log-persistent",
+            "/srv/a:b",
+            "logs",
+            "./logs:old",
+            "dir/a:b",
+        ] {
+            assert!(!is_remote(t), "{:?}", t);
+        }
+        // Without a slash before it, it's a host, as for rsync.
+        assert!(is_remote("logs:old"));
+    }
+}
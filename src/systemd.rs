@@ -0,0 +1,287 @@
+use super::*;
+use libsystemd::unit;
+use std::io::Write as IoWrite;
+
+const DEFAULT_WANTED_BY: &str = "local-fs.target";
+
+/// Render extra `Key=Value` lines for a unit section.
+fn render_directives(directives: &BTreeMap<String, String>) -> Result<String> {
+    let mut r = String::new();
+    for (k, v) in directives {
+        if k.is_empty() || k.contains(|c: char| c == '=' || c.is_whitespace()) {
+            bail!("Invalid unit directive name: {:?}", k);
+        }
+        if v.contains('\n') {
+            bail!("Invalid value for unit directive {}: {:?}", k, v);
+        }
+        r.push_str(&format!("{}={}\n", k, v));
+    }
+    Ok(r)
+}
+
+/// Render a dependency directive such as `After=`, if `units` is non-empty.
+fn render_deps(key: &str, units: &[String]) -> Result<String> {
+    if units.is_empty() {
+        return Ok(String::new());
+    }
+    if let Some(u) = units
+        .iter()
+        .find(|u| u.is_empty() || u.contains(char::is_whitespace))
+    {
+        bail!("Invalid unit name for {}: {:?}", key, u);
+    }
+    Ok(format!("{}={}\n", key, units.join(" ")))
+}
+
+/// A mount unit to generate.
+#[derive(Debug)]
+pub(crate) struct MountUnit<'a> {
+    pub(crate) what: &'a str,
+    pub(crate) where_: &'a str,
+    pub(crate) fstype: &'a str,
+    pub(crate) options: Vec<&'a str>,
+    /// If empty, the mount is part of `local-fs.target`; otherwise
+    /// it's ordered before and pulled in by these units.
+    pub(crate) wanted_by: &'a [String],
+    /// Additional ordering, besides that implied by `wanted_by`.
+    pub(crate) before: &'a [String],
+    pub(crate) after: &'a [String],
+    /// Additional settings from the config file.
+    pub(crate) settings: Option<&'a UnitSettings>,
+    pub(crate) description: Option<String>,
+    /// The path the mount depends on, if `what` isn't one (e.g. overlayfs).
+    pub(crate) requires: Option<&'a str>,
+}
+
+impl<'a> MountUnit<'a> {
+    pub(crate) fn new(what: &'a str, where_: &'a str, fstype: &'a str) -> Self {
+        Self {
+            what,
+            where_,
+            fstype,
+            options: Vec::new(),
+            wanted_by: &[],
+            before: &[],
+            after: &[],
+            settings: None,
+            description: None,
+            requires: None,
+        }
+    }
+
+    /// Write the unit into `unit_dir`, returning its name.
+    pub(crate) fn write(&self, unit_dir: &str) -> Result<String> {
+        let dir = openat::Dir::open(unit_dir)?;
+        let name = format!("{}.mount", unit::escape_path(self.where_));
+        let mut options = self.options.clone();
+        let (extra_unit, extra_mount) = if let Some(settings) = self.settings {
+            options.extend(settings.options.iter().map(|s| s.as_str()));
+            (
+                render_directives(&settings.unit)?,
+                render_directives(&settings.mount)?,
+            )
+        } else {
+            Default::default()
+        };
+        let opts = if options.is_empty() {
+            Cow::Borrowed("")
+        } else {
+            Cow::Owned(format!("Options={}\n", options.join(",")))
+        };
+        let wanted_by = if self.wanted_by.is_empty() {
+            Cow::Borrowed(DEFAULT_WANTED_BY)
+        } else {
+            Cow::Owned(self.wanted_by.join(" "))
+        };
+        let description = self
+            .description
+            .as_ref()
+            .map(|d| Cow::Owned(format!("Description={}\n", d)))
+            .unwrap_or(Cow::Borrowed(""));
+        let ordering = render_deps("Before", self.before)? + &render_deps("After", self.after)?;
+        failure::record_unit(unit_dir, &name);
+        dir.write_file_with(&name, 0o644, |f| -> Result<()> {
+            write!(
+                f,
+                r##"[Unit]
+{description}DefaultDependencies=no
+Conflicts=umount.target
+Before={wanted_by} umount.target
+RequiresMountsFor={requires_path}
+{ordering}{extra_unit}
+[Mount]
+What={what_path}
+Where={where_path}
+Type={mnt_type}
+{opts}{extra_mount}
+[Install]
+WantedBy={wanted_by}
+"##,
+                requires_path = self.requires.unwrap_or(self.what),
+                what_path = self.what,
+                where_path = self.where_,
+                mnt_type = self.fstype,
+                opts = opts,
+                ordering = ordering,
+                extra_unit = extra_unit,
+                extra_mount = extra_mount,
+                wanted_by = wanted_by,
+                description = description,
+            )?;
+            Ok(())
+        })?;
+        Ok(name)
+    }
+}
+
+/// Units written here are gone after a reboot.
+pub(crate) const RUNTIME_UNIT_DIR: &str = "/run/systemd/system";
+
+/// Where generated units are written, and whether they're only
+/// enabled until the next reboot.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Install<'a> {
+    pub(crate) unit_dir: &'a str,
+    pub(crate) runtime: bool,
+}
+
+impl Install<'_> {
+    /// Enable `unit`, and start it if `now` is set.  The caller
+    /// must have run daemon-reload.
+    pub(crate) fn enable(&self, unit: &str, now: bool) -> Result<()> {
+        self.enable_all(&[unit], now)
+    }
+
+    /// Like `enable`, in a single systemctl invocation.
+    pub(crate) fn enable_all<S: AsRef<str>>(&self, units: &[S], now: bool) -> Result<()> {
+        if units.is_empty() {
+            return Ok(());
+        }
+        let mut c = Command::new("systemctl");
+        c.arg("enable");
+        if self.runtime {
+            c.arg("--runtime");
+        }
+        if now {
+            c.arg("--now");
+            // At boot, we run before local-fs-pre.target, which units with
+            // default dependencies are ordered after; waiting for them would
+            // deadlock.  Our mount units are safe to wait for.
+            if !units.iter().all(|u| u.as_ref().ends_with(".mount")) && booting()? {
+                c.arg("--no-block");
+            }
+        }
+        c.args(units.iter().map(|u| u.as_ref())).run()
+    }
+}
+
+/// Whether the system is still booting up.
+fn booting() -> Result<bool> {
+    let o = Command::new("systemctl")
+        .arg("is-system-running")
+        .output()?;
+    let state = String::from_utf8_lossy(&o.stdout);
+    Ok(matches!(state.trim(), "initializing" | "starting"))
+}
+
+/// The name of our own service unit.
+pub(crate) const SERVICE_NAME: &str = "coreos-cloud-instance-store-provisioner.service";
+/// Run when the config file changes.  Unlike `SERVICE_NAME`, it
+/// doesn't remain active after exiting, so every change starts it.
+pub(crate) const RECONFIGURE_SERVICE_NAME: &str =
+    "coreos-cloud-instance-store-provisioner-reconfigure.service";
+/// Watches the config file, triggering `RECONFIGURE_SERVICE_NAME`.
+pub(crate) const PATH_UNIT_NAME: &str = "coreos-cloud-instance-store-provisioner.path";
+
+/// Sandboxing for our services.
+const SERVICE_HARDENING: &str = "WorkingDirectory=/usr
+ProtectHome=yes
+ReadOnlyPaths=/usr
+PrivateTmp=yes
+ProtectClock=yes
+ProtectHostname=yes
+ProtectControlGroups=yes
+RestrictSUIDSGID=yes
+";
+
+/// Write a unit running `exec` at boot into `unit_dir`, ordered before
+/// local filesystems are mounted so that redirected directories are in
+/// place before anything uses them, along with a path unit which applies
+/// changes to the config file (including one written after boot).  Unless
+/// `first_boot_only` is set, we run on every boot, which is what allows
+/// reprovisioning after e.g. an AWS stop/start.
+pub(crate) fn write_service(unit_dir: &str, exec: &Path, first_boot_only: bool) -> Result<()> {
+    let dir = openat::Dir::open(unit_dir)?;
+    let exec = exec
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid UTF-8 in {:?}", exec))?;
+    let condition = if first_boot_only {
+        "ConditionFirstBoot=yes\n"
+    } else {
+        ""
+    };
+    dir.write_file_with(SERVICE_NAME, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Configure instance storage
+{condition}DefaultDependencies=no
+Wants=local-fs-pre.target
+Before=local-fs-pre.target
+After=systemd-udev-trigger.service systemd-remount-fs.service
+Conflicts=shutdown.target
+Before=shutdown.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={exec} provision
+{hardening}
+[Install]
+WantedBy=multi-user.target
+"##,
+            condition = condition,
+            exec = exec,
+            hardening = SERVICE_HARDENING,
+        )?;
+        Ok(())
+    })?;
+    dir.write_file_with(RECONFIGURE_SERVICE_NAME, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Apply changes to the instance storage configuration
+After={service}
+
+[Service]
+Type=oneshot
+ExecStart={exec} provision --reconfigure
+{hardening}"##,
+            service = SERVICE_NAME,
+            exec = exec,
+            hardening = SERVICE_HARDENING,
+        )?;
+        Ok(())
+    })?;
+    dir.write_file_with(PATH_UNIT_NAME, 0o644, |f| -> Result<()> {
+        write!(
+            f,
+            r##"[Unit]
+Description=Watch for changes to the instance storage configuration
+
+[Path]
+PathChanged={config}
+PathChanged={legacy_config}
+Unit={service}
+
+[Install]
+WantedBy=paths.target
+"##,
+            config = CONFIG_PATH,
+            legacy_config = LEGACY_CONFIG_PATH,
+            service = RECONFIGURE_SERVICE_NAME,
+        )?;
+        Ok(())
+    })?;
+    Ok(())
+}
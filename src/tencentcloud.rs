@@ -0,0 +1,21 @@
+use super::*;
+
+/// Cloud Block Storage disks use their disk ID as serial.
+const CBS_SERIAL_PREFIX: &str = "disk-";
+
+/// On IT/I-series instances, the local data disks are NVMe, whereas
+/// Cloud Block Storage (including the system disk) is virtio.  Be
+/// defensive though and also exclude anything that looks like CBS.
+pub(crate) fn devices() -> Result<Vec<String>> {
+    Ok(block::list()?
+        .into_iter()
+        .filter(|dev| dev.name.starts_with("nvme"))
+        .filter(|dev| {
+            dev.serial
+                .as_ref()
+                .filter(|s| s.trim().starts_with(CBS_SERIAL_PREFIX))
+                .is_none()
+        })
+        .map(|dev| dev.path())
+        .collect())
+}
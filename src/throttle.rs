@@ -0,0 +1,88 @@
+//! CPU and I/O limits for the heavy commands we spawn (mkfs and the
+//! like), so that a first boot on a large instance doesn't starve other
+//! first-boot work such as image pulls.  Set once from the config.
+
+use super::*;
+use std::os::unix::process::CommandExt;
+use std::sync::OnceLock;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum IoClass {
+    BestEffort,
+    Idle,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Settings {
+    /// Niceness, as for nice(1).
+    nice: Option<i32>,
+    /// I/O scheduling class, as for ionice(1).
+    ionice_class: Option<IoClass>,
+    /// Priority within the best-effort class, 0 (highest) to 7.
+    ionice_level: Option<u8>,
+    /// Restrict to these CPUs.
+    #[serde(default)]
+    cpus: Vec<usize>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+pub(crate) fn init(settings: &Settings) -> Result<()> {
+    if settings.ionice_level.is_some_and(|l| l > 7) {
+        bail!("ionice-level must be between 0 and 7");
+    }
+    if let Some(cpu) = settings
+        .cpus
+        .iter()
+        .find(|&&c| c >= libc::CPU_SETSIZE as usize)
+    {
+        bail!("Invalid CPU number {}", cpu);
+    }
+    let _ = SETTINGS.set(settings.clone());
+    Ok(())
+}
+
+/// Apply the configured limits to `c`, which should be a command
+/// doing a lot of CPU or I/O work.
+pub(crate) fn apply(c: &mut Command) -> &mut Command {
+    let s = match SETTINGS.get() {
+        Some(s) => s.clone(),
+        None => return c,
+    };
+    let ioprio = s.ionice_class.map(|class| match class {
+        IoClass::BestEffort => {
+            (2 << IOPRIO_CLASS_SHIFT) | libc::c_int::from(s.ionice_level.unwrap_or(4))
+        }
+        IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+    });
+    // Only async-signal-safe calls are allowed here.
+    unsafe {
+        c.pre_exec(move || {
+            if let Some(nice) = s.nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(ioprio) = ioprio {
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if !s.cpus.is_empty() {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                for cpu in s.cpus.iter() {
+                    libc::CPU_SET(*cpu, &mut set);
+                }
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        })
+    }
+}
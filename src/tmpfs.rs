@@ -0,0 +1,71 @@
+//! With `fallback: tmpfs`, a bounded tmpfs on each directory when there's
+//! no instance storage.  The units are only enabled until the next boot,
+//! so that instance storage is used if the instance type changes.
+
+use super::*;
+use std::os::unix::fs::PermissionsExt;
+
+pub(crate) fn setup(
+    config: &Config,
+    directories: &[Directory],
+    size: &str,
+    selinux: bool,
+) -> Result<()> {
+    let install = systemd::Install {
+        unit_dir: systemd::RUNTIME_UNIT_DIR,
+        runtime: true,
+    };
+    std::fs::create_dir_all(install.unit_dir)?;
+    let mut units = Vec::new();
+    let mut owned = Vec::new();
+    for dir in directories {
+        let d = Path::new(&dir.path);
+        let facts = policy::Facts::gather(d)?;
+        let decision = policy::evaluate(&config.directory_policy, &facts, selinux);
+        match decision.action {
+            policy::Action::Replace => {}
+            policy::Action::Skip => {
+                println!("Skipping {}", dir.path);
+                continue;
+            }
+            policy::Action::Fail => bail!("Policy forbids redirecting {}", dir.path),
+        }
+        std::fs::create_dir_all(d).with_context(|| format!("Creating {}", dir.path))?;
+        // The tmpfs root takes over the mode and label of the directory.
+        let mode = match dir.permissions.as_deref() {
+            Some(p) => p.to_string(),
+            None => format!("{:o}", std::fs::metadata(d)?.permissions().mode() & 0o7777),
+        };
+        let mut options = vec![format!("size={}", size), format!("mode={}", mode)];
+        if selinux {
+            options.push(format!("rootcontext=\"{}\"", selinux::get_context(d)?));
+        }
+        let parent = d.parent().and_then(|p| p.to_str()).unwrap_or("/");
+        let unit = systemd::MountUnit {
+            options: options.iter().map(|o| o.as_str()).collect(),
+            wanted_by: &dir.wanted_by,
+            before: &dir.before,
+            after: &dir.after,
+            settings: dir.mount_unit.as_ref(),
+            description: Some(format!("tmpfs for {}", dir.path)),
+            requires: Some(parent),
+            ..systemd::MountUnit::new("tmpfs", &dir.path, "tmpfs")
+        }
+        .write(install.unit_dir)?;
+        units.push(unit);
+        if dir.has_ownership() && dir.wanted_by.is_empty() {
+            owned.push(dir);
+        }
+        println!("Set up {} on tmpfs", dir.path);
+    }
+    Command::new("systemctl").arg("daemon-reload").run()?;
+    install.enable_all(&units, true)?;
+    // Now that they're mounted; weakly activated ones only get the mode.
+    for dir in owned {
+        ownership::apply(dir, Path::new(&dir.path))?;
+    }
+    if config.oomd {
+        oomd::memory_pressure(install)?;
+    }
+    Ok(())
+}
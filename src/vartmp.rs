@@ -0,0 +1,31 @@
+//! `/var/tmp` on instance storage, for build-heavy nodes which
+//! frequently exhaust it on the root disk.
+
+use super::*;
+use std::os::unix::fs::PermissionsExt;
+
+pub(crate) const PATH: &str = "/var/tmp";
+const TMPFILES_NAME: &str = "ccisp-var-tmp.conf";
+/// Cleaned up more eagerly than the default of 30 days; this sorts
+/// before the distribution's `tmp.conf`, so it takes precedence.
+const TMPFILES: &str = "# Written by coreos-cloud-instance-store-provisioner
+q /var/tmp 1777 root root 10d
+";
+
+/// Fix up the mounted `/var/tmp` as a world-writable sticky
+/// directory, and install the cleanup policy.
+pub(crate) fn setup(selinux: bool, runtime: bool) -> Result<()> {
+    std::fs::set_permissions(PATH, std::fs::Permissions::from_mode(0o1777))
+        .with_context(|| format!("Setting mode of {}", PATH))?;
+    if selinux {
+        selinux::set_context(PATH, selinux::TMP_CONTEXT)?;
+    }
+    let dir = if runtime {
+        "/run/tmpfiles.d"
+    } else {
+        "/etc/tmpfiles.d"
+    };
+    std::fs::create_dir_all(dir)?;
+    openat::Dir::open(dir)?.write_file_contents(TMPFILES_NAME, 0o644, TMPFILES)?;
+    Ok(())
+}
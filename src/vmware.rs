@@ -0,0 +1,32 @@
+use super::*;
+
+pub(crate) fn devices(config: &Config) -> Result<Vec<String>> {
+    if config.vmware_disks.is_empty() {
+        println!("No vmware-disks configured");
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    let mut serials = Vec::new();
+    for disk in config.vmware_disks.iter() {
+        if disk.starts_with("/dev/") {
+            match std::fs::canonicalize(disk) {
+                Ok(p) => paths.push(p),
+                Err(e) => println!("Configured disk {} not found: {}", disk, e),
+            }
+        } else {
+            serials.push(disk.to_lowercase());
+        }
+    }
+    Ok(block::list()?
+        .into_iter()
+        .filter(|dev| {
+            let serial_matches = dev
+                .serial
+                .as_ref()
+                .filter(|s| serials.contains(&s.trim().to_lowercase()))
+                .is_some();
+            serial_matches || paths.iter().any(|p| p == Path::new(&dev.path()))
+        })
+        .map(|dev| dev.path())
+        .collect())
+}
@@ -0,0 +1,10 @@
+use super::*;
+
+/// Secondary NVMe scratch disks attached by some bare-metal and
+/// high-frequency plans; Block Storage volumes are network attached
+/// and don't use this prefix.
+const PREFIX: &str = "VULTR_NVME_";
+
+pub(crate) fn devices() -> Result<Vec<String>> {
+    block::with_serial_prefix(PREFIX)
+}